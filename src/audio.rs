@@ -0,0 +1,34 @@
+//! Audio playback over the HDMI data islands.
+//!
+//! The application mixes stereo frames into [`DviOut::audio_queue`] at
+//! [`AUDIO_RATE`], and the video interrupt resamples them to the rate
+//! actually produced by the video clock, draining them into audio sample
+//! packets. When the queue runs dry, silence is sent.
+//!
+//! [`DviOut::audio_queue`]: crate::dvi::DviOut::audio_queue
+
+mod mixer;
+mod resample;
+mod sample;
+
+pub use mixer::{Mixer, PlayMode, VoiceId, N_VOICES, UNITY_VOLUME};
+pub use resample::{AudioClock, Resampler};
+pub use sample::{AdpcmState, Encoding, Sample, SampleCursor, SampleError};
+
+/// Output sample rate in Hz.
+pub const AUDIO_RATE: u32 = 44_100;
+
+/// Size of the audio queue in frames.
+///
+/// This holds almost three video frames' worth of audio at 60Hz. The
+/// resampler aims to keep it half full.
+pub const AUDIO_QUEUE_SIZE: usize = 2048;
+
+/// Pack a stereo frame into a queue entry.
+pub const fn pack_frame([l, r]: [i16; 2]) -> u32 {
+    l as u16 as u32 | ((r as u16 as u32) << 16)
+}
+
+pub const fn unpack_frame(frame: u32) -> [i16; 2] {
+    [frame as i16, (frame >> 16) as i16]
+}
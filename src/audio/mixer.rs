@@ -0,0 +1,278 @@
+use crate::render::Queue;
+
+use super::{pack_frame, sample::SampleCursor, Sample, AUDIO_RATE};
+
+/// The number of simultaneous voices.
+pub const N_VOICES: usize = 4;
+
+/// Volume for playback at the sample's own level.
+pub const UNITY_VOLUME: u16 = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PlayMode {
+    OneShot,
+    Loop,
+}
+
+/// Handle for a playing voice.
+///
+/// The handle goes stale when its voice finishes or is stopped, even if
+/// the slot is reused for another sound.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VoiceId {
+    slot: usize,
+    generation: u32,
+}
+
+struct Voice {
+    cursor: SampleCursor,
+    mode: PlayMode,
+    /// Position between source samples, 16.16 fixed point.
+    phase: u32,
+    /// Source samples per output sample, 16.16 fixed point.
+    step: u32,
+    current: i16,
+    /// Whether a one-shot voice has reached the end of its sample, so
+    /// `current` is the last one to output.
+    ended: bool,
+    gain_l: i64,
+    gain_r: i64,
+    generation: u32,
+}
+
+/// A software mixer for sample playback.
+///
+/// Samples are converted to the output rate by sample-and-hold, which is
+/// adequate for UI sound cues. The mixer runs on the application core and
+/// feeds the queue drained by the video interrupt.
+pub struct Mixer {
+    voices: [Option<Voice>; N_VOICES],
+    /// Counts voices started, so every [`VoiceId`] is distinct.
+    generation: u32,
+}
+
+impl Voice {
+    fn set_gain(&mut self, volume: u16, pan: i8) {
+        // Pan is a balance control: the nearer channel stays at full volume.
+        let volume = volume as i64;
+        let pan = pan as i64;
+        self.gain_l = volume * (128 - pan).min(128) / 128;
+        self.gain_r = volume * (128 + pan).min(128) / 128;
+    }
+
+    /// Return the current sample, then advance. Returns `None` when a
+    /// one-shot voice is finished.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    fn next(&mut self) -> Option<i16> {
+        if self.ended {
+            return None;
+        }
+        let value = self.current;
+        self.phase += self.step;
+        while self.phase >= 1 << 16 {
+            self.phase -= 1 << 16;
+            match self.cursor.next_sample() {
+                Some(x) => self.current = x,
+                None if self.mode == PlayMode::Loop => {
+                    self.cursor.rewind();
+                    self.current = self.cursor.next_sample()?;
+                }
+                None => {
+                    self.ended = true;
+                    break;
+                }
+            }
+        }
+        Some(value)
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixer {
+    pub const fn new() -> Self {
+        Mixer {
+            voices: [const { None }; N_VOICES],
+            generation: 0,
+        }
+    }
+
+    fn voice(&self, id: VoiceId) -> Option<&Voice> {
+        self.voices[id.slot]
+            .as_ref()
+            .filter(|voice| voice.generation == id.generation)
+    }
+
+    fn voice_mut(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices[id.slot]
+            .as_mut()
+            .filter(|voice| voice.generation == id.generation)
+    }
+
+    /// Start playing a sample.
+    ///
+    /// `volume` is linear, with [`UNITY_VOLUME`] being the sample's own
+    /// level. `pan` ranges from -128 (left) to 127 (right).
+    ///
+    /// Returns `None` if all voices are busy or the sample is empty.
+    pub fn play(
+        &mut self,
+        sample: &'static Sample,
+        mode: PlayMode,
+        volume: u16,
+        pan: i8,
+    ) -> Option<VoiceId> {
+        let slot = self.voices.iter().position(Option::is_none)?;
+        let mut cursor = sample.cursor();
+        let current = cursor.next_sample()?;
+        self.generation = self.generation.wrapping_add(1);
+        let step = ((sample.rate() as u64) << 16) / AUDIO_RATE as u64;
+        let mut voice = Voice {
+            cursor,
+            mode,
+            phase: 0,
+            step: step as u32,
+            current,
+            ended: false,
+            gain_l: 0,
+            gain_r: 0,
+            generation: self.generation,
+        };
+        voice.set_gain(volume, pan);
+        self.voices[slot] = Some(voice);
+        Some(VoiceId {
+            slot,
+            generation: self.generation,
+        })
+    }
+
+    pub fn stop(&mut self, id: VoiceId) {
+        if self.voice(id).is_some() {
+            self.voices[id.slot] = None;
+        }
+    }
+
+    pub fn stop_all(&mut self) {
+        self.voices = [const { None }; N_VOICES];
+    }
+
+    /// Whether the voice is still playing.
+    ///
+    /// One-shot voices stop by themselves at the end of their sample.
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.voice(id).is_some()
+    }
+
+    pub fn set_volume_pan(&mut self, id: VoiceId, volume: u16, pan: i8) {
+        if let Some(voice) = self.voice_mut(id) {
+            voice.set_gain(volume, pan);
+        }
+    }
+
+    /// Mix the next stereo frame.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn next_frame(&mut self) -> [i16; 2] {
+        // Four voices at full volume need more than 32 bits.
+        let mut l = 0i64;
+        let mut r = 0i64;
+        for slot in &mut self.voices {
+            if let Some(voice) = slot {
+                match voice.next() {
+                    Some(x) => {
+                        l += x as i64 * voice.gain_l;
+                        r += x as i64 * voice.gain_r;
+                    }
+                    None => *slot = None,
+                }
+            }
+        }
+        let clamp = |x: i64| (x >> 8).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        [clamp(l), clamp(r)]
+    }
+
+    /// Mix `frames` frames into an audio queue, or as many as fit.
+    ///
+    /// The queue should be fed at a steady [`AUDIO_RATE`], for example
+    /// `AUDIO_RATE / 60` frames once per video frame. The resampler in the
+    /// video interrupt absorbs small differences from the actual output
+    /// rate, keeping the queue about half full.
+    pub fn fill<const SIZE: usize>(&mut self, queue: &Queue<SIZE>, frames: usize) {
+        let free = SIZE - 1 - queue.len();
+        for _ in 0..frames.min(free) {
+            let frame = self.next_frame();
+            queue.push_unchecked(pack_frame(frame));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+
+    use super::{Mixer, PlayMode, N_VOICES, UNITY_VOLUME};
+    use crate::audio::Sample;
+
+    /// A 16 bit PCM sample of `len` samples at the output rate, with both
+    /// bytes of every sample set to `byte`.
+    fn pcm16(len: u8, byte: u8) -> &'static Sample {
+        let mut bytes = alloc::vec![byte; 16 + 2 * len as usize];
+        bytes[..16].copy_from_slice(b"PDVS\x01\x00\x00\x00\x44\xac\x00\x00\x00\x00\x00\x00");
+        bytes[12] = len;
+        Box::leak(Box::new(Sample::from_bytes(bytes.leak()).unwrap()))
+    }
+
+    /// Count the frames output for a one-shot sample.
+    fn frames_played(sample: &'static Sample) -> usize {
+        let mut mixer = Mixer::new();
+        let id = mixer.play(sample, PlayMode::OneShot, UNITY_VOLUME, 0);
+        let frames = (0..16)
+            .filter(|_| mixer.next_frame() == [0x1010, 0x1010])
+            .count();
+        assert!(id.is_some_and(|id| !mixer.is_playing(id)));
+        frames
+    }
+
+    #[test]
+    fn one_shot_length() {
+        assert_eq!(frames_played(pcm16(1, 0x10)), 1);
+        assert_eq!(frames_played(pcm16(5, 0x10)), 5);
+    }
+
+    #[test]
+    fn loud_mix_clamps() {
+        let mut mixer = Mixer::new();
+        for _ in 0..N_VOICES {
+            mixer.play(pcm16(4, 0x7f), PlayMode::OneShot, u16::MAX, 0);
+        }
+        assert_eq!(mixer.next_frame(), [i16::MAX, i16::MAX]);
+    }
+
+    #[test]
+    fn stale_id() {
+        let mut mixer = Mixer::new();
+        let sample = pcm16(1, 0x10);
+        let old = mixer
+            .play(sample, PlayMode::OneShot, UNITY_VOLUME, 0)
+            .unwrap();
+        mixer.stop(old);
+        // The new voice reuses the old one's slot.
+        let new = mixer.play(sample, PlayMode::Loop, UNITY_VOLUME, 0).unwrap();
+        assert!(!mixer.is_playing(old));
+        mixer.stop(old);
+        mixer.set_volume_pan(old, 0, 0);
+        assert!(mixer.is_playing(new));
+        assert_eq!(mixer.next_frame(), [0x1010, 0x1010]);
+    }
+
+    #[test]
+    fn empty_sample() {
+        let mut mixer = Mixer::new();
+        let id = mixer.play(pcm16(0, 0x10), PlayMode::OneShot, UNITY_VOLUME, 0);
+        assert!(id.is_none());
+        assert_eq!(mixer.next_frame(), [0, 0]);
+    }
+}
@@ -0,0 +1,259 @@
+use fugit::HertzU32;
+
+use crate::{dvi::timing::DviTiming, render::Queue};
+
+use super::{unpack_frame, AUDIO_RATE};
+
+/// Recommended audio clock regeneration N for 44.1kHz.
+const ACR_N_44100: u32 = 6272;
+
+/// Audio clock regeneration parameters and packet scheduling.
+///
+/// The sink reconstructs the audio clock as `f_tmds * N / (128 * CTS)`,
+/// so CTS is derived from the pixel clock actually achieved rather than
+/// the nominal one.
+pub struct AudioClock {
+    pub n: u32,
+    pub cts: u32,
+    /// Output samples per scanline, 16.16 fixed point.
+    samples_per_line: u32,
+    /// Application samples per output sample, 16.16 fixed point.
+    resample_step: u32,
+    line_phase: u32,
+    /// Samples that were due, but didn't fit in the line they were due on.
+    backlog: u32,
+}
+
+/// Converts the application's audio stream to the output sample rate.
+///
+/// This uses linear interpolation. The nominal ratio comes from the
+/// [`AudioClock`], and is trimmed by the queue fill level so that any
+/// remaining difference between the producer's clock and the video clock
+/// doesn't make the queue over- or underflow.
+pub struct Resampler {
+    prev: [i16; 2],
+    next: [i16; 2],
+    /// Position between `prev` and `next`, 16.16 fixed point.
+    phase: u32,
+    nominal_step: u32,
+    step: u32,
+    /// Low-pass filtered fill level, in frames with 8 fractional bits.
+    fill_avg: i32,
+}
+
+/// Time constant of the fill level filter, log2 of adjustments.
+const FILL_FILTER_SHIFT: u32 = 10;
+
+/// Gain of the rate control, log2 of fill frames per unit of step.
+///
+/// With 14, each frame of error adjusts the rate by about 61ppm.
+const RATE_GAIN_SHIFT: u32 = 14;
+
+/// Maximum rate adjustment, log2 of the nominal step (about 0.8%).
+const RATE_LIMIT_SHIFT: u32 = 7;
+
+impl AudioClock {
+    /// Compute audio parameters for a video mode.
+    ///
+    /// `bit_clk` is the TMDS bit clock actually achieved by the clock
+    /// setup, which may differ slightly from `timing.bit_clk`.
+    pub fn new(timing: &DviTiming, bit_clk: HertzU32) -> Self {
+        let pixel_clk = bit_clk.to_Hz() as u64 / 10;
+        let n = ACR_N_44100;
+        let audio_rate = AUDIO_RATE as u64;
+        let cts = (pixel_clk * n as u64 + 64 * audio_rate) / (128 * audio_rate);
+        let h_total = timing.h_total_pixels() as u64;
+        let samples_per_line = ((n as u64 * h_total) << 16) / (128 * cts);
+        // Output rate is pixel_clk / h_total * samples_per_line.
+        let resample_step = ((audio_rate * h_total) << 32) / (pixel_clk * samples_per_line);
+        AudioClock {
+            n,
+            cts: cts as u32,
+            samples_per_line: samples_per_line as u32,
+            resample_step: resample_step as u32,
+            line_phase: 0,
+            backlog: 0,
+        }
+    }
+
+    /// The number of audio samples to send for the current scanline, with
+    /// room for at most `room`.
+    ///
+    /// Samples that don't fit are sent on following lines, so the sink
+    /// still gets them all.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn samples_for_line(&mut self, room: usize) -> usize {
+        self.line_phase += self.samples_per_line;
+        let due = self.backlog as usize + (self.line_phase >> 16) as usize;
+        self.line_phase &= 0xffff;
+        let n = due.min(room);
+        self.backlog = (due - n) as u32;
+        n
+    }
+}
+
+impl Resampler {
+    pub fn new(clock: &AudioClock) -> Self {
+        Resampler {
+            prev: [0; 2],
+            next: [0; 2],
+            phase: 0,
+            nominal_step: clock.resample_step,
+            step: clock.resample_step,
+            fill_avg: 0,
+        }
+    }
+
+    /// Produce one output frame, consuming from the queue as needed.
+    ///
+    /// On underrun, silence is substituted for the missing input.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn next_frame<const SIZE: usize>(&mut self, queue: &Queue<SIZE>) -> [i16; 2] {
+        let t = self.phase as i32;
+        let lerp = |a: i16, b: i16| (a as i32 + (((b as i32 - a as i32) * t) >> 16)) as i16;
+        let frame = [
+            lerp(self.prev[0], self.next[0]),
+            lerp(self.prev[1], self.next[1]),
+        ];
+        self.phase += self.step;
+        while self.phase >= 1 << 16 {
+            self.phase -= 1 << 16;
+            self.prev = self.next;
+            self.next = unpack_frame(queue.try_take().unwrap_or_default());
+        }
+        frame
+    }
+
+    /// Trim the conversion ratio based on the queue fill level.
+    ///
+    /// This should be called regularly, for example once per audio packet.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn adjust<const SIZE: usize>(&mut self, queue: &Queue<SIZE>) {
+        let fill = (queue.len() as i32) << 8;
+        self.fill_avg += (fill - self.fill_avg) >> FILL_FILTER_SHIFT;
+        let error = (self.fill_avg >> 8) - (SIZE / 2) as i32;
+        let limit = (self.nominal_step >> RATE_LIMIT_SHIFT) as i32;
+        let correction =
+            ((error * self.nominal_step as i32) >> RATE_GAIN_SHIFT).clamp(-limit, limit);
+        self.step = self.nominal_step.wrapping_add_signed(correction);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fugit::HertzU32;
+
+    use super::{AudioClock, Resampler, RATE_GAIN_SHIFT, RATE_LIMIT_SHIFT};
+    use crate::{
+        audio::{pack_frame, AUDIO_QUEUE_SIZE, AUDIO_RATE},
+        dvi::timing::VGA_TIMING,
+        render::Queue,
+    };
+
+    /// Lines in a second of VGA.
+    const LINES_PER_SECOND: usize = 60 * 525;
+
+    fn clock() -> AudioClock {
+        AudioClock::new(&VGA_TIMING, HertzU32::MHz(252))
+    }
+
+    #[test]
+    fn clock_rate() {
+        let mut clock = clock();
+        // The standard values for a 25.2 MHz pixel clock.
+        assert_eq!((clock.n, clock.cts), (6272, 28000));
+        let samples: usize = (0..LINES_PER_SECOND)
+            .map(|_| clock.samples_for_line(4))
+            .sum();
+        assert!(samples.abs_diff(AUDIO_RATE as usize) <= 1, "{samples}");
+    }
+
+    #[test]
+    fn clock_backlog() {
+        let mut full = clock();
+        let mut reference = clock();
+        let mut sent = 0;
+        for line in 0..100 {
+            // No room for a while, then room for two per line.
+            let room = if line < 10 { 0 } else { 2 };
+            sent += full.samples_for_line(room);
+        }
+        let due: usize = (0..100).map(|_| reference.samples_for_line(4)).sum();
+        assert_eq!(sent, due);
+        assert_eq!(full.backlog, 0);
+    }
+
+    /// Adjust the rate with the queue held at `fill` frames, until the
+    /// filtered fill level settles.
+    fn settle(fill: usize) -> Resampler {
+        let queue = Queue::<AUDIO_QUEUE_SIZE>::new();
+        for _ in 0..fill {
+            queue.push_unchecked(0);
+        }
+        let mut resampler = Resampler::new(&clock());
+        for _ in 0..20_000 {
+            resampler.adjust(&queue);
+        }
+        resampler
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limit = clock().resample_step >> RATE_LIMIT_SHIFT;
+        let full = settle(AUDIO_QUEUE_SIZE - 1);
+        assert_eq!(full.step, full.nominal_step + limit);
+        let empty = settle(0);
+        assert_eq!(empty.step, empty.nominal_step - limit);
+        // Only off by the rounding of the fill level filter, a few frames.
+        let half = settle(AUDIO_QUEUE_SIZE / 2);
+        let rounding = (4 * half.nominal_step) >> RATE_GAIN_SHIFT;
+        assert!(half.nominal_step - half.step <= rounding);
+    }
+
+    /// Run the resampler as the video interrupt does, for `seconds`, with
+    /// the application producing frames `ppm` parts per million fast.
+    ///
+    /// Returns the resampler, after checking the queue never under- or
+    /// overflowed.
+    fn run(ppm: i64, seconds: usize) -> Resampler {
+        let queue = Queue::<AUDIO_QUEUE_SIZE>::new();
+        for _ in 0..AUDIO_QUEUE_SIZE / 2 {
+            queue.push_unchecked(0);
+        }
+        let mut clock = clock();
+        let mut resampler = Resampler::new(&clock);
+        // Frames produced per line, 32.32 fixed point.
+        let per_line = ((AUDIO_RATE as i128 * (1_000_000 + ppm) as i128) << 32)
+            / (1_000_000 * LINES_PER_SECOND as i128);
+        let mut produced = 0i128;
+        for line in 0..seconds * LINES_PER_SECOND {
+            produced += per_line;
+            while produced >= 1 << 32 {
+                produced -= 1 << 32;
+                assert!(queue.len() < AUDIO_QUEUE_SIZE - 1, "overflow");
+                queue.push_unchecked(pack_frame([1, 1]));
+            }
+            for _ in 0..clock.samples_for_line(4) {
+                resampler.next_frame(&queue);
+                assert!(!queue.is_empty(), "underflow");
+            }
+            if line % 2 == 0 {
+                resampler.adjust(&queue);
+            }
+        }
+        resampler
+    }
+
+    #[test]
+    fn rate_converges() {
+        for ppm in [-5000, -300, 0, 300, 5000] {
+            let resampler = run(ppm, 20);
+            let nominal = resampler.nominal_step as i64;
+            let offset = resampler.step as i64 - nominal;
+            assert!(offset.abs() <= nominal >> RATE_LIMIT_SHIFT);
+            // Consuming at the producer's rate, to within 100ppm.
+            let offset_ppm = offset * 1_000_000 / nominal;
+            assert!((offset_ppm - ppm).abs() <= 100, "{ppm}: {offset_ppm}");
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Sound samples stored in flash.
+//!
+//! A sample asset is a 16 byte header followed by the sample data. All
+//! fields are little endian:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | magic, `b"PDVS"`                              |
+//! | 4      | 1    | encoding, see [`Encoding`]                    |
+//! | 5      | 1    | IMA-ADPCM initial step index (0 otherwise)    |
+//! | 6      | 2    | IMA-ADPCM initial predictor (0 otherwise)     |
+//! | 8      | 4    | sample rate in Hz                             |
+//! | 12     | 4    | number of samples                             |
+//!
+//! Samples are mono; stereo placement is done by the mixer. 8 bit PCM is
+//! unsigned (as in WAV files), 16 bit PCM is signed. IMA-ADPCM data is a
+//! single headerless block, two samples per byte, low nibble first.
+//!
+//! Assets are intended to be embedded with `include_bytes!`:
+//!
+//! ```ignore
+//! static CHIME: Sample = match Sample::from_bytes(include_bytes!("chime.pdvs")) {
+//!     Ok(sample) => sample,
+//!     Err(_) => panic!("bad sample asset"),
+//! };
+//! mixer.play(&CHIME, PlayMode::OneShot, UNITY_VOLUME, 0);
+//! ```
+
+const MAGIC: [u8; 4] = *b"PDVS";
+const HEADER_LEN: usize = 16;
+const N_ADPCM_STEPS: usize = 89;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum Encoding {
+    Pcm8 = 0,
+    Pcm16 = 1,
+    ImaAdpcm = 2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum SampleError {
+    /// The asset is shorter than its header claims.
+    Truncated,
+    /// The asset doesn't start with the expected magic number.
+    BadMagic,
+    /// The encoding byte is not a known [`Encoding`].
+    UnknownEncoding(u8),
+    /// The ADPCM step index is out of range.
+    BadStepIndex(u8),
+    /// The sample rate is zero.
+    BadSampleRate,
+}
+
+/// A mono sound sample, borrowing its data from flash.
+#[derive(Clone, Copy)]
+pub struct Sample {
+    encoding: Encoding,
+    rate: u32,
+    len: u32,
+    adpcm: AdpcmState,
+    data: &'static [u8],
+}
+
+/// Decoder state for IMA-ADPCM.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct AdpcmState {
+    pub predictor: i16,
+    pub step_index: u8,
+}
+
+/// A position within a sample, decoding as it goes.
+#[derive(Clone, Copy)]
+pub struct SampleCursor {
+    sample: &'static Sample,
+    pos: u32,
+    adpcm: AdpcmState,
+}
+
+#[rustfmt::skip]
+static ADPCM_STEPS: [u16; N_ADPCM_STEPS] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408,
+    449, 494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066,
+    2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630,
+    9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794,
+    32767,
+];
+
+static ADPCM_INDEX_ADJUST: [i8; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+impl Sample {
+    /// Parse a sample asset.
+    ///
+    /// This is a `const fn`, so that a sample can be parsed into a static,
+    /// which is what [`Sample::cursor`] and the mixer need.
+    pub const fn from_bytes(bytes: &'static [u8]) -> Result<Self, SampleError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(SampleError::Truncated);
+        }
+        let (header, data) = bytes.split_at(HEADER_LEN);
+        if header[0] != MAGIC[0]
+            || header[1] != MAGIC[1]
+            || header[2] != MAGIC[2]
+            || header[3] != MAGIC[3]
+        {
+            return Err(SampleError::BadMagic);
+        }
+        let encoding = match header[4] {
+            0 => Encoding::Pcm8,
+            1 => Encoding::Pcm16,
+            2 => Encoding::ImaAdpcm,
+            x => return Err(SampleError::UnknownEncoding(x)),
+        };
+        let step_index = header[5];
+        if step_index as usize >= N_ADPCM_STEPS {
+            return Err(SampleError::BadStepIndex(step_index));
+        }
+        let predictor = i16::from_le_bytes([header[6], header[7]]);
+        let rate = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if rate == 0 {
+            return Err(SampleError::BadSampleRate);
+        }
+        let len = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        let data_len = match encoding {
+            Encoding::Pcm8 => len as usize,
+            Encoding::Pcm16 => match (len as usize).checked_mul(2) {
+                Some(data_len) => data_len,
+                None => return Err(SampleError::Truncated),
+            },
+            Encoding::ImaAdpcm => (len as usize).div_ceil(2),
+        };
+        if data.len() < data_len {
+            return Err(SampleError::Truncated);
+        }
+        Ok(Sample {
+            encoding,
+            rate,
+            len,
+            adpcm: AdpcmState {
+                predictor,
+                step_index,
+            },
+            data: data.split_at(data_len).0,
+        })
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Sample rate in Hz.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Length in samples.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn cursor(&'static self) -> SampleCursor {
+        SampleCursor {
+            sample: self,
+            pos: 0,
+            adpcm: self.adpcm,
+        }
+    }
+}
+
+impl AdpcmState {
+    /// Decode one 4 bit IMA-ADPCM code, returning the new sample value.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn decode(&mut self, code: u8) -> i16 {
+        let step = ADPCM_STEPS[self.step_index as usize] as i32;
+        let mut diff = step >> 3;
+        if code & 1 != 0 {
+            diff += step >> 2;
+        }
+        if code & 2 != 0 {
+            diff += step >> 1;
+        }
+        if code & 4 != 0 {
+            diff += step;
+        }
+        let predictor = if code & 8 != 0 {
+            self.predictor as i32 - diff
+        } else {
+            self.predictor as i32 + diff
+        };
+        self.predictor = predictor.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let step_index = self.step_index as i32 + ADPCM_INDEX_ADJUST[code as usize & 7] as i32;
+        self.step_index = step_index.clamp(0, ADPCM_STEPS.len() as i32 - 1) as u8;
+        self.predictor
+    }
+}
+
+impl SampleCursor {
+    /// Decode the next sample, or `None` at the end of the sample.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn next_sample(&mut self) -> Option<i16> {
+        if self.pos >= self.sample.len {
+            return None;
+        }
+        let pos = self.pos as usize;
+        let data = self.sample.data;
+        let value = match self.sample.encoding {
+            Encoding::Pcm8 => ((data[pos] as i16) - 128) << 8,
+            Encoding::Pcm16 => i16::from_le_bytes([data[pos * 2], data[pos * 2 + 1]]),
+            Encoding::ImaAdpcm => {
+                let code = data[pos / 2] >> ((pos % 2) * 4);
+                self.adpcm.decode(code & 0xf)
+            }
+        };
+        self.pos += 1;
+        Some(value)
+    }
+
+    /// Return to the start of the sample.
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.adpcm = self.sample.adpcm;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdpcmState, Encoding, Sample, SampleError};
+
+    fn header(encoding: u8, step_index: u8, predictor: i16, len: u32) -> [u8; 16] {
+        let mut h = [0; 16];
+        h[0..4].copy_from_slice(b"PDVS");
+        h[4] = encoding;
+        h[5] = step_index;
+        h[6..8].copy_from_slice(&predictor.to_le_bytes());
+        h[8..12].copy_from_slice(&22_050u32.to_le_bytes());
+        h[12..16].copy_from_slice(&len.to_le_bytes());
+        h
+    }
+
+    fn leak<const N: usize>(bytes: [u8; N]) -> &'static Sample {
+        let bytes = alloc::boxed::Box::leak(alloc::boxed::Box::new(bytes));
+        alloc::boxed::Box::leak(alloc::boxed::Box::new(Sample::from_bytes(bytes).unwrap()))
+    }
+
+    #[test]
+    fn adpcm_decode() {
+        // Reference values from the IMA ADPCM specification algorithm.
+        let mut state = AdpcmState::default();
+        let codes = [7, 7, 7, 7, 7, 0, 0xf, 8, 3, 1];
+        let expected = [11, 41, 104, 240, 533, 575, 1, -81, 441, 645];
+        for (code, expected) in codes.into_iter().zip(expected) {
+            assert_eq!(state.decode(code), expected);
+        }
+        assert_eq!(state.step_index, 44);
+    }
+
+    #[test]
+    fn adpcm_clamps() {
+        let mut state = AdpcmState {
+            predictor: 32000,
+            step_index: 60,
+        };
+        for _ in 0..4 {
+            assert_eq!(state.decode(7), i16::MAX);
+        }
+        assert_eq!(state.step_index, 88);
+        let mut state = AdpcmState {
+            predictor: -32000,
+            step_index: 60,
+        };
+        for _ in 0..4 {
+            assert_eq!(state.decode(0xf), i16::MIN);
+        }
+    }
+
+    #[test]
+    fn adpcm_asset() {
+        let mut bytes = [0; 21];
+        bytes[..16].copy_from_slice(&header(2, 0, 0, 10));
+        bytes[16..].copy_from_slice(&[0x77, 0x77, 0x07, 0x8f, 0x13]);
+        let sample = leak(bytes);
+        assert_eq!(sample.encoding(), Encoding::ImaAdpcm);
+        let mut cursor = sample.cursor();
+        let expected = [11, 41, 104, 240, 533, 575, 1, -81, 441, 645];
+        for expected in expected {
+            assert_eq!(cursor.next_sample(), Some(expected));
+        }
+        assert_eq!(cursor.next_sample(), None);
+        cursor.rewind();
+        assert_eq!(cursor.next_sample(), Some(11));
+    }
+
+    #[test]
+    fn pcm_asset() {
+        let mut bytes = [0; 19];
+        bytes[..16].copy_from_slice(&header(0, 0, 0, 3));
+        bytes[16..].copy_from_slice(&[0, 128, 255]);
+        let mut cursor = leak(bytes).cursor();
+        assert_eq!(cursor.next_sample(), Some(-32768));
+        assert_eq!(cursor.next_sample(), Some(0));
+        assert_eq!(cursor.next_sample(), Some(127 << 8));
+
+        let mut bytes = [0; 20];
+        bytes[..16].copy_from_slice(&header(1, 0, 0, 2));
+        bytes[16..].copy_from_slice(&[0x34, 0x12, 0x00, 0x80]);
+        let mut cursor = leak(bytes).cursor();
+        assert_eq!(cursor.next_sample(), Some(0x1234));
+        assert_eq!(cursor.next_sample(), Some(i16::MIN));
+        assert_eq!(cursor.next_sample(), None);
+    }
+
+    #[test]
+    fn static_asset() {
+        static ASSET: [u8; 18] = *b"PDVS\x00\x00\x00\x00\x44\xac\x00\x00\x02\x00\x00\x00\x80\xff";
+        static SAMPLE: Sample = match Sample::from_bytes(&ASSET) {
+            Ok(sample) => sample,
+            Err(_) => panic!("bad sample asset"),
+        };
+        assert_eq!(SAMPLE.rate(), 44_100);
+        let mut cursor = SAMPLE.cursor();
+        assert_eq!(cursor.next_sample(), Some(0));
+        assert_eq!(cursor.next_sample(), Some(127 << 8));
+        assert_eq!(cursor.next_sample(), None);
+    }
+
+    #[test]
+    fn bad_assets() {
+        static SHORT: [u8; 4] = *b"PDVS";
+        assert_eq!(
+            Sample::from_bytes(&SHORT).err(),
+            Some(SampleError::Truncated)
+        );
+        let bytes = alloc::boxed::Box::leak(alloc::boxed::Box::new(header(3, 0, 0, 0)));
+        assert_eq!(
+            Sample::from_bytes(bytes).err(),
+            Some(SampleError::UnknownEncoding(3))
+        );
+        let bytes = alloc::boxed::Box::leak(alloc::boxed::Box::new(header(1, 0, 0, 1)));
+        assert_eq!(
+            Sample::from_bytes(bytes).err(),
+            Some(SampleError::Truncated)
+        );
+        let bytes = alloc::boxed::Box::leak(alloc::boxed::Box::new(header(1, 0, 0, u32::MAX)));
+        assert_eq!(
+            Sample::from_bytes(bytes).err(),
+            Some(SampleError::Truncated)
+        );
+    }
+}
@@ -1,85 +1,141 @@
 use crate::hal::{
-    clocks::{ClockSource, ClocksManager},
-    fugit::{KilohertzU32, MegahertzU32, RateExtU32},
+    clocks::{ClockSource, ClocksManager, StoppableClock},
+    fugit::{HertzU32, KilohertzU32, RateExtU32},
     pac,
-    pll::{
-        self,
-        common_configs::{PLL_SYS_150MHZ, PLL_USB_48MHZ},
-        setup_pll_blocking, PLLConfig, PhaseLockedLoop,
-    },
+    pll::{self, common_configs::PLL_USB_48MHZ, setup_pll_blocking, PLLConfig, PhaseLockedLoop},
     rosc::RingOscillator,
     xosc::{self, setup_xosc_blocking, CrystalOscillator},
     Clock, Watchdog,
 };
 
-struct ClockCfg {
-    vco_freq: KilohertzU32,
-    post_div1: u32,
-    post_div2: u32,
-}
+mod scaling;
+mod search;
+
+pub use scaling::{ClockLimits, CoreVoltage, RATED_HSTX_CLOCK, SAFE_CLOCK_LIMITS};
+pub use search::PllSetting;
 
-// Values taken from pico-sdk hardware_pll/include/hardware/pll.h
-const PICO_PLL_VCO_MIN_FREQ: MegahertzU32 = MegahertzU32::MHz(750);
-const PICO_PLL_VCO_MAX_FREQ: MegahertzU32 = MegahertzU32::MHz(1600);
 const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
-/// Determine PLL parameters for target frequency
+/// Writes to POWMAN registers are ignored without this in the top half.
+const POWMAN_PASSWORD: u32 = 0x5afe << 16;
+
+/// Time for the regulator output to settle after a change, in cycles of
+/// the boot clock, which is at most 150 MHz.
+const VREG_SETTLE_CYCLES: u32 = 150_000;
+
+/// How far the system clock may be from the requested frequency.
 ///
-/// Logic is adapted from check_sys_clock_khz in pico-sdk
-#[doc(alias = "check_sys_clock_khz", alias = "vcocalc")]
-fn configure_sys_clock(requested_freq: KilohertzU32) -> Option<ClockCfg> {
-    let crystal_freq: KilohertzU32 = XOSC_CRYSTAL_FREQ.Hz();
-
-    // Its called a feedback divider but it really is a clock multiplier
-    // see 2.18.2 in rp2040-datasheet.pdf
-    for feedback_divider in (16..=320).rev() {
-        let vco_freq: KilohertzU32 = feedback_divider * crystal_freq;
-
-        // Stop the loop since all consecutive numbers will also be less than this
-        if vco_freq < PICO_PLL_VCO_MIN_FREQ {
-            break;
-        }
+/// DVI sinks accept a pixel clock within 0.5% of nominal.
+const MAX_CLOCK_ERROR_PPM: u32 = 5000;
 
-        if vco_freq > PICO_PLL_VCO_MAX_FREQ {
-            continue;
-        }
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ClockError {
+    /// The nearest the PLL can get to `requested` is off by more than the
+    /// allowed error.
+    OutOfTolerance {
+        requested: HertzU32,
+        nearest: PllSetting,
+    },
+    /// The system clock would be faster than `max`.
+    TooFast { max: HertzU32 },
+    /// The HSTX clock would be faster than `max`.
+    HstxTooFast { max: HertzU32 },
+    /// The system clock needs a higher core voltage than the limit allows.
+    CoreVoltage {
+        needed: CoreVoltage,
+        max: CoreVoltage,
+    },
+}
 
-        for post_div1 in (1..=7).rev() {
-            for post_div2 in (1..=post_div1).rev() {
-                let divider = post_div1 * post_div2;
+/// The system clock the RP2350 is rated for at the default core voltage.
+pub const RATED_SYS_CLOCK: KilohertzU32 = KilohertzU32::kHz(150_000);
 
-                let output_frequency: KilohertzU32 = vco_freq / divider;
+/// Where the HSTX clock comes from.
+#[derive(Clone, Copy)]
+pub enum HstxClockSource {
+    /// Divide down the system clock, which then has to run at `divisor`
+    /// times the HSTX clock. See [`HstxMultiple`].
+    ///
+    /// [`HstxMultiple`]: crate::dvi::HstxMultiple
+    SystemClock { divisor: u32 },
+    /// Run PLL_USB at the HSTX clock, leaving the system clock free to run
+    /// at `sys_clock`. USB and the ADC are left without a clock.
+    PllUsb { sys_clock: KilohertzU32 },
+}
 
-                // Doing this instead of % to work around https://github.com/korken89/fugit/issues/41
-                // Ensure the vco_freq is divisible by the clock dividers
-                let vco_freq_divisible = output_frequency * divider == vco_freq;
+/// The clocks [`init_clocks`] should set up.
+#[derive(Clone, Copy)]
+pub struct ClockConfig {
+    /// The HSTX clock, which is half the bit clock as HSTX shifts out on
+    /// both edges, unless [`HstxEdges::Single`] is chosen.
+    ///
+    /// [`HstxEdges::Single`]: crate::dvi::HstxEdges::Single
+    pub hstx_clock: KilohertzU32,
+    pub hstx_source: HstxClockSource,
+    pub limits: ClockLimits,
+}
 
-                if output_frequency == requested_freq && vco_freq_divisible {
-                    return Some(ClockCfg {
-                        vco_freq,
-                        post_div1,
-                        post_div2,
-                    });
-                }
-            }
+impl ClockConfig {
+    /// The system clock to aim for.
+    pub fn sys_clock(&self) -> KilohertzU32 {
+        match self.hstx_source {
+            HstxClockSource::SystemClock { divisor } => self.hstx_clock * divisor,
+            HstxClockSource::PllUsb { sys_clock } => sys_clock,
         }
     }
-
-    None
 }
 
 /// Since we need to overclock the pico, we need to set these clocks up ourselves
+///
+/// The system clock, and PLL_USB if it drives HSTX, are set as close to
+/// the requested frequencies as the PLLs can get, with the core voltage and
+/// flash clock divider to match the system clock. This fails before
+/// touching the hardware if the result isn't close enough or would go past
+/// the limits.
+#[allow(clippy::too_many_arguments)]
 pub fn init_clocks(
     xosc: pac::XOSC,
     rosc: pac::ROSC,
     clocks: pac::CLOCKS,
     pll_sys: pac::PLL_SYS,
     pll_usb: pac::PLL_USB,
+    powman: pac::POWMAN,
+    qmi: pac::QMI,
     resets: &mut pac::RESETS,
     watchdog: &mut Watchdog,
-    freq_khz: KilohertzU32,
-    hstx_divisor: u32,
-) -> ClocksManager {
+    config: &ClockConfig,
+) -> Result<ClocksManager, ClockError> {
+    scaling::check_hstx_clock(config.hstx_clock.convert(), &config.limits)?;
+    let sys_pll = search::find(
+        XOSC_CRYSTAL_FREQ.Hz(),
+        config.sys_clock().convert(),
+        MAX_CLOCK_ERROR_PPM,
+    )?;
+    let usb_pll = match config.hstx_source {
+        HstxClockSource::SystemClock { .. } => None,
+        HstxClockSource::PllUsb { .. } => Some(search::find(
+            XOSC_CRYSTAL_FREQ.Hz(),
+            config.hstx_clock.convert(),
+            MAX_CLOCK_ERROR_PPM,
+        )?),
+    };
+    let voltage = scaling::core_voltage(sys_pll.freq, &config.limits)?;
+    let flash_clkdiv = scaling::flash_clkdiv(sys_pll.freq, &config.limits);
+    defmt::info!(
+        "System clock {} Hz ({} ppm from requested), core voltage {} mV, flash clock divider {}",
+        sys_pll.freq.to_Hz(),
+        sys_pll.error_ppm,
+        voltage.millivolts(),
+        flash_clkdiv
+    );
+    if let Some(usb_pll) = usb_pll {
+        defmt::info!(
+            "HSTX clock from PLL_USB at {} Hz ({} ppm from requested)",
+            usb_pll.freq.to_Hz(),
+            usb_pll.error_ppm
+        );
+    }
+
     // Enable the xosc
     let xosc = setup_xosc_blocking(xosc, XOSC_CRYSTAL_FREQ.Hz())
         .expect("crystal oscillator should be configured");
@@ -91,32 +147,17 @@ pub fn init_clocks(
 
     let mut clocks = ClocksManager::new(clocks);
 
-    let clk_cfg = configure_sys_clock(freq_khz);
-    let pll_config = match clk_cfg {
-        Some(ClockCfg {
-            vco_freq,
-            post_div1,
-            post_div2,
-        }) => PLLConfig {
-            vco_freq: vco_freq.convert(),
-            refdiv: 1,
-            post_div1: post_div1 as u8,
-            post_div2: post_div2 as u8,
-        },
-        None => PLL_SYS_150MHZ,
-    };
-
-    // INFO: Overclock to 10 * 25.175 MHz ~= 252 MHz for mandatory minimum DVI output resolution: VGA (640x480) @ 60 Hz
-    // Section following comes from https://docs.rs/rp2040-hal/latest/rp2040_hal/clocks/index.html#usage-extended
+    // The boot clock is at most 150 MHz, which both of these suit, so they
+    // can go ahead of switching to the PLL.
+    set_core_voltage(&powman, voltage);
+    set_flash_clkdiv(&qmi, flash_clkdiv);
 
-    // Configure PLLs
-    //                   REF     FBDIV VCO            POSTDIV
-    // PLL SYS: 12 / 1 = 12MHz * 125 = 1512MHZ / 6 / 1 = 252MHz
-    // PLL USB: 12 / 1 = 12MHz * 40  = 480 MHz / 5 / 2 =  48MHz
+    // The PLL dividers were found by the search above, and logged. PLL USB
+    // runs at 48 MHz unless it was chosen to clock HSTX.
     let pll_sys = setup_pll_blocking(
         pll_sys,
         xosc.operating_frequency(),
-        pll_config,
+        pll_config(&sys_pll),
         &mut clocks,
         resets,
     )
@@ -124,18 +165,58 @@ pub fn init_clocks(
     let pll_usb = setup_pll_blocking(
         pll_usb,
         xosc.operating_frequency(),
-        PLL_USB_48MHZ,
+        usb_pll.as_ref().map_or(PLL_USB_48MHZ, pll_config),
         &mut clocks,
         resets,
     )
-    .expect("sys pll should be configured");
+    .expect("usb pll should be configured");
 
-    let clocks = configure_clocks(clocks, xosc, pll_sys, pll_usb, hstx_divisor);
+    let clocks = configure_clocks(clocks, xosc, pll_sys, pll_usb, config.hstx_source);
 
     // Disable Ring Oscillator
     rosc.disable();
 
-    clocks
+    Ok(clocks)
+}
+
+fn pll_config(setting: &PllSetting) -> PLLConfig {
+    PLLConfig {
+        vco_freq: setting.vco_freq(XOSC_CRYSTAL_FREQ.Hz()),
+        refdiv: setting.refdiv,
+        post_div1: setting.post_div1,
+        post_div2: setting.post_div2,
+    }
+}
+
+/// Set the core voltage, waiting for it to settle.
+fn set_core_voltage(powman: &pac::POWMAN, voltage: CoreVoltage) {
+    let unlimited = voltage > CoreVoltage::UNLOCKED_MAX;
+    powman.vreg_ctrl().modify(|r, w| unsafe {
+        w.bits(r.bits() | POWMAN_PASSWORD)
+            .unlock()
+            .set_bit()
+            .disable_voltage_limit()
+            .bit(unlimited)
+    });
+    while powman.vreg().read().update_in_progress().bit_is_set() {}
+    powman.vreg().modify(|r, w| unsafe {
+        w.bits(r.bits() | POWMAN_PASSWORD)
+            .vsel()
+            .bits(voltage.vsel())
+    });
+    while powman.vreg().read().update_in_progress().bit_is_set() {}
+    cortex_m::asm::delay(VREG_SETTLE_CYCLES);
+}
+
+/// Set the divider from the system clock to the flash QSPI clock.
+///
+/// This runs from RAM so that no instruction fetch from flash is in
+/// flight while the timing changes.
+#[inline(never)]
+#[link_section = ".data"]
+fn set_flash_clkdiv(qmi: &pac::QMI, clkdiv: u8) {
+    qmi.m0_timing()
+        .modify(|_, w| unsafe { w.clkdiv().bits(clkdiv) });
 }
 
 fn configure_clocks(
@@ -143,18 +224,46 @@ fn configure_clocks(
     xosc: CrystalOscillator<xosc::Stable>,
     pll_sys: PhaseLockedLoop<pll::Locked, pac::PLL_SYS>,
     pll_usb: PhaseLockedLoop<pll::Locked, pac::PLL_USB>,
-    hstx_divisor: u32,
+    hstx_source: HstxClockSource,
 ) -> ClocksManager {
-    clocks.init_default(&xosc, &pll_sys, &pll_usb).unwrap();
+    match hstx_source {
+        HstxClockSource::SystemClock { divisor } => {
+            clocks.init_default(&xosc, &pll_sys, &pll_usb).unwrap();
 
-    // CLK HSTX = same as system clock
-    clocks
-        .hstx_clock
-        .configure_clock(
-            &clocks.system_clock,
-            clocks.system_clock.get_freq() / hstx_divisor,
-        )
-        .unwrap();
+            // CLK HSTX = system clock / divisor
+            clocks
+                .hstx_clock
+                .configure_clock(
+                    &clocks.system_clock,
+                    clocks.system_clock.get_freq() / divisor,
+                )
+                .unwrap();
+        }
+        HstxClockSource::PllUsb { .. } => {
+            // As init_default, but PLL_USB isn't at the 48 MHz USB and the
+            // ADC need, so they're stopped instead.
+            clocks
+                .reference_clock
+                .configure_clock(&xosc, xosc.get_freq())
+                .unwrap();
+            clocks
+                .system_clock
+                .configure_clock(&pll_sys, pll_sys.get_freq())
+                .unwrap();
+            clocks
+                .peripheral_clock
+                .configure_clock(&clocks.system_clock, clocks.system_clock.freq())
+                .unwrap();
+            clocks.usb_clock.disable();
+            clocks.adc_clock.disable();
+
+            // CLK HSTX = PLL USB
+            clocks
+                .hstx_clock
+                .configure_clock(&pll_usb, pll_usb.get_freq())
+                .unwrap();
+        }
+    }
 
     clocks
 }
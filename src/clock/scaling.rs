@@ -0,0 +1,220 @@
+//! Scaling the core voltage and flash clock with the system clock.
+//!
+//! The RP2350 is rated for 150 MHz at the default 1.10 V. Faster system
+//! clocks need more core voltage to run reliably, and the flash needs a
+//! larger QSPI clock divider to stay within its own rating.
+
+use fugit::HertzU32;
+
+use super::ClockError;
+
+/// A core voltage, as the regulator's `VSEL` value.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum CoreVoltage {
+    V1_10 = 0b01011,
+    V1_15 = 0b01100,
+    V1_20 = 0b01101,
+    V1_25 = 0b01110,
+    V1_30 = 0b01111,
+    V1_35 = 0b10000,
+    V1_40 = 0b10001,
+    V1_50 = 0b10010,
+}
+
+impl CoreVoltage {
+    /// The highest voltage the regulator accepts without disabling its
+    /// voltage limit.
+    pub const UNLOCKED_MAX: Self = CoreVoltage::V1_30;
+
+    pub const fn vsel(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn millivolts(self) -> u32 {
+        match self {
+            CoreVoltage::V1_10 => 1100,
+            CoreVoltage::V1_15 => 1150,
+            CoreVoltage::V1_20 => 1200,
+            CoreVoltage::V1_25 => 1250,
+            CoreVoltage::V1_30 => 1300,
+            CoreVoltage::V1_35 => 1350,
+            CoreVoltage::V1_40 => 1400,
+            CoreVoltage::V1_50 => 1500,
+        }
+    }
+}
+
+/// The voltage needed for each range of system clock, up to the given
+/// frequency.
+///
+/// These are conservative; many chips manage with less, but margins vary
+/// from part to part.
+const VOLTAGE_STEPS: [(HertzU32, CoreVoltage); 6] = [
+    (HertzU32::MHz(150), CoreVoltage::V1_10),
+    (HertzU32::MHz(200), CoreVoltage::V1_15),
+    (HertzU32::MHz(250), CoreVoltage::V1_20),
+    (HertzU32::MHz(300), CoreVoltage::V1_25),
+    (HertzU32::MHz(350), CoreVoltage::V1_30),
+    (HertzU32::MHz(400), CoreVoltage::V1_40),
+];
+
+/// The flash clock divider is never set below this. At 1 the QMI needs
+/// careful receive delay tuning.
+const FLASH_CLKDIV_MIN: u32 = 2;
+
+/// How far [`init_clocks`](super::init_clocks) may push the chip.
+#[derive(Clone, Copy)]
+pub struct ClockLimits {
+    /// The fastest system clock to attempt.
+    pub max_sys_clock: HertzU32,
+    /// The highest core voltage to apply. Above
+    /// [`CoreVoltage::UNLOCKED_MAX`] this also disables the regulator's
+    /// voltage limit.
+    pub max_core_voltage: CoreVoltage,
+    /// The fastest QSPI clock the flash is rated for.
+    pub max_flash_clock: HertzU32,
+    /// The fastest HSTX clock to attempt.
+    pub max_hstx_clock: HertzU32,
+}
+
+/// Limits that keep the core voltage within what the regulator allows
+/// by default.
+pub const SAFE_CLOCK_LIMITS: ClockLimits = ClockLimits {
+    max_sys_clock: HertzU32::MHz(350),
+    max_core_voltage: CoreVoltage::UNLOCKED_MAX,
+    max_flash_clock: HertzU32::MHz(133),
+    max_hstx_clock: RATED_HSTX_CLOCK,
+};
+
+/// The HSTX clock the RP2350 is rated for. Shifting out on both edges,
+/// that is a 300 Mbps bit clock.
+pub const RATED_HSTX_CLOCK: HertzU32 = HertzU32::MHz(150);
+
+/// Check that HSTX can run at `hstx_clk`.
+pub fn check_hstx_clock(hstx_clk: HertzU32, limits: &ClockLimits) -> Result<(), ClockError> {
+    if hstx_clk > limits.max_hstx_clock {
+        return Err(ClockError::HstxTooFast {
+            max: limits.max_hstx_clock,
+        });
+    }
+    Ok(())
+}
+
+/// Pick the core voltage to run the system at `sys_clk`.
+pub fn core_voltage(sys_clk: HertzU32, limits: &ClockLimits) -> Result<CoreVoltage, ClockError> {
+    if sys_clk > limits.max_sys_clock {
+        return Err(ClockError::TooFast {
+            max: limits.max_sys_clock,
+        });
+    }
+    let Some(&(_, needed)) = VOLTAGE_STEPS.iter().find(|(max, _)| sys_clk <= *max) else {
+        return Err(ClockError::TooFast {
+            max: VOLTAGE_STEPS[VOLTAGE_STEPS.len() - 1].0,
+        });
+    };
+    if needed > limits.max_core_voltage {
+        return Err(ClockError::CoreVoltage {
+            needed,
+            max: limits.max_core_voltage,
+        });
+    }
+    Ok(needed)
+}
+
+/// The smallest QSPI clock divider that keeps the flash within its rating.
+pub fn flash_clkdiv(sys_clk: HertzU32, limits: &ClockLimits) -> u8 {
+    let clkdiv = sys_clk
+        .to_Hz()
+        .div_ceil(limits.max_flash_clock.to_Hz())
+        .max(FLASH_CLKDIV_MIN);
+    // The divider field is 8 bits, and 0 means 256.
+    clkdiv.min(255) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use fugit::HertzU32;
+
+    use super::{
+        check_hstx_clock, core_voltage, flash_clkdiv, ClockLimits, CoreVoltage, SAFE_CLOCK_LIMITS,
+    };
+    use crate::clock::ClockError;
+
+    #[test]
+    fn hstx_clock() {
+        let limits = &SAFE_CLOCK_LIMITS;
+        // VGA shifting out on both edges, then on one.
+        assert_eq!(check_hstx_clock(HertzU32::MHz(126), limits), Ok(()));
+        assert_eq!(
+            check_hstx_clock(HertzU32::MHz(252), limits),
+            Err(ClockError::HstxTooFast {
+                max: HertzU32::MHz(150)
+            })
+        );
+        let limits = ClockLimits {
+            max_hstx_clock: HertzU32::MHz(252),
+            ..SAFE_CLOCK_LIMITS
+        };
+        assert_eq!(check_hstx_clock(HertzU32::MHz(252), &limits), Ok(()));
+    }
+
+    #[test]
+    fn voltage() {
+        let limits = &SAFE_CLOCK_LIMITS;
+        assert_eq!(
+            core_voltage(HertzU32::MHz(126), limits),
+            Ok(CoreVoltage::V1_10)
+        );
+        assert_eq!(
+            core_voltage(HertzU32::MHz(150), limits),
+            Ok(CoreVoltage::V1_10)
+        );
+        assert_eq!(
+            core_voltage(HertzU32::MHz(252), limits),
+            Ok(CoreVoltage::V1_25)
+        );
+        assert_eq!(
+            core_voltage(HertzU32::kHz(371_250), limits),
+            Err(ClockError::TooFast {
+                max: HertzU32::MHz(350)
+            })
+        );
+        let limits = ClockLimits {
+            max_sys_clock: HertzU32::MHz(400),
+            ..SAFE_CLOCK_LIMITS
+        };
+        assert_eq!(
+            core_voltage(HertzU32::kHz(371_250), &limits),
+            Err(ClockError::CoreVoltage {
+                needed: CoreVoltage::V1_40,
+                max: CoreVoltage::V1_30
+            })
+        );
+        let limits = ClockLimits {
+            max_sys_clock: HertzU32::MHz(500),
+            max_core_voltage: CoreVoltage::V1_50,
+            ..SAFE_CLOCK_LIMITS
+        };
+        assert_eq!(
+            core_voltage(HertzU32::kHz(371_250), &limits),
+            Ok(CoreVoltage::V1_40)
+        );
+        assert_eq!(
+            core_voltage(HertzU32::MHz(450), &limits),
+            Err(ClockError::TooFast {
+                max: HertzU32::MHz(400)
+            })
+        );
+    }
+
+    #[test]
+    fn flash() {
+        let limits = &SAFE_CLOCK_LIMITS;
+        assert_eq!(flash_clkdiv(HertzU32::MHz(126), limits), 2);
+        assert_eq!(flash_clkdiv(HertzU32::MHz(252), limits), 2);
+        assert_eq!(flash_clkdiv(HertzU32::MHz(300), limits), 3);
+        assert_eq!(flash_clkdiv(HertzU32::kHz(371_250), limits), 3);
+        assert_eq!(flash_clkdiv(HertzU32::MHz(400), limits), 4);
+    }
+}
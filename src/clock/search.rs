@@ -0,0 +1,184 @@
+//! Searching for PLL settings.
+//!
+//! The PLL divides the crystal by `refdiv`, multiplies that reference by
+//! `fbdiv` to run the VCO, then divides the VCO by two post dividers. Not
+//! every frequency is reachable, so [`find`] tries every combination the
+//! hardware allows and reports how close the best one gets.
+
+use fugit::HertzU32;
+
+use super::ClockError;
+
+// Limits from the PLL section of the RP2350 datasheet; the VCO range
+// matches pico-sdk hardware_pll/include/hardware/pll.h
+const VCO_MIN_FREQ: u64 = 750_000_000;
+const VCO_MAX_FREQ: u64 = 1_600_000_000;
+const REF_MIN_FREQ: u32 = 5_000_000;
+const REFDIV_MAX: u32 = 63;
+const FBDIV_MIN: u32 = 16;
+const FBDIV_MAX: u32 = 320;
+const POST_DIV_MAX: u32 = 7;
+
+/// A PLL configuration and the frequency it produces.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct PllSetting {
+    pub refdiv: u8,
+    pub fbdiv: u16,
+    pub post_div1: u8,
+    pub post_div2: u8,
+    /// The output frequency, rounded to the nearest hertz.
+    pub freq: HertzU32,
+    /// How far `freq` is from the requested frequency, in parts per million.
+    pub error_ppm: i32,
+}
+
+impl PllSetting {
+    pub fn vco_freq(&self, xosc: HertzU32) -> HertzU32 {
+        xosc / self.refdiv as u32 * self.fbdiv as u32
+    }
+}
+
+/// Find the PLL setting closest to `requested`.
+///
+/// Ties go to the lowest `refdiv` and then the highest VCO frequency, as
+/// both reduce jitter. Fails if the closest setting is off by more than
+/// `max_error_ppm`.
+#[doc(alias = "check_sys_clock_khz", alias = "vcocalc")]
+pub fn find(
+    xosc: HertzU32,
+    requested: HertzU32,
+    max_error_ppm: u32,
+) -> Result<PllSetting, ClockError> {
+    let xosc = xosc.to_Hz();
+    let target = requested.to_Hz() as u64;
+    // (error in millihertz, refdiv, fbdiv, post_div1, post_div2)
+    let mut best: Option<(u64, u32, u32, u32, u32)> = None;
+    for refdiv in 1..=REFDIV_MAX {
+        let ref_freq = xosc / refdiv;
+        if ref_freq < REF_MIN_FREQ {
+            break;
+        }
+        // The hal recomputes fbdiv from the VCO frequency, which is only
+        // exact when the reference is a whole number of hertz.
+        if xosc % refdiv != 0 {
+            continue;
+        }
+        for fbdiv in (FBDIV_MIN..=FBDIV_MAX).rev() {
+            let vco_freq = ref_freq as u64 * fbdiv as u64;
+            if vco_freq < VCO_MIN_FREQ {
+                break;
+            }
+            if vco_freq > VCO_MAX_FREQ {
+                continue;
+            }
+            for post_div1 in (1..=POST_DIV_MAX).rev() {
+                for post_div2 in (1..=post_div1).rev() {
+                    let divider = (post_div1 * post_div2) as u64;
+                    let error = (vco_freq * 1000).abs_diff(target * 1000 * divider) / divider;
+                    if best.is_none_or(|(best_error, ..)| error < best_error) {
+                        best = Some((error, refdiv, fbdiv, post_div1, post_div2));
+                    }
+                }
+            }
+        }
+    }
+    let (_, refdiv, fbdiv, post_div1, post_div2) = best.expect("crystal should drive the PLL");
+
+    let vco_freq = (xosc / refdiv) as u64 * fbdiv as u64;
+    let divider = (post_div1 * post_div2) as u64;
+    let freq = (vco_freq + divider / 2) / divider;
+    let error_ppm = match target {
+        0 => i32::MAX,
+        _ => {
+            let ppm = (vco_freq as i64 - (target * divider) as i64) * 1_000_000
+                / (target * divider) as i64;
+            ppm.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+        }
+    };
+    let setting = PllSetting {
+        refdiv: refdiv as u8,
+        fbdiv: fbdiv as u16,
+        post_div1: post_div1 as u8,
+        post_div2: post_div2 as u8,
+        freq: HertzU32::Hz(freq as u32),
+        error_ppm,
+    };
+    if error_ppm.unsigned_abs() > max_error_ppm {
+        return Err(ClockError::OutOfTolerance {
+            requested,
+            nearest: setting,
+        });
+    }
+    Ok(setting)
+}
+
+#[cfg(test)]
+mod test {
+    use fugit::HertzU32;
+
+    use super::{find, PllSetting};
+    use crate::clock::ClockError;
+
+    const XOSC: HertzU32 = HertzU32::MHz(12);
+
+    #[test]
+    fn exact() {
+        let setting = find(XOSC, HertzU32::MHz(126), 0).unwrap();
+        assert_eq!(
+            setting,
+            PllSetting {
+                refdiv: 1,
+                fbdiv: 126,
+                post_div1: 6,
+                post_div2: 2,
+                freq: HertzU32::MHz(126),
+                error_ppm: 0,
+            }
+        );
+        assert_eq!(setting.vco_freq(XOSC), HertzU32::MHz(1512));
+    }
+
+    #[test]
+    fn refdiv() {
+        // 251 MHz needs a VCO at an odd multiple of 6 MHz.
+        let setting = find(XOSC, HertzU32::MHz(251), 0).unwrap();
+        assert_eq!((setting.refdiv, setting.fbdiv), (2, 251));
+        assert_eq!(setting.freq, HertzU32::MHz(251));
+        assert_eq!(setting.vco_freq(XOSC), HertzU32::MHz(1506));
+    }
+
+    #[test]
+    fn nearest() {
+        // 5 × 25.175 MHz, the VGA bit clock at half rate.
+        let setting = find(XOSC, HertzU32::kHz(125_875), 1000).unwrap();
+        assert_eq!(setting.freq, HertzU32::MHz(126));
+        assert_eq!(setting.error_ppm, 993);
+        assert_eq!(
+            find(XOSC, HertzU32::kHz(125_875), 900),
+            Err(ClockError::OutOfTolerance {
+                requested: HertzU32::kHz(125_875),
+                nearest: setting,
+            })
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        // The lowest the PLL can go is 750 MHz / 49.
+        let Err(ClockError::OutOfTolerance { nearest, .. }) = find(XOSC, HertzU32::MHz(10), 5000)
+        else {
+            panic!("10 MHz should be unreachable");
+        };
+        assert_eq!(
+            nearest,
+            PllSetting {
+                refdiv: 2,
+                fbdiv: 125,
+                post_div1: 7,
+                post_div2: 7,
+                freq: HertzU32::Hz(15_306_122),
+                error_ppm: 530_612,
+            }
+        );
+    }
+}
@@ -1,11 +1,16 @@
 use alloc::format;
 use embedded_hal::digital::StatefulOutputPin;
 
+use pico_dvi_rs::{
+    dvi::{self, UnderrunPolicy, VERTICAL_REPEAT},
+    render::{rgb, BW_PALETTE_1BPP, FONT_HEIGHT},
+    scanlist::ScanlistError,
+};
+
 use crate::{
-    dvi::VERTICAL_REPEAT,
     hal::gpio::{FunctionSioOutput, Pin, PinId, PullDown},
-    render::{end_display_list, rgb, start_display_list, BW_PALETTE_1BPP, FONT_HEIGHT},
-    PALETTE_4BPP,
+    scanout::{end_display_list, start_display_list},
+    DVI_OUT, PALETTE_4BPP,
 };
 
 use self::conway::GameOfLife;
@@ -19,6 +24,11 @@ const TILE_DATA: &[u32] = &[
     0x44444444, 0x44454444, 0x44444444, 0x44454444, 0x66444444, 0x44454444, 0x44444444, 0x44454444,
 ];
 
+/// Log video output statistics this often, in frames, or never if 0.
+///
+/// The default is about every ten seconds at 60 Hz.
+const LOG_STATS_INTERVAL: u32 = 600;
+
 struct Counter<P: PinId> {
     led_pin: Pin<P, FunctionSioOutput, PullDown>,
     count: u32,
@@ -28,14 +38,17 @@ impl<P: PinId> Counter<P> {
     // We might just want to move the led pin into the serializer,
     // but for the moment we let the app continue to own it.
     fn count(&mut self) {
-        if self.count % 15 == 0 {
+        if self.count.is_multiple_of(15) {
             self.led_pin.toggle().unwrap();
         }
         self.count = self.count.wrapping_add(1);
+        if LOG_STATS_INTERVAL != 0 && self.count.is_multiple_of(LOG_STATS_INTERVAL) {
+            dvi::stats().log();
+        }
     }
 }
 
-fn colorbars<P: PinId>(counter: &Counter<P>) {
+fn colorbars<P: PinId>(counter: &Counter<P>) -> Result<(), ScanlistError> {
     let height = 480 / VERTICAL_REPEAT as u32;
     let (mut rb, mut sb) = start_display_list();
     rb.begin_stripe(height - FONT_HEIGHT);
@@ -48,7 +61,7 @@ fn colorbars<P: PinId>(counter: &Counter<P>) {
     sb.solid(92, rgb(0xc0, 0, 0xc0));
     sb.solid(90, rgb(0xc0, 0, 0));
     sb.solid(92, rgb(0, 0, 0xc0));
-    sb.end_stripe();
+    sb.end_stripe()?;
     sb.begin_stripe(40 / VERTICAL_REPEAT as u32);
     sb.solid(92, rgb(0, 0, 0xc0));
     sb.solid(90, rgb(0x13, 0x13, 0x13));
@@ -57,7 +70,7 @@ fn colorbars<P: PinId>(counter: &Counter<P>) {
     sb.solid(92, rgb(0, 0xc0, 0xc0));
     sb.solid(90, rgb(0x13, 0x13, 0x13));
     sb.solid(92, rgb(0xc0, 0xc0, 0xc0));
-    sb.end_stripe();
+    sb.end_stripe()?;
     sb.begin_stripe(120 / VERTICAL_REPEAT as u32 - FONT_HEIGHT);
     sb.solid(114, rgb(0, 0x21, 0x4c));
     sb.solid(114, rgb(0xff, 0xff, 0xff));
@@ -67,7 +80,7 @@ fn colorbars<P: PinId>(counter: &Counter<P>) {
     sb.solid(30, rgb(0x13, 0x13, 0x13));
     sb.solid(30, rgb(0x1d, 0x1d, 0x1d));
     sb.solid(92, rgb(0x13, 0x13, 0x13));
-    sb.end_stripe();
+    sb.end_stripe()?;
     rb.begin_stripe(FONT_HEIGHT);
     let text = format!("Hello pico-dvi-rs, frame {}", counter.count);
     let width = rb.text(&text);
@@ -76,11 +89,11 @@ fn colorbars<P: PinId>(counter: &Counter<P>) {
     sb.begin_stripe(FONT_HEIGHT);
     sb.pal_1bpp(width, &BW_PALETTE_1BPP);
     sb.solid(640 - width, rgb(0, 0, 0));
-    sb.end_stripe();
-    end_display_list(rb, sb);
+    sb.end_stripe()?;
+    end_display_list(rb, sb)
 }
 
-fn tiles<P: PinId>(counter: &Counter<P>) {
+fn tiles<P: PinId>(counter: &Counter<P>) -> Result<(), ScanlistError> {
     let (mut rb, mut sb) = start_display_list();
     let anim_frame = counter.count % 240;
     let (x_off, y_off) = if anim_frame < 60 {
@@ -112,10 +125,8 @@ fn tiles<P: PinId>(counter: &Counter<P>) {
         y += this_height;
     }
     sb.begin_stripe(tiled_height);
-    unsafe {
-        sb.pal_4bpp(640, &PALETTE_4BPP);
-    }
-    sb.end_stripe();
+    sb.pal_4bpp(640, &PALETTE_4BPP);
+    sb.end_stripe()?;
     rb.begin_stripe(FONT_HEIGHT);
     let text = format!("Hello pico-dvi-rs, frame {}", counter.count);
     let width = rb.text(&text);
@@ -124,22 +135,28 @@ fn tiles<P: PinId>(counter: &Counter<P>) {
     sb.begin_stripe(FONT_HEIGHT);
     sb.pal_1bpp(width, &BW_PALETTE_1BPP);
     sb.solid(640 - width, rgb(0, 0, 0));
-    sb.end_stripe();
-    end_display_list(rb, sb);
+    sb.end_stripe()?;
+    end_display_list(rb, sb)
 }
 
 pub fn demo<P: PinId>(led_pin: Pin<P, FunctionSioOutput, PullDown>) -> ! {
     let mut counter = Counter { led_pin, count: 0 };
     let mut game_of_life = GameOfLife::new(include_str!("demo/universe.txt"));
 
+    // Underruns show as red lines in development builds, and are hidden
+    // in release builds.
+    if !cfg!(debug_assertions) {
+        DVI_OUT.set_underrun_policy(UnderrunPolicy::RepeatLine);
+    }
+
     loop {
         for _ in 0..120 {
             counter.count();
-            colorbars(&counter);
+            colorbars(&counter).unwrap();
         }
         for _ in 0..240 {
             counter.count();
-            tiles(&counter);
+            tiles(&counter).unwrap();
         }
         for i in 0..240 {
             counter.count();
@@ -147,7 +164,7 @@ pub fn demo<P: PinId>(led_pin: Pin<P, FunctionSioOutput, PullDown>) -> ! {
             if i % 5 == 0 {
                 game_of_life.tick();
             }
-            game_of_life.render(&counter);
+            game_of_life.render(&counter).unwrap();
         }
     }
 }
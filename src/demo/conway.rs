@@ -1,17 +1,16 @@
 use core::ops::Range;
 
-use crate::{
-    hal::gpio::PinId,
-    render::{Palette1bpp, BW_PALETTE_1BPP},
-};
+use crate::hal::gpio::PinId;
 use alloc::format;
-
-use super::Counter;
-use crate::{
+use pico_dvi_rs::{
     dvi::VERTICAL_REPEAT,
-    render::{end_display_list, rgb, start_display_list, xrgb, FONT_HEIGHT},
+    render::{rgb, xrgb, Palette1bpp, BW_PALETTE_1BPP, FONT_HEIGHT},
+    scanlist::ScanlistError,
 };
 
+use super::Counter;
+use crate::scanout::{end_display_list, start_display_list};
+
 // Sadly these can not be generic on GameOfLife struct due to limitations with const-generics
 const BOARD_WIDTH: usize = 420;
 const BOARD_HEIGHT: usize = 210;
@@ -269,7 +268,7 @@ pub static CONWAY_TEXT_PALETTE: Palette1bpp = Palette1bpp::new_rgb(BACKGROUND, T
 pub static CONWAY_PALETTE: Palette1bpp = Palette1bpp::new_rgb(DEAD, ALIVE);
 
 impl GameOfLife {
-    pub(super) fn render<P: PinId>(&self, counter: &Counter<P>) {
+    pub(super) fn render<P: PinId>(&self, counter: &Counter<P>) -> Result<(), ScanlistError> {
         let height = 480 / VERTICAL_REPEAT as u32;
         let width = 640;
         let background = xrgb(BACKGROUND);
@@ -287,7 +286,7 @@ impl GameOfLife {
         rb.end_stripe();
         sb.begin_stripe(padding_top);
         sb.solid(width, background);
-        sb.end_stripe();
+        sb.end_stripe()?;
 
         rb.begin_stripe(BOARD_HEIGHT as u32);
         rb.blit_1bpp(
@@ -300,13 +299,13 @@ impl GameOfLife {
         sb.solid(padding_left, background);
         sb.pal_1bpp(BOARD_WIDTH as u32, &CONWAY_PALETTE);
         sb.solid(padding_right, background);
-        sb.end_stripe();
+        sb.end_stripe()?;
 
         rb.begin_stripe(padding_bottom - FONT_HEIGHT * 2);
         rb.end_stripe();
         sb.begin_stripe(padding_bottom - FONT_HEIGHT * 2);
         sb.solid(width, background);
-        sb.end_stripe();
+        sb.end_stripe()?;
 
         {
             rb.begin_stripe(FONT_HEIGHT);
@@ -317,7 +316,7 @@ impl GameOfLife {
             sb.begin_stripe(FONT_HEIGHT);
             sb.pal_1bpp(text_width, &CONWAY_TEXT_PALETTE);
             sb.solid(width - text_width, background);
-            sb.end_stripe();
+            sb.end_stripe()?;
             rb.begin_stripe(FONT_HEIGHT);
             let text = format!("Hello pico-dvi-rs, frame {}", counter.count);
             let text_width = rb.text(&text);
@@ -326,8 +325,8 @@ impl GameOfLife {
             sb.begin_stripe(FONT_HEIGHT);
             sb.pal_1bpp(text_width, &BW_PALETTE_1BPP);
             sb.solid(width - text_width, rgb(0x00, 0x00, 0x00));
-            sb.end_stripe();
+            sb.end_stripe()?;
         }
-        end_display_list(rb, sb);
+        end_display_list(rb, sb)
     }
 }
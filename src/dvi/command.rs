@@ -0,0 +1,167 @@
+//! Building HSTX command lists.
+//!
+//! The HSTX FIFO takes a command word giving an operation and a length in
+//! pixels, followed by the data for it. [`CommandList`] assembles these
+//! into a fixed-size array, keeping count of words and pixels so that
+//! lists can be checked against the line timing when they're built.
+
+use super::BPP;
+
+/// An HSTX command.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Command {
+    /// Output one data word per pixel without encoding.
+    Raw = 0,
+    /// Output one data word without encoding, repeated for every pixel.
+    RawRepeat = 1,
+    /// TMDS encode pixels packed into data words.
+    Tmds = 2,
+    /// TMDS encode the pixels of one data word, repeatedly.
+    TmdsRepeat = 3,
+    /// Do nothing; takes no length and no data.
+    Nop = 0xf,
+}
+
+impl Command {
+    /// The command word for `len` pixels.
+    pub const fn word(self, len: u32) -> u32 {
+        assert!(len < 1 << 12, "HSTX command too long");
+        ((self as u32) << 12) | len
+    }
+}
+
+/// Pixels packed in each data word of a TMDS command.
+pub const PIXELS_PER_WORD: u32 = 32 / BPP as u32;
+
+/// A list of `N` HSTX command and data words.
+///
+/// The builder methods panic if the list overflows or a command is
+/// malformed, which is a compile error when the list is built in a
+/// const context.
+#[derive(Clone, Copy)]
+pub struct CommandList<const N: usize> {
+    words: [u32; N],
+    len: usize,
+    pixels: u32,
+}
+
+impl<const N: usize> Default for CommandList<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CommandList<N> {
+    pub const fn new() -> Self {
+        CommandList {
+            words: [0; N],
+            len: 0,
+            pixels: 0,
+        }
+    }
+
+    /// Number of words so far, which is the index of the next one.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    const fn push(mut self, word: u32) -> Self {
+        assert!(self.len < N, "HSTX command list overflow");
+        self.words[self.len] = word;
+        self.len += 1;
+        self
+    }
+
+    const fn push_all(mut self, words: &[u32]) -> Self {
+        let mut i = 0;
+        while i < words.len() {
+            self = self.push(words[i]);
+            i += 1;
+        }
+        self
+    }
+
+    const fn command(mut self, command: Command, len: u32) -> Self {
+        // A zero length would take the data word as the next command.
+        assert!(len > 0, "empty HSTX command");
+        self.pixels += len;
+        self.push(command.word(len))
+    }
+
+    /// Output each of `words` for one pixel.
+    pub const fn raw(self, words: &[u32]) -> Self {
+        self.command(Command::Raw, words.len() as u32)
+            .push_all(words)
+    }
+
+    /// Output `word` for `len` pixels.
+    pub const fn raw_repeat(self, len: u32, word: u32) -> Self {
+        self.command(Command::RawRepeat, len).push(word)
+    }
+
+    /// Encode `len` pixels packed into `words`.
+    pub const fn tmds(self, len: u32, words: &[u32]) -> Self {
+        assert!(
+            words.len() as u32 == len.div_ceil(PIXELS_PER_WORD),
+            "wrong number of pixel words"
+        );
+        self.command(Command::Tmds, len).push_all(words)
+    }
+
+    /// Encode the pixels packed into `word` repeatedly, for `len` pixels.
+    pub const fn tmds_repeat(self, len: u32, word: u32) -> Self {
+        self.command(Command::TmdsRepeat, len).push(word)
+    }
+
+    pub const fn nop(self) -> Self {
+        self.push(Command::Nop.word(0))
+    }
+
+    /// Finish the list, which must fill it exactly.
+    pub const fn build(self) -> [u32; N] {
+        assert!(self.len == N, "HSTX command list underflow");
+        self.words
+    }
+
+    /// Finish the list, checking that it outputs `pixels` pixels.
+    pub const fn build_pixels(self, pixels: u32) -> [u32; N] {
+        assert!(
+            self.pixels == pixels,
+            "HSTX command list doesn't match the timing"
+        );
+        self.build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, CommandList, PIXELS_PER_WORD};
+
+    #[test]
+    fn build() {
+        const LIST: [u32; 8] = CommandList::new()
+            .raw_repeat(10, 0x123)
+            .raw(&[1, 2])
+            .nop()
+            .tmds_repeat(2 * PIXELS_PER_WORD, !0)
+            .build_pixels(12 + 2 * PIXELS_PER_WORD);
+        let tmds_repeat = Command::TmdsRepeat.word(2 * PIXELS_PER_WORD);
+        assert_eq!(LIST, [0x100a, 0x123, 0x0002, 1, 2, 0xf000, tmds_repeat, !0]);
+    }
+
+    #[test]
+    fn len() {
+        let list = CommandList::<4>::new().raw_repeat(3, 0);
+        assert_eq!(list.len(), 2);
+        let list = list.tmds(PIXELS_PER_WORD, &[0]);
+        assert_eq!(
+            list.build_pixels(3 + PIXELS_PER_WORD)[2..],
+            [Command::Tmds.word(PIXELS_PER_WORD), 0]
+        );
+    }
+}
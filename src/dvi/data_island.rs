@@ -1,4 +1,8 @@
-use core::{arch::asm, mem::MaybeUninit};
+#[cfg(target_arch = "arm")]
+use core::arch::asm;
+use core::mem::MaybeUninit;
+
+use super::tmds::TERC4_SYMBOLS;
 
 #[derive(Default)]
 pub struct DataPacket {
@@ -66,25 +70,36 @@ pub enum VideoCode {
     // Many other codes exist, but we can't reach them from RP2040
 }
 
-#[link_section = ".data"]
-pub static TERC4_SYMBOLS: [u16; 16] = [
-    0b1010011100,
-    0b1001100011,
-    0b1011100100,
-    0b1011100010,
-    0b0101110001,
-    0b0100011110,
-    0b0110001110,
-    0b0100111100,
-    0b1011001100,
-    0b0100111001,
-    0b0110011100,
-    0b1011000110,
-    0b1010001110,
-    0b1001110001,
-    0b0101100011,
-    0b1011000011,
-];
+impl super::ColorMode {
+    /// The AVI InfoFrame pixel format matching this mode.
+    pub const fn pixel_format(self) -> PixelFormat {
+        match self {
+            super::ColorMode::RgbFull | super::ColorMode::RgbLimited => PixelFormat::Rgb,
+            super::ColorMode::Ycbcr444 => PixelFormat::Ycbcr444,
+        }
+    }
+
+    /// The AVI InfoFrame colorimetry matching this mode.
+    ///
+    /// This field only applies to YCbCr; RGB sinks are told nothing.
+    pub const fn colorimetry(self) -> Colorimetry {
+        match self {
+            super::ColorMode::RgbFull | super::ColorMode::RgbLimited => Colorimetry::NoData,
+            super::ColorMode::Ycbcr444 => Colorimetry::Itu601,
+        }
+    }
+
+    /// The AVI InfoFrame RGB quantization range matching this mode.
+    ///
+    /// This field only applies to RGB; YCbCr is always limited range here.
+    pub const fn quantization_range(self) -> QuantizationRange {
+        match self {
+            super::ColorMode::RgbFull => QuantizationRange::Full,
+            super::ColorMode::RgbLimited => QuantizationRange::Limited,
+            super::ColorMode::Ycbcr444 => QuantizationRange::Default,
+        }
+    }
+}
 
 const fn mk_terc4_table() -> [u32; 256] {
     let mut table = [0; 256];
@@ -133,6 +148,7 @@ fn compute_bch(inp: &[u8]) -> u8 {
     inp.iter().fold(0, |v, b| BCH_TABLE[(b ^ v) as usize])
 }
 
+#[cfg(target_arch = "arm")]
 #[link_section = ".data"]
 pub fn clear_data_packet(dp: &mut MaybeUninit<DataPacket>) {
     unsafe {
@@ -149,6 +165,11 @@ pub fn clear_data_packet(dp: &mut MaybeUninit<DataPacket>) {
     }
 }
 
+#[cfg(not(target_arch = "arm"))]
+pub fn clear_data_packet(dp: &mut MaybeUninit<DataPacket>) {
+    dp.write(DataPacket::default());
+}
+
 impl DataPacket {
     #[link_section = ".data"]
     fn compute_header_parity(&mut self) {
@@ -190,18 +211,17 @@ impl DataPacket {
         };
         self.header[2] = b << 4;
         self.compute_header_parity();
-        for i in 0..n {
-            let [l, r] = audio[i];
-            self.subpacket[i][0] = 0;
-            self.subpacket[i][1] = l as u8;
-            self.subpacket[i][2] = (l >> 8) as u8;
-            self.subpacket[i][3] = 0;
-            self.subpacket[i][4] = r as u8;
-            self.subpacket[i][5] = (r >> 8) as u8;
+        for (&[l, r], subpacket) in audio.iter().zip(&mut self.subpacket) {
+            subpacket[0] = 0;
+            subpacket[1] = l as u8;
+            subpacket[2] = (l >> 8) as u8;
+            subpacket[3] = 0;
+            subpacket[4] = r as u8;
+            subpacket[5] = (r >> 8) as u8;
             let pl = parity_u16(l as u16);
             let pr = parity_u16(r as u16);
-            self.subpacket[i][6] = ((pl << 3) | (pr << 7)) ^ 0x99;
-            self.compute_subpacket_parity(i);
+            subpacket[6] = ((pl << 3) | (pr << 7)) ^ 0x99;
+            subpacket[7] = compute_bch(&subpacket[0..7]);
         }
         // Assumes packet is clear
         //for i in n..4 {
@@ -261,6 +281,7 @@ impl DataPacket {
     }
 
     #[link_section = ".data"]
+    #[allow(clippy::too_many_arguments)]
     pub fn set_avi_info_frame(
         &mut self,
         s: ScanInfo,
@@ -327,3 +348,59 @@ fn parity_u16(x: u16) -> u8 {
     let a = a ^ (a >> 1);
     a as u8 & 1
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ActiveFormatAspectRatio, Colorimetry, DataPacket, PictureAspectRatio, PixelFormat,
+        QuantizationRange, ScanInfo, VideoCode,
+    };
+    use crate::dvi::tmds::{decode_island, IslandError};
+
+    fn avi_info_frame() -> DataPacket {
+        let mut packet = DataPacket::default();
+        packet.set_avi_info_frame(
+            ScanInfo::Underscan,
+            PixelFormat::Rgb,
+            Colorimetry::NoData,
+            PictureAspectRatio::Ratio4_3,
+            ActiveFormatAspectRatio::SameAsPar,
+            QuantizationRange::Full,
+            VideoCode::Code640x480P60,
+        );
+        packet
+    }
+
+    fn encode(packet: &DataPacket, hv: u8) -> [u32; 36] {
+        let mut symbols = [0; 36];
+        packet.encode(hv, &mut symbols);
+        symbols
+    }
+
+    #[test]
+    fn encode_packets() {
+        let mut audio_clock = DataPacket::default();
+        audio_clock.set_audio_clock_regeneration(25200, 6144);
+        let mut audio_info = DataPacket::default();
+        audio_info.set_audio_info_frame(48000);
+        for packet in [avi_info_frame(), audio_clock, audio_info] {
+            for hv in 0..4 {
+                let (sync, decoded) = decode_island(&encode(&packet, hv)).unwrap();
+                assert_eq!(sync, hv);
+                assert_eq!(decoded.header, packet.header);
+                assert_eq!(decoded.subpacket, packet.subpacket);
+            }
+        }
+    }
+
+    #[test]
+    fn parity_errors() {
+        let mut packet = avi_info_frame();
+        packet.subpacket[2][3] ^= 0x10;
+        let error = decode_island(&encode(&packet, 0)).unwrap_err();
+        assert_eq!(error, IslandError::SubpacketParity { n: 2 });
+        packet.header[1] ^= 1;
+        let error = decode_island(&encode(&packet, 0)).unwrap_err();
+        assert_eq!(error, IslandError::HeaderParity);
+    }
+}
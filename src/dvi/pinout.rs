@@ -32,13 +32,15 @@ impl DviPinout {
         Self { pins, polarity }
     }
 
-    pub(crate) const fn cfg_bits(&self, pin: usize) -> u32 {
+    /// The output configuration for `pin`, when the shift register moves
+    /// `shift` bits per HSTX clock.
+    pub(crate) const fn cfg_bits(&self, pin: usize, shift: u8) -> u32 {
         let pair = self.pins[pin / 2];
         let mut bits = match pair {
             DviPair::Clk => 1 << 17, // CLK
             _ => {
                 let perm = pair as u8 as u32 * 10;
-                perm | ((perm + 1) << 8) // SEL_P | SEL_N
+                perm | ((perm + shift as u32 - 1) << 8) // SEL_P | SEL_N
             }
         };
         if pin % 2 != self.polarity as u8 as usize {
@@ -46,4 +48,12 @@ impl DviPinout {
         }
         bits
     }
+
+    /// The positive and negative pins carrying `pair`.
+    #[cfg(any(test, not(target_os = "none")))]
+    pub(crate) fn pins_for(&self, pair: DviPair) -> (usize, usize) {
+        let ix = self.pins.iter().position(|&p| p == pair).unwrap();
+        let pos = 2 * ix + self.polarity as usize;
+        (pos, pos ^ 1)
+    }
 }
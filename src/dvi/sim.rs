@@ -0,0 +1,678 @@
+//! Simulation of the HSTX output, decoding the link back into frames.
+//!
+//! Command words are expanded and shifted out to the pins as [`setup_hstx`]
+//! configures the hardware, then recovered by a model sink: symbols are
+//! framed against the generated clock, decoded, and their timing checked
+//! against the [`DviTiming`]. Completed frames, data island packets and
+//! any errors found along the way are collected.
+//!
+//! The encoders' running disparity is reset during raw (control) periods,
+//! as DVI requires. This only affects the choice of symbols, not the
+//! decoded data.
+//!
+//! [`setup_hstx`]: super::setup_hstx
+
+use alloc::vec::Vec;
+
+use crate::render::Frame;
+
+use super::{
+    pinout::{DviPair, DviPinout},
+    timing::DviTiming,
+    tmds::{decode_island, DcBalance, IslandError, IslandPacket, TmdsSymbol},
+    HstxConfig,
+};
+
+/// Stop recording errors after this many, as a broken stream tends to
+/// produce one for every symbol.
+const MAX_ERRORS: usize = 64;
+
+/// A problem found in the output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum LinkError {
+    /// A command word with an unknown command.
+    Command { word: u32 },
+    /// The pins of a pair weren't complementary. Lane 3 is the clock.
+    Differential { lane: u8 },
+    /// The clock fell part way through a symbol.
+    ClockAlignment,
+    /// A video guard band symbol was wrong, at symbol `x` of `line`.
+    GuardBand { line: u32, x: u32 },
+    /// Video data on a lane drifted too far from DC balance.
+    DcBalance { line: u32, x: u32, lane: u8 },
+    /// A data island failed to decode.
+    Island { line: u32, error: IslandError },
+    /// A data island was cut short after `len` symbols.
+    IslandLength { line: u32, len: u32 },
+    /// A measured interval didn't match the timing.
+    Timing {
+        line: u32,
+        interval: Interval,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// Intervals measured by the sink, horizontal ones in pixels and vertical
+/// ones in lines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Interval {
+    HTotal,
+    HSync,
+    HBackPorch,
+    HActive,
+    VTotal,
+    VSync,
+    VBackPorch,
+    VActive,
+}
+
+/// Length of a data island carrying one packet, in symbols.
+const ISLAND_LEN: usize = 36;
+
+/// A simulated HSTX peripheral driving a model sink.
+pub struct Hstx {
+    config: HstxConfig,
+    bits: [u32; 8],
+    command: u32,
+    remaining: u32,
+    disparity: [i32; 3],
+    clock_phase: u32,
+    lanes: [(usize, usize); 3],
+    clock: (usize, usize),
+    last_clock: bool,
+    symbol: [u16; 3],
+    n_bits: u32,
+    sink: Sink,
+}
+
+impl Hstx {
+    pub fn new(timing: &DviTiming, config: HstxConfig, pinout: DviPinout) -> Self {
+        Hstx {
+            config,
+            bits: core::array::from_fn(|pin| pinout.cfg_bits(pin, config.shift)),
+            command: 0,
+            remaining: 0,
+            disparity: [0; 3],
+            clock_phase: 0,
+            lanes: [DviPair::D0, DviPair::D1, DviPair::D2].map(|pair| pinout.pins_for(pair)),
+            clock: pinout.pins_for(DviPair::Clk),
+            last_clock: false,
+            symbol: [0; 3],
+            n_bits: 0,
+            sink: Sink::new(*timing),
+        }
+    }
+
+    /// Feed words to the FIFO, as the DMA would.
+    pub fn push(&mut self, words: &[u32]) {
+        for &word in words {
+            if self.remaining == 0 {
+                self.command = word >> 12;
+                self.remaining = word & 0xfff;
+                match self.command {
+                    0..=3 => (),
+                    0xf => self.remaining = 0,
+                    _ => {
+                        self.sink.error(LinkError::Command { word });
+                        self.remaining = 0;
+                    }
+                }
+                continue;
+            }
+            let tmds = self.command & 2 != 0;
+            let repeat = self.command & 1 != 0;
+            let (n_shifts, shift) = if tmds {
+                (self.config.enc_n_shifts, self.config.enc_shift)
+            } else {
+                (self.config.raw_n_shifts, self.config.raw_shift)
+            };
+            let n_shifts = if n_shifts == 0 { 32 } else { n_shifts as u32 };
+            let count = if repeat {
+                self.remaining
+            } else {
+                self.remaining.min(n_shifts)
+            };
+            for i in 0..count {
+                let data = word.rotate_right(shift as u32 * (i % n_shifts));
+                let out = if tmds {
+                    self.encode(data)
+                } else {
+                    self.disparity = [0; 3];
+                    data
+                };
+                self.shift_out(out);
+            }
+            self.remaining -= count;
+        }
+    }
+
+    /// Frames completed so far.
+    pub fn frames(&self) -> &[Frame] {
+        &self.sink.frames
+    }
+
+    /// Data island packets received so far, with the line each arrived on,
+    /// counted from the first hsync.
+    pub fn packets(&self) -> &[(u32, IslandPacket)] {
+        &self.sink.packets
+    }
+
+    pub fn errors(&self) -> &[LinkError] {
+        &self.sink.errors
+    }
+
+    fn encode(&mut self, data: u32) -> u32 {
+        let mut out = 0;
+        for (lane, &(rot, nbits)) in self.config.tmds_lanes.iter().enumerate() {
+            let mask = (0xff << (7 - nbits)) as u8;
+            let byte = data.rotate_right(rot as u32) as u8 & mask;
+            let (disparity, symbol) = TmdsSymbol::encode(self.disparity[lane], byte);
+            self.disparity[lane] = disparity;
+            out |= symbol.raw() << (10 * lane);
+        }
+        out
+    }
+
+    /// Run the output shift register over one expanded word.
+    fn shift_out(&mut self, word: u32) {
+        let n_shifts = if self.config.n_shifts == 0 {
+            32
+        } else {
+            self.config.n_shifts as u32
+        };
+        let clkdiv = if self.config.clkdiv == 0 {
+            16
+        } else {
+            self.config.clkdiv as u32
+        };
+        // Without both edges, SEL_P and SEL_N are the same and each bit lasts
+        // two half cycles, of which only the first is sampled.
+        let half_cycles_per_bit = 2 / self.config.shift as u32;
+        let mut word = word;
+        for _ in 0..n_shifts {
+            // SEL_P drives the first half of the cycle and SEL_N the second.
+            for sel in [0, 8] {
+                let clock = self.clock_phase >= clkdiv;
+                let mut pins = 0;
+                for (pin, &bits) in self.bits.iter().enumerate() {
+                    let level = if bits & (1 << 17) != 0 {
+                        clock as u32
+                    } else {
+                        (word >> ((bits >> sel) & 0x1f)) & 1
+                    };
+                    pins |= (level ^ ((bits >> 16) & 1)) << pin;
+                }
+                if self.clock_phase.is_multiple_of(half_cycles_per_bit) {
+                    self.receive(pins);
+                }
+                self.clock_phase = (self.clock_phase + 1) % (2 * clkdiv);
+            }
+            word = word.rotate_right(self.config.shift as u32);
+        }
+    }
+
+    /// Sample the pins for one bit period.
+    fn receive(&mut self, pins: u32) {
+        let level = |pin: usize| (pins >> pin) & 1;
+        let (clk_p, clk_n) = self.clock;
+        if level(clk_p) == level(clk_n) {
+            self.sink.error(LinkError::Differential { lane: 3 });
+        }
+        let clock = level(clk_p) != 0;
+        if self.last_clock && !clock && self.n_bits != 0 {
+            self.sink.error(LinkError::ClockAlignment);
+            self.n_bits = 0;
+            self.symbol = [0; 3];
+        }
+        self.last_clock = clock;
+        for (lane, &(p, n)) in self.lanes.iter().enumerate() {
+            if level(p) == level(n) {
+                self.sink
+                    .error(LinkError::Differential { lane: lane as u8 });
+            }
+            self.symbol[lane] |= (level(p) as u16) << self.n_bits;
+        }
+        self.n_bits += 1;
+        if self.n_bits == 10 {
+            self.sink.symbol(self.symbol);
+            self.n_bits = 0;
+            self.symbol = [0; 3];
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Period {
+    Control,
+    VideoGuard(u32),
+    Video,
+    Island,
+}
+
+const VIDEO_GUARD: [u32; 3] = [0x2cc, 0x133, 0x2cc];
+/// Control bits on lanes 1 and 2 announcing video and data islands.
+const VIDEO_PREAMBLE: u8 = 0b0001;
+const ISLAND_PREAMBLE: u8 = 0b0101;
+const PREAMBLE_LEN: u32 = 8;
+
+struct Sink {
+    timing: DviTiming,
+    period: Period,
+    preamble: (u8, u32),
+    hsync: bool,
+    vsync: bool,
+    // Horizontal state, from the leading edge of hsync.
+    line: u32,
+    in_line: bool,
+    x: u32,
+    line_vsync: bool,
+    hsync_width: u32,
+    hsync_done: bool,
+    active_start: Option<u32>,
+    row: Vec<u32>,
+    balance: [DcBalance; 3],
+    island: Vec<u32>,
+    // Vertical state, from the leading edge of vsync.
+    in_frame: bool,
+    prev_vsync: bool,
+    v_lines: u32,
+    vsync_lines: u32,
+    back_porch: u32,
+    active_lines: u32,
+    pixels: Vec<u32>,
+    frames: Vec<Frame>,
+    packets: Vec<(u32, IslandPacket)>,
+    errors: Vec<LinkError>,
+}
+
+impl Sink {
+    fn new(timing: DviTiming) -> Self {
+        Sink {
+            timing,
+            period: Period::Control,
+            preamble: (0, 0),
+            hsync: false,
+            vsync: false,
+            line: 0,
+            in_line: false,
+            x: 0,
+            line_vsync: false,
+            hsync_width: 0,
+            hsync_done: false,
+            active_start: None,
+            row: Vec::new(),
+            balance: [DcBalance::default(); 3],
+            island: Vec::with_capacity(ISLAND_LEN),
+            in_frame: false,
+            prev_vsync: false,
+            v_lines: 0,
+            vsync_lines: 0,
+            back_porch: 0,
+            active_lines: 0,
+            pixels: Vec::new(),
+            frames: Vec::new(),
+            packets: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, error: LinkError) {
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error);
+        }
+    }
+
+    fn check(&mut self, interval: Interval, expected: u32, actual: u32) {
+        if expected != actual {
+            let line = self.line;
+            self.error(LinkError::Timing {
+                line,
+                interval,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    fn symbol(&mut self, symbol: [u16; 3]) {
+        let symbol = symbol.map(|s| TmdsSymbol::from_raw(s as u32));
+        if let [Some(c0), Some(c1), Some(c2)] = symbol.map(TmdsSymbol::control) {
+            self.end_period();
+            self.sync(c0);
+            let ctl = c1 | (c2 << 2);
+            if ctl == self.preamble.0 {
+                self.preamble.1 += 1;
+            } else {
+                self.preamble = (ctl, 1);
+            }
+        } else {
+            if self.period == Period::Control {
+                self.period = match self.preamble {
+                    (VIDEO_PREAMBLE, n) if n >= PREAMBLE_LEN => Period::VideoGuard(2),
+                    (ISLAND_PREAMBLE, n) if n >= PREAMBLE_LEN => Period::Island,
+                    _ => Period::Video,
+                };
+            }
+            self.preamble = (0, 0);
+            self.data(symbol);
+        }
+        if self.hsync {
+            if !self.hsync_done {
+                self.hsync_width += 1;
+            }
+        } else if self.hsync_width != 0 {
+            self.hsync_done = true;
+        }
+        self.x += 1;
+    }
+
+    fn data(&mut self, symbol: [TmdsSymbol; 3]) {
+        let (line, x) = (self.line, self.x);
+        match self.period {
+            Period::Control => unreachable!(),
+            Period::VideoGuard(n) => {
+                self.hsync = false;
+                if symbol.map(TmdsSymbol::raw) != VIDEO_GUARD {
+                    self.error(LinkError::GuardBand { line, x });
+                }
+                self.period = if n > 1 {
+                    Period::VideoGuard(n - 1)
+                } else {
+                    Period::Video
+                };
+            }
+            Period::Video => {
+                self.hsync = false;
+                if self.active_start.is_none() {
+                    self.active_start = Some(x);
+                }
+                for (lane, &s) in symbol.iter().enumerate() {
+                    if !self.balance[lane].push(s) {
+                        let lane = lane as u8;
+                        self.error(LinkError::DcBalance { line, x, lane });
+                    }
+                }
+                let [b, g, r] = symbol.map(TmdsSymbol::decode);
+                self.row
+                    .push(((r as u32) << 16) | ((g as u32) << 8) | b as u32);
+            }
+            Period::Island => {
+                // Follow sync through the island; errors are left to the
+                // island decoder.
+                if let Some(c0) = symbol[0].terc4() {
+                    self.sync(c0 & 3);
+                }
+                let [s0, s1, s2] = symbol.map(TmdsSymbol::raw);
+                self.island.push(s0 | (s1 << 10) | (s2 << 20));
+                if self.island.len() == ISLAND_LEN {
+                    let symbols = self.island.as_slice().try_into().unwrap();
+                    match decode_island(symbols) {
+                        Ok((_, packet)) => self.packets.push((line, packet)),
+                        Err(error) => self.error(LinkError::Island { line, error }),
+                    }
+                    self.island.clear();
+                    self.period = Period::Control;
+                }
+            }
+        }
+    }
+
+    /// Note the end of a video period or data island, on receiving a
+    /// control symbol.
+    fn end_period(&mut self) {
+        if self.period == Period::Island {
+            let (line, len) = (self.line, self.island.len() as u32);
+            self.error(LinkError::IslandLength { line, len });
+            self.island.clear();
+        }
+        self.balance.iter_mut().for_each(DcBalance::reset);
+        self.period = Period::Control;
+    }
+
+    /// Update the sync state from the sync bits of lane 0.
+    fn sync(&mut self, bits: u8) {
+        let hsync = (bits & 1 != 0) == self.timing.horizontal().sync_polarity;
+        self.vsync = ((bits >> 1) & 1 != 0) == self.timing.vertical().sync_polarity;
+        if hsync && !self.hsync {
+            self.end_line();
+        }
+        self.hsync = hsync;
+    }
+
+    fn end_line(&mut self) {
+        if self.in_line {
+            let total = self.timing.h_total_pixels();
+            let t = self.timing.horizontal();
+            self.check(Interval::HTotal, total, self.x);
+            self.check(Interval::HSync, t.sync_width, self.hsync_width);
+            let active = self.active_start.is_some();
+            if let Some(start) = self.active_start {
+                let back_porch = start.saturating_sub(self.hsync_width);
+                self.check(Interval::HBackPorch, t.back_porch, back_porch);
+                let width = self.row.len() as u32;
+                self.check(Interval::HActive, t.active, width);
+            }
+            self.end_line_vertical(active);
+            self.line += 1;
+        }
+        self.in_line = true;
+        self.x = 0;
+        self.line_vsync = self.vsync;
+        self.hsync_width = 0;
+        self.hsync_done = false;
+        self.active_start = None;
+        self.row.clear();
+    }
+
+    fn end_line_vertical(&mut self, active: bool) {
+        let vsync = self.line_vsync;
+        if vsync && !self.prev_vsync {
+            if self.in_frame {
+                self.end_frame();
+            }
+            self.in_frame = true;
+            self.v_lines = 0;
+            self.vsync_lines = 0;
+            self.back_porch = 0;
+            self.active_lines = 0;
+            self.pixels.clear();
+        }
+        self.prev_vsync = vsync;
+        if !self.in_frame {
+            return;
+        }
+        self.v_lines += 1;
+        if vsync {
+            self.vsync_lines += 1;
+        } else if active {
+            self.active_lines += 1;
+            let width = self.timing.h_active_pixels() as usize;
+            self.row.resize(width, 0);
+            self.pixels.extend_from_slice(&self.row);
+        } else if self.active_lines == 0 {
+            self.back_porch += 1;
+        }
+    }
+
+    fn end_frame(&mut self) {
+        let total = self.timing.total_lines();
+        let t = self.timing.vertical();
+        self.check(Interval::VTotal, total, self.v_lines);
+        self.check(Interval::VSync, t.sync_width, self.vsync_lines);
+        self.check(Interval::VBackPorch, t.back_porch, self.back_porch);
+        self.check(Interval::VActive, t.active, self.active_lines);
+        self.frames.push(Frame {
+            width: self.timing.h_active_pixels(),
+            height: self.active_lines,
+            pixels: core::mem::take(&mut self.pixels),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use fugit::KilohertzU32;
+
+    use super::{Hstx, Interval, LinkError};
+    use crate::dvi::{
+        command::Command,
+        pinout::{DviPair::*, DviPinout, DviPolarity},
+        timing::{DviTiming, DviTimingLineState, DviTimingState, SyncTiming},
+        HstxConfig, HstxEdges,
+    };
+
+    const H: SyncTiming = SyncTiming {
+        sync_polarity: false,
+        front_porch: 16,
+        sync_width: 40,
+        back_porch: 24,
+        active: 32,
+    };
+    const V: SyncTiming = SyncTiming {
+        sync_polarity: false,
+        front_porch: 2,
+        sync_width: 2,
+        back_porch: 3,
+        active: 8,
+    };
+    /// Small enough to simulate quickly, with room for data islands.
+    const TIMING: DviTiming = match DviTiming::new(H, V, KilohertzU32::kHz(252000)) {
+        Ok(timing) => timing,
+        Err(_) => panic!("test timing should be valid"),
+    };
+
+    fn hstx(edges: HstxEdges) -> Hstx {
+        let pinout = DviPinout::new([D2, Clk, D1, D0], DviPolarity::Pos);
+        Hstx::new(&TIMING, HstxConfig::new(edges), pinout)
+    }
+
+    fn pixel(x: u32, y: u32) -> u32 {
+        (x & 0x1f) | (((y * 3) & 0x1f) << 5) | (((x + y) & 0x1f) << 10)
+    }
+
+    fn expected(x: u32, y: u32) -> u32 {
+        let p = pixel(x, y);
+        ((p >> 10 & 0x1f) << 19) | ((p >> 5 & 0x1f) << 11) | ((p & 0x1f) << 3)
+    }
+
+    /// Drive lines as the DVI interrupt does, with `sync` supplying the
+    /// sync pulse for each line.
+    fn run(
+        hstx: &mut Hstx,
+        timing: &DviTiming,
+        lines: u32,
+        sync: impl Fn(&mut Hstx, DviTimingLineState),
+    ) {
+        let mut state = DviTimingState::new(0);
+        for _ in 0..lines {
+            let line_state = state.v_state(&TIMING);
+            sync(hstx, line_state);
+            let v_sync = line_state == DviTimingLineState::Sync;
+            match state.v_scanline_index(&TIMING, 0) {
+                Some(y) => {
+                    let words: Vec<u32> = (0..TIMING.h_active_pixels() / 2)
+                        .map(|i| pixel(2 * i, y) | (pixel(2 * i + 1, y) << 16))
+                        .collect();
+                    hstx.push(&[Command::Tmds.word(TIMING.h_active_pixels())]);
+                    hstx.push(&words);
+                }
+                None => hstx.push(&timing.make_sync_line_only(v_sync)),
+            }
+            state.advance(&TIMING);
+        }
+    }
+
+    #[test]
+    fn frame() {
+        for edges in [HstxEdges::Both, HstxEdges::Single] {
+            let mut hstx = hstx(edges);
+            run(
+                &mut hstx,
+                &TIMING,
+                2 * TIMING.total_lines() + 1,
+                |hstx, state| hstx.push(&TIMING.make_sync_pulse(state == DviTimingLineState::Sync)),
+            );
+            assert_eq!(hstx.errors(), &[]);
+            assert_eq!(hstx.frames().len(), 1);
+            let frame = &hstx.frames()[0];
+            assert_eq!((frame.width, frame.height), (32, 8));
+            for y in 0..8 {
+                for x in 0..32 {
+                    assert_eq!(frame.pixel(x, y), expected(x, y));
+                }
+            }
+            let png = frame.to_png();
+            assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+            // The CRC of an IEND chunk is fixed.
+            assert_eq!(png[png.len() - 4..], [0xae, 0x42, 0x60, 0x82]);
+        }
+    }
+
+    #[test]
+    fn timing_error() {
+        let mut hstx = hstx(HstxEdges::Both);
+        let wide_sync = DviTiming::new(
+            SyncTiming {
+                sync_width: 44,
+                back_porch: 20,
+                ..H
+            },
+            V,
+            TIMING.bit_clk(),
+        )
+        .unwrap();
+        run(&mut hstx, &wide_sync, 2, |hstx, state| {
+            hstx.push(&wide_sync.make_sync_pulse(state == DviTimingLineState::Sync))
+        });
+        assert_eq!(
+            hstx.errors(),
+            &[LinkError::Timing {
+                line: 0,
+                interval: Interval::HSync,
+                expected: 40,
+                actual: 44
+            }]
+        );
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn data_island() {
+        use crate::dvi::data_island::{
+            ActiveFormatAspectRatio, Colorimetry, DataPacket, PictureAspectRatio, PixelFormat,
+            QuantizationRange, ScanInfo, VideoCode,
+        };
+
+        let mut packet = DataPacket::default();
+        packet.set_avi_info_frame(
+            ScanInfo::NoData,
+            PixelFormat::Rgb,
+            Colorimetry::NoData,
+            PictureAspectRatio::NoData,
+            ActiveFormatAspectRatio::NoData,
+            QuantizationRange::Default,
+            VideoCode::Code640x480P60,
+        );
+        let mut hstx = hstx(HstxEdges::Both);
+        run(
+            &mut hstx,
+            &TIMING,
+            2 * TIMING.total_lines() + 1,
+            |hstx, state| {
+                let mut line = TIMING.data_island_line();
+                TIMING.encode_data_island(&mut line, state, &packet);
+                hstx.push(line.words());
+            },
+        );
+        assert_eq!(hstx.errors(), &[]);
+        assert_eq!(hstx.frames().len(), 1);
+        assert_eq!(hstx.frames()[0].pixel(5, 3), expected(5, 3));
+        assert_eq!(hstx.packets().len() as u32, 2 * TIMING.total_lines() + 1);
+        for (_, received) in hstx.packets() {
+            assert_eq!(received.header, packet.header);
+            assert_eq!(received.subpacket, packet.subpacket);
+        }
+    }
+}
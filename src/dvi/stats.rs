@@ -0,0 +1,334 @@
+//! Counting underruns and render times.
+//!
+//! Core 1 records these as it renders and scans out lines, and publishes
+//! them at the end of each frame. They're read with [`stats`], from any
+//! core, to see how close display lists come to the line budget.
+
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+/// Scanlist stripes timed separately; later stripes share the last slot.
+pub const MAX_STRIPES: usize = 16;
+
+/// Stored for a frame without a display list swap.
+const NO_SWAP: u32 = u32::MAX;
+
+/// Video output statistics for one frame, or accumulated over many.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub struct FrameStats {
+    /// Lines never rendered, because the buffer was still in use for an
+    /// earlier line when they were due to start.
+    pub missed_lines: u32,
+    /// Lines rendered, but not finished by the time they were scanned out.
+    pub late_lines: u32,
+    /// Scanlines between a display list being submitted and its first line
+    /// being rendered, if one was swapped in.
+    pub swap_latency: Option<u32>,
+    /// The longest time taken to render a line of each scanlist stripe, in
+    /// system clock cycles.
+    pub stripe_cycles: [u32; MAX_STRIPES],
+}
+
+impl FrameStats {
+    /// Fold in the stats of another frame, adding up the counts and
+    /// keeping the worst latency and render times.
+    pub fn accumulate(&mut self, frame: &FrameStats) {
+        self.missed_lines = self.missed_lines.saturating_add(frame.missed_lines);
+        self.late_lines = self.late_lines.saturating_add(frame.late_lines);
+        self.swap_latency = self.swap_latency.max(frame.swap_latency);
+        for (total, cycles) in self.stripe_cycles.iter_mut().zip(frame.stripe_cycles) {
+            *total = (*total).max(cycles);
+        }
+    }
+
+    /// The longest time taken to render any line, in system clock cycles.
+    pub fn max_line_cycles(&self) -> u32 {
+        self.stripe_cycles.into_iter().max().unwrap_or_default()
+    }
+}
+
+/// A snapshot of the statistics, from [`stats`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub struct VideoStats {
+    /// Frames completed since video output started.
+    pub frames: u32,
+    /// The last complete frame.
+    pub last_frame: FrameStats,
+    /// All complete frames, as by [`FrameStats::accumulate`].
+    pub total: FrameStats,
+}
+
+impl VideoStats {
+    /// Log the snapshot.
+    ///
+    /// Render times are best compared with the time for a line, from
+    /// [`DviTiming::line_cycles`].
+    ///
+    /// [`DviTiming::line_cycles`]: super::timing::DviTiming::line_cycles
+    pub fn log(&self) {
+        let (frame, total) = (&self.last_frame, &self.total);
+        defmt::info!(
+            "video frame {}: {} missed, {} late, swap latency {} lines, worst line {} cycles",
+            self.frames,
+            frame.missed_lines,
+            frame.late_lines,
+            frame.swap_latency,
+            frame.max_line_cycles(),
+        );
+        defmt::info!(
+            "video total: {} missed, {} late, swap latency {} lines, worst line {} cycles",
+            total.missed_lines,
+            total.late_lines,
+            total.swap_latency,
+            total.max_line_cycles(),
+        );
+        defmt::info!("worst line per stripe: {}", total.stripe_cycles);
+    }
+}
+
+/// [`FrameStats`] that can be updated from the interrupt and the render
+/// loop alike.
+struct AtomicFrameStats {
+    missed_lines: AtomicU32,
+    late_lines: AtomicU32,
+    swap_latency: AtomicU32,
+    stripe_cycles: [AtomicU32; MAX_STRIPES],
+}
+
+impl AtomicFrameStats {
+    const fn new() -> Self {
+        AtomicFrameStats {
+            missed_lines: AtomicU32::new(0),
+            late_lines: AtomicU32::new(0),
+            swap_latency: AtomicU32::new(NO_SWAP),
+            stripe_cycles: [const { AtomicU32::new(0) }; MAX_STRIPES],
+        }
+    }
+
+    fn load(&self) -> FrameStats {
+        let swap_latency = self.swap_latency.load(Ordering::Relaxed);
+        FrameStats {
+            missed_lines: self.missed_lines.load(Ordering::Relaxed),
+            late_lines: self.late_lines.load(Ordering::Relaxed),
+            swap_latency: (swap_latency != NO_SWAP).then_some(swap_latency),
+            stripe_cycles: core::array::from_fn(|i| self.stripe_cycles[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn store(&self, frame: &FrameStats) {
+        self.missed_lines
+            .store(frame.missed_lines, Ordering::Relaxed);
+        self.late_lines.store(frame.late_lines, Ordering::Relaxed);
+        self.swap_latency
+            .store(frame.swap_latency.unwrap_or(NO_SWAP), Ordering::Relaxed);
+        for (slot, cycles) in self.stripe_cycles.iter().zip(frame.stripe_cycles) {
+            slot.store(cycles, Ordering::Relaxed);
+        }
+    }
+
+    /// Load the stats and reset them for the next frame.
+    fn take(&self) -> FrameStats {
+        let swap_latency = self.swap_latency.swap(NO_SWAP, Ordering::Relaxed);
+        FrameStats {
+            missed_lines: self.missed_lines.swap(0, Ordering::Relaxed),
+            late_lines: self.late_lines.swap(0, Ordering::Relaxed),
+            swap_latency: (swap_latency != NO_SWAP).then_some(swap_latency),
+            stripe_cycles: core::array::from_fn(|i| {
+                self.stripe_cycles[i].swap(0, Ordering::Relaxed)
+            }),
+        }
+    }
+}
+
+/// The statistics being gathered.
+///
+/// Everything but [`Stats::submit`] and [`Stats::snapshot`] is called on
+/// core 1. The published stats are guarded by a sequence count, odd while
+/// they're being written, so that readers never see a torn update.
+pub struct Stats {
+    frame: AtomicFrameStats,
+    /// Scanlines since video output started, wrapping.
+    lines: AtomicU32,
+    /// The value of `lines` when the pending display list was submitted.
+    submitted_at: AtomicU32,
+    seq: AtomicU32,
+    frames: AtomicU32,
+    last_frame: AtomicFrameStats,
+    total: AtomicFrameStats,
+}
+
+pub static STATS: Stats = Stats::new();
+
+impl Stats {
+    const fn new() -> Self {
+        Stats {
+            frame: AtomicFrameStats::new(),
+            lines: AtomicU32::new(0),
+            submitted_at: AtomicU32::new(0),
+            seq: AtomicU32::new(0),
+            frames: AtomicU32::new(0),
+            last_frame: AtomicFrameStats::new(),
+            total: AtomicFrameStats::new(),
+        }
+    }
+
+    #[inline]
+    pub fn missed_line(&self) {
+        self.frame.missed_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn late_line(&self) {
+        self.frame.late_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the time taken to render a line of scanlist stripe `stripe`.
+    #[inline]
+    pub fn line_rendered(&self, stripe: usize, cycles: u32) {
+        let slot = &self.frame.stripe_cycles[stripe.min(MAX_STRIPES - 1)];
+        slot.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    /// Note that a display list is about to be handed to the system.
+    pub fn submit(&self) {
+        let now = self.lines.load(Ordering::Relaxed);
+        self.submitted_at.store(now, Ordering::Relaxed);
+    }
+
+    /// Note that the display list last submitted has been swapped in.
+    ///
+    /// The swap synchronizes with the submission, which makes
+    /// `submitted_at` visible here.
+    pub fn swapped(&self) {
+        let now = self.lines.load(Ordering::Relaxed);
+        let latency = now.wrapping_sub(self.submitted_at.load(Ordering::Relaxed));
+        self.frame.swap_latency.store(latency, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn end_line(&self) {
+        self.lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Publish the stats for the frame just finished.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn end_frame(&self) {
+        let frame = self.frame.take();
+        let mut total = self.total.load();
+        total.accumulate(&frame);
+
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.last_frame.store(&frame);
+        self.total.store(&total);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> VideoStats {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if !seq.is_multiple_of(2) {
+                core::hint::spin_loop();
+                continue;
+            }
+            let stats = VideoStats {
+                frames: self.frames.load(Ordering::Relaxed),
+                last_frame: self.last_frame.load(),
+                total: self.total.load(),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return stats;
+            }
+        }
+    }
+}
+
+/// Statistics on video output, as of the last complete frame.
+pub fn stats() -> VideoStats {
+    STATS.snapshot()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameStats, Stats, VideoStats, MAX_STRIPES};
+
+    #[test]
+    fn accumulate() {
+        let mut total = FrameStats::default();
+        let mut frame = FrameStats {
+            missed_lines: 2,
+            late_lines: 1,
+            swap_latency: None,
+            stripe_cycles: [0; MAX_STRIPES],
+        };
+        frame.stripe_cycles[0] = 3000;
+        frame.stripe_cycles[1] = 1000;
+        total.accumulate(&frame);
+        frame.swap_latency = Some(40);
+        frame.stripe_cycles[1] = 2000;
+        total.accumulate(&frame);
+        frame.swap_latency = Some(10);
+        frame.missed_lines = u32::MAX;
+        total.accumulate(&frame);
+        assert_eq!(total.missed_lines, u32::MAX);
+        assert_eq!(total.late_lines, 3);
+        assert_eq!(total.swap_latency, Some(40));
+        assert_eq!(total.stripe_cycles[..3], [3000, 2000, 0]);
+        assert_eq!(total.max_line_cycles(), 3000);
+    }
+
+    #[test]
+    fn publish() {
+        let stats = Stats::new();
+        assert_eq!(stats.snapshot(), VideoStats::default());
+        stats.submit();
+        for _ in 0..5 {
+            stats.end_line();
+        }
+        stats.swapped();
+        stats.line_rendered(0, 100);
+        stats.line_rendered(0, 50);
+        stats.line_rendered(MAX_STRIPES + 3, 70);
+        stats.late_line();
+        stats.end_frame();
+        let mut frame = FrameStats {
+            late_lines: 1,
+            swap_latency: Some(5),
+            ..FrameStats::default()
+        };
+        frame.stripe_cycles[0] = 100;
+        frame.stripe_cycles[MAX_STRIPES - 1] = 70;
+        assert_eq!(
+            stats.snapshot(),
+            VideoStats {
+                frames: 1,
+                last_frame: frame,
+                total: frame,
+            }
+        );
+
+        stats.missed_line();
+        stats.line_rendered(0, 80);
+        stats.end_frame();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.frames, 2);
+        assert_eq!(
+            snapshot.last_frame,
+            FrameStats {
+                missed_lines: 1,
+                stripe_cycles: {
+                    let mut cycles = [0; MAX_STRIPES];
+                    cycles[0] = 80;
+                    cycles
+                },
+                ..FrameStats::default()
+            }
+        );
+        assert_eq!(snapshot.total.missed_lines, 1);
+        assert_eq!(snapshot.total.late_lines, 1);
+        assert_eq!(snapshot.total.swap_latency, Some(5));
+        assert_eq!(snapshot.total.stripe_cycles[0], 100);
+    }
+}
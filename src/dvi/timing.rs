@@ -1,45 +1,265 @@
 //! timing information yoinked from
 //! <https://github.com/Wren6991/PicoDVI/blob/51237271437e9d1eb62c97e40171fbf6ffe01ac6/software/libdvi/dvi_timing.c>
 
-use fugit::KilohertzU32;
+use fugit::{HertzU32, KilohertzU32, NanosDurationU32};
 
 #[cfg(feature = "audio")]
-use crate::dvi::data_island::{DataPacket, TERC4_SYMBOLS};
+use crate::dvi::{data_island::DataPacket, tmds::TERC4_SYMBOLS};
 
-use super::{hstx_cmd_raw, hstx_cmd_raw_repeat};
+use super::command::{CommandList, PIXELS_PER_WORD};
 
 // Perhaps there should be a trait with associated constants for resolution,
 // to allow compile-time allocation of scanline buffers etc.
+/// Video timing that has been checked to be outputtable.
+///
+/// The fields are private so that every timing goes through
+/// [`DviTiming::new`]; the sync line builders rely on its checks.
+#[derive(Clone, Copy)]
 pub struct DviTiming {
-    pub h_sync_polarity: bool,
-    pub h_front_porch: u32,
-    pub h_sync_width: u32,
-    pub h_back_porch: u32,
-    pub h_active_pixels: u32,
-
-    pub v_sync_polarity: bool,
-    pub v_front_porch: u32,
-    pub v_sync_width: u32,
-    pub v_back_porch: u32,
-    pub v_active_lines: u32,
-
-    pub bit_clk: KilohertzU32,
+    h_sync_polarity: bool,
+    h_front_porch: u32,
+    h_sync_width: u32,
+    h_back_porch: u32,
+    h_active_pixels: u32,
+
+    v_sync_polarity: bool,
+    v_front_porch: u32,
+    v_sync_width: u32,
+    v_back_porch: u32,
+    v_active_lines: u32,
+
+    bit_clk: KilohertzU32,
+}
+
+/// Timing of one axis, in pixels for horizontal or lines for vertical.
+#[derive(Clone, Copy)]
+pub struct SyncTiming {
+    pub sync_polarity: bool,
+    pub front_porch: u32,
+    pub sync_width: u32,
+    pub back_porch: u32,
+    pub active: u32,
 }
 
 // Number of trailing sync words to encode as raw
 const SYNC_TRAILING_RAW: usize = 8;
-pub const SYNC_LINE_WORDS: usize = 7 + SYNC_TRAILING_RAW;
-pub const SYNC_LINE_ONLY_WORDS: usize = 3 + SYNC_TRAILING_RAW;
-pub const SYNC_DATA_ISLAND_LEN: usize = 56;
+/// Front porch, sync and back porch repeats, then the trailing words.
+pub const SYNC_LINE_WORDS: usize = 3 * 2 + 1 + SYNC_TRAILING_RAW;
+pub const SYNC_LINE_ONLY_WORDS: usize = 2 + 1 + SYNC_TRAILING_RAW;
+
+/// Length of the control period announcing video or a data island.
+#[cfg(feature = "audio")]
+const PREAMBLE_LEN: u32 = 8;
+#[cfg(feature = "audio")]
+const GUARD_LEN: u32 = 2;
+
+/// Symbols in a data island carrying one packet, guard bands included.
+#[cfg(feature = "audio")]
+pub const ISLAND_SYMBOLS: usize = 32 + 2 * GUARD_LEN as usize;
+
+/// Trailing raw words of a data island sync line, enough to hold the video
+/// preamble and guard band on active lines.
+#[cfg(feature = "audio")]
+const ISLAND_TRAILING_RAW: usize = (PREAMBLE_LEN + GUARD_LEN) as usize;
+
+/// Front porch and preamble repeats, the island, the sync and back porch
+/// repeats, then the trailing words.
+#[cfg(feature = "audio")]
+pub const SYNC_DATA_ISLAND_LEN: usize =
+    2 * 2 + 1 + ISLAND_SYMBOLS + 2 * 2 + 1 + ISLAND_TRAILING_RAW;
+
+/// Shortest porches and sync pulse the sync lines can be built with.
+#[cfg(not(feature = "audio"))]
+const MIN_H_PORCHES: [u32; 3] = [1, 1, SYNC_TRAILING_RAW as u32 + 1];
+/// Shortest porches and sync pulse the sync lines can be built with,
+/// including those carrying data islands.
+#[cfg(feature = "audio")]
+const MIN_H_PORCHES: [u32; 3] = [
+    PREAMBLE_LEN + 1,
+    ISLAND_SYMBOLS as u32 + 1,
+    ISLAND_TRAILING_RAW as u32 + 1,
+];
+
+/// Range of bit clocks allowed by DVI, for pixel clocks of 25 to 165 MHz.
+const BIT_CLK_RANGE_KHZ: (u32, u32) = (250_000, 1_650_000);
+
+/// Reasons a [`DviTiming`] can't be output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum TimingError {
+    /// The front porch is shorter than `min` pixels.
+    FrontPorch { min: u32 },
+    /// The horizontal sync pulse is shorter than `min` pixels.
+    SyncWidth { min: u32 },
+    /// The back porch is shorter than `min` pixels.
+    BackPorch { min: u32 },
+    /// The active width doesn't fill whole pixel words, or is shorter than
+    /// `min` pixels.
+    ActiveWidth { min: u32 },
+    /// A part of the line is too long for one HSTX command.
+    TooWide,
+    /// There are no active lines, or no vertical sync.
+    Vertical,
+    /// The bit clock is outside the range DVI allows.
+    BitClock,
+}
 
 #[link_section = ".data"]
 static TMDS_CTRL: [u32; 4] = [0x354, 0xab, 0x154, 0x2ab];
 
 impl DviTiming {
-    fn total_lines(&self) -> u32 {
+    /// Build a timing, checking that the sync lines can be built and the
+    /// timing output.
+    pub const fn new(
+        h: SyncTiming,
+        v: SyncTiming,
+        bit_clk: KilohertzU32,
+    ) -> Result<Self, TimingError> {
+        DviTiming {
+            h_sync_polarity: h.sync_polarity,
+            h_front_porch: h.front_porch,
+            h_sync_width: h.sync_width,
+            h_back_porch: h.back_porch,
+            h_active_pixels: h.active,
+
+            v_sync_polarity: v.sync_polarity,
+            v_front_porch: v.front_porch,
+            v_sync_width: v.sync_width,
+            v_back_porch: v.back_porch,
+            v_active_lines: v.active,
+
+            bit_clk,
+        }
+        .validate()
+    }
+
+    const fn validate(self) -> Result<Self, TimingError> {
+        let [min_front_porch, min_sync_width, min_back_porch] = MIN_H_PORCHES;
+        if self.h_front_porch < min_front_porch {
+            return Err(TimingError::FrontPorch {
+                min: min_front_porch,
+            });
+        }
+        if self.h_sync_width < min_sync_width {
+            return Err(TimingError::SyncWidth {
+                min: min_sync_width,
+            });
+        }
+        if self.h_back_porch < min_back_porch {
+            return Err(TimingError::BackPorch {
+                min: min_back_porch,
+            });
+        }
+        // The error line ends in whole words of pixels.
+        let min_active = SYNC_TRAILING_RAW as u32 * PIXELS_PER_WORD + 1;
+        if self.h_active_pixels < min_active
+            || !self.h_active_pixels.is_multiple_of(PIXELS_PER_WORD)
+        {
+            return Err(TimingError::ActiveWidth { min: min_active });
+        }
+        let longest = max(
+            max(self.h_front_porch, self.h_sync_width),
+            max(self.h_back_porch, self.h_active_pixels),
+        );
+        if longest >= 1 << 12 {
+            return Err(TimingError::TooWide);
+        }
+        if self.v_active_lines == 0 || self.v_sync_width == 0 {
+            return Err(TimingError::Vertical);
+        }
+        let bit_clk = self.bit_clk.to_kHz();
+        if bit_clk < BIT_CLK_RANGE_KHZ.0 || bit_clk > BIT_CLK_RANGE_KHZ.1 {
+            return Err(TimingError::BitClock);
+        }
+        Ok(self)
+    }
+
+    /// Horizontal timing, in pixels.
+    pub const fn horizontal(&self) -> SyncTiming {
+        SyncTiming {
+            sync_polarity: self.h_sync_polarity,
+            front_porch: self.h_front_porch,
+            sync_width: self.h_sync_width,
+            back_porch: self.h_back_porch,
+            active: self.h_active_pixels,
+        }
+    }
+
+    /// Vertical timing, in lines.
+    pub const fn vertical(&self) -> SyncTiming {
+        SyncTiming {
+            sync_polarity: self.v_sync_polarity,
+            front_porch: self.v_front_porch,
+            sync_width: self.v_sync_width,
+            back_porch: self.v_back_porch,
+            active: self.v_active_lines,
+        }
+    }
+
+    /// Width of the visible part of a scanline, in pixels.
+    pub const fn h_active_pixels(&self) -> u32 {
+        self.h_active_pixels
+    }
+
+    /// Height of the visible part of a frame, in lines.
+    pub const fn v_active_lines(&self) -> u32 {
+        self.v_active_lines
+    }
+
+    /// TMDS bit clock, ten times the pixel clock.
+    pub const fn bit_clk(&self) -> KilohertzU32 {
+        self.bit_clk
+    }
+
+    /// Total width of a scanline including blanking, in pixels.
+    pub const fn h_total_pixels(&self) -> u32 {
+        self.h_front_porch + self.h_sync_width + self.h_back_porch + self.h_active_pixels
+    }
+
+    /// Width of horizontal blanking, in pixels.
+    pub const fn h_blank_pixels(&self) -> u32 {
+        self.h_front_porch + self.h_sync_width + self.h_back_porch
+    }
+
+    /// Total height of a frame including blanking, in lines.
+    pub const fn total_lines(&self) -> u32 {
         self.v_front_porch + self.v_sync_width + self.v_back_porch + self.v_active_lines
     }
 
+    /// The pixel clock, a tenth of the bit clock.
+    pub const fn pixel_clock(&self) -> HertzU32 {
+        HertzU32::Hz(self.bit_clk.to_kHz() * 100)
+    }
+
+    /// Time taken by one scanline.
+    pub const fn line_time(&self) -> NanosDurationU32 {
+        let ns = self.h_total_pixels() as u64 * 10_000_000 / self.bit_clk.to_kHz() as u64;
+        NanosDurationU32::nanos(ns as u32)
+    }
+
+    /// Frames per second, in thousandths.
+    pub const fn frame_rate_millihertz(&self) -> u32 {
+        let pixels = self.h_total_pixels() as u64 * self.total_lines() as u64;
+        (self.bit_clk.to_kHz() as u64 * 100_000 / pixels) as u32
+    }
+
+    /// System clock cycles in a scanline, at `sys_clk`.
+    pub const fn line_cycles(&self, sys_clk: HertzU32) -> u32 {
+        self.pixel_cycles(self.h_total_pixels(), sys_clk)
+    }
+
+    /// System clock cycles in horizontal blanking, at `sys_clk`.
+    ///
+    /// This is the budget for the sync pulse interrupt to set up the next
+    /// line before the active pixels start.
+    pub const fn h_blank_cycles(&self, sys_clk: HertzU32) -> u32 {
+        self.pixel_cycles(self.h_blank_pixels(), sys_clk)
+    }
+
+    const fn pixel_cycles(&self, pixels: u32, sys_clk: HertzU32) -> u32 {
+        let cycles = pixels as u64 * sys_clk.to_Hz() as u64 / self.pixel_clock().to_Hz() as u64;
+        cycles as u32
+    }
+
     fn state_for_v_count(&self, v_count: u32) -> DviTimingLineState {
         let mut y = v_count;
         if y < self.v_front_porch {
@@ -57,7 +277,7 @@ impl DviTiming {
         }
     }
 
-    pub fn tmds3_for_sync(&self, h_sync: bool, v_sync: bool) -> u32 {
+    pub const fn tmds3_for_sync(&self, h_sync: bool, v_sync: bool) -> u32 {
         let h_bit = (h_sync == self.h_sync_polarity) as usize;
         let v_bit = (v_sync == self.v_sync_polarity) as usize;
         let tmds_lane_0 = TMDS_CTRL[h_bit + (v_bit << 1)];
@@ -66,124 +286,184 @@ impl DviTiming {
         tmds_lane_0 | (tmds_lane_1 << 10) | (tmds_lane_2 << 20)
     }
 
-    pub fn make_sync_pulse(&self, v_sync: bool) -> [u32; SYNC_LINE_WORDS] {
+    pub const fn make_sync_pulse(&self, v_sync: bool) -> [u32; SYNC_LINE_WORDS] {
         let h_sync_off = self.tmds3_for_sync(false, v_sync);
-        let mut line = [h_sync_off; SYNC_LINE_WORDS];
-        line[0] = hstx_cmd_raw_repeat(self.h_front_porch);
-        // line[1] is already h_sync_off
-        line[2] = hstx_cmd_raw_repeat(self.h_sync_width);
-        line[3] = self.tmds3_for_sync(true, v_sync);
         const TAIL: u32 = SYNC_TRAILING_RAW as u32;
-        line[4] = hstx_cmd_raw_repeat(self.h_back_porch - TAIL);
-        // line[5] is already h_sync_off
-        line[6] = hstx_cmd_raw(TAIL);
-        line
+        CommandList::new()
+            .raw_repeat(self.h_front_porch, h_sync_off)
+            .raw_repeat(self.h_sync_width, self.tmds3_for_sync(true, v_sync))
+            .raw_repeat(self.h_back_porch - TAIL, h_sync_off)
+            .raw(&[h_sync_off; SYNC_TRAILING_RAW])
+            .build_pixels(self.h_front_porch + self.h_sync_width + self.h_back_porch)
     }
 
-    pub fn make_sync_line_only(&self, v_sync: bool) -> [u32; SYNC_LINE_ONLY_WORDS] {
+    pub const fn make_sync_line_only(&self, v_sync: bool) -> [u32; SYNC_LINE_ONLY_WORDS] {
         let h_sync_off = self.tmds3_for_sync(false, v_sync);
-        let mut line = [h_sync_off; SYNC_LINE_ONLY_WORDS];
         const TAIL: u32 = SYNC_TRAILING_RAW as u32;
-        line[0] = hstx_cmd_raw_repeat(self.h_active_pixels - TAIL);
-        // line[1] is already h_sync_off
-        line[2] = hstx_cmd_raw(TAIL);
-        line
+        CommandList::new()
+            .raw_repeat(self.h_active_pixels - TAIL, h_sync_off)
+            .raw(&[h_sync_off; SYNC_TRAILING_RAW])
+            .build_pixels(self.h_active_pixels)
+    }
+
+    /// An active line of a single color, `word` holding packed pixels.
+    ///
+    /// This ends in as many words as the other lines, so the FIFO is as
+    /// full when the DMA finishes.
+    pub const fn make_err_line(&self, word: u32) -> [u32; SYNC_LINE_ONLY_WORDS] {
+        err_line(self.h_active_pixels, word)
     }
 
-    pub fn init_data_island(&self, line: &mut [u32; SYNC_DATA_ISLAND_LEN]) {
-        line[0] = hstx_cmd_raw_repeat(self.h_front_porch - 8);
-        line[2] = hstx_cmd_raw_repeat(8);
-        line[4] = hstx_cmd_raw(36);
-        line[41] = hstx_cmd_raw_repeat(self.h_sync_width - 36);
-        line[43] = hstx_cmd_raw_repeat(self.h_back_porch - 10);
-        line[45] = hstx_cmd_raw(10);
+    /// A sync pulse with room for a data island, to be filled in by
+    /// [`encode_data_island`](Self::encode_data_island).
+    #[cfg(feature = "audio")]
+    pub const fn data_island_line(&self) -> DataIslandLine {
+        let list = CommandList::new().raw_repeat(self.h_front_porch - PREAMBLE_LEN, 0);
+        let front_porch = list.len() - 1;
+        let list = list.raw_repeat(PREAMBLE_LEN, 0);
+        let preamble = list.len() - 1;
+        let list = list.raw(&[0; ISLAND_SYMBOLS]);
+        let island = list.len() - ISLAND_SYMBOLS;
+        let list = list.raw_repeat(self.h_sync_width - ISLAND_SYMBOLS as u32, 0);
+        let sync = list.len() - 1;
+        let tail = ISLAND_TRAILING_RAW as u32;
+        let list = list.raw_repeat(self.h_back_porch - tail, 0);
+        let back_porch = list.len() - 1;
+        let list = list.raw(&[0; ISLAND_TRAILING_RAW]);
+        let trailer = list.len() - ISLAND_TRAILING_RAW;
+        DataIslandLine {
+            words: list.build_pixels(self.h_front_porch + self.h_sync_width + self.h_back_porch),
+            front_porch,
+            preamble,
+            island,
+            sync,
+            back_porch,
+            trailer,
+        }
     }
 
+    #[cfg(feature = "audio")]
     const VIDEO_GUARD: u32 = 0x2cc | (0x133 << 10) | (0x2cc << 20);
     #[cfg(feature = "audio")]
     #[link_section = ".data"]
     pub fn encode_data_island(
         &self,
-        line: &mut [u32; SYNC_DATA_ISLAND_LEN],
+        line: &mut DataIslandLine,
         state: DviTimingLineState,
         packet: &DataPacket,
     ) {
-        let v_sync = matches!(state, DviTimingLineState::Sync);
-        let h_bit = self.h_sync_polarity as u8;
-        let v_bit = (v_sync == self.v_sync_polarity) as u8;
-        let hv = h_bit + (v_bit << 1);
-        packet.encode(hv, &mut line[5..41]);
-        let sync_off = self.tmds3_for_sync(false, v_sync);
-        let sync_on = self.tmds3_for_sync(true, v_sync);
-        const CTRL_MASK: u32 = TMDS_CTRL[0] ^ TMDS_CTRL[1];
-        let vid_preamble = sync_off ^ (CTRL_MASK << 10);
-        let data_preamble = vid_preamble ^ (CTRL_MASK << 20);
-        line[1] = sync_off;
-        line[3] = data_preamble;
-        line[42] = sync_on;
-        line[44] = sync_off;
-        match state {
-            DviTimingLineState::Active => {
-                line[46..54].fill(vid_preamble);
-                line[54..56].fill(Self::VIDEO_GUARD);
-            }
-            _ => {
-                line[46..56].fill(sync_off);
-            }
-        }
+        let hv = self.encode_island_sync(line, state);
+        let island = line.island;
+        packet.encode(hv as u8, &mut line.words[island..island + ISLAND_SYMBOLS]);
     }
 
     #[cfg(feature = "audio")]
     #[link_section = ".data"]
-    pub fn encode_data_island_empty(
-        &self,
-        line: &mut [u32; SYNC_DATA_ISLAND_LEN],
-        state: DviTimingLineState,
-    ) {
-        let v_sync = matches!(state, DviTimingLineState::Sync);
-        let h_bit = self.h_sync_polarity as u8;
-        let v_bit = (v_sync == self.v_sync_polarity) as u8;
-        let hv = (h_bit + (v_bit << 1)) as usize;
+    pub fn encode_data_island_empty(&self, line: &mut DataIslandLine, state: DviTimingLineState) {
+        let hv = self.encode_island_sync(line, state);
         let gb = TERC4_SYMBOLS[hv + 12] as u32 | (0x133 << 10) | (0x133 << 20);
-        line[5..7].fill(gb);
-        line[7] = TERC4_SYMBOLS[hv] as u32 | (0x29c << 10) | (0x29c << 20);
-        line[8..39].fill(TERC4_SYMBOLS[hv + 8] as u32 | (0x29c << 10) | (0x29c << 20));
-        line[39..41].fill(gb);
+        let guard = GUARD_LEN as usize;
+        let island = &mut line.words[line.island..line.island + ISLAND_SYMBOLS];
+        let (packet, trailing_guard) = island.split_at_mut(ISLAND_SYMBOLS - guard);
+        let (leading_guard, packet) = packet.split_at_mut(guard);
+        leading_guard.fill(gb);
+        packet[0] = TERC4_SYMBOLS[hv] as u32 | (0x29c << 10) | (0x29c << 20);
+        packet[1..].fill(TERC4_SYMBOLS[hv + 8] as u32 | (0x29c << 10) | (0x29c << 20));
+        trailing_guard.fill(gb);
+    }
+
+    /// Fill in the parts of a data island line around the island itself,
+    /// returning the sync bits the island should carry.
+    #[cfg(feature = "audio")]
+    #[inline(always)]
+    fn encode_island_sync(&self, line: &mut DataIslandLine, state: DviTimingLineState) -> usize {
+        let v_sync = matches!(state, DviTimingLineState::Sync);
+        let h_bit = self.h_sync_polarity as usize;
+        let v_bit = (v_sync == self.v_sync_polarity) as usize;
         let sync_off = self.tmds3_for_sync(false, v_sync);
         let sync_on = self.tmds3_for_sync(true, v_sync);
         const CTRL_MASK: u32 = TMDS_CTRL[0] ^ TMDS_CTRL[1];
         let vid_preamble = sync_off ^ (CTRL_MASK << 10);
         let data_preamble = vid_preamble ^ (CTRL_MASK << 20);
-        line[1] = sync_off;
-        line[3] = data_preamble;
-        line[42] = sync_on;
-        line[44] = sync_off;
+        line.words[line.front_porch] = sync_off;
+        line.words[line.preamble] = data_preamble;
+        line.words[line.sync] = sync_on;
+        line.words[line.back_porch] = sync_off;
+        let trailer = &mut line.words[line.trailer..];
         match state {
             DviTimingLineState::Active => {
-                line[46..54].fill(vid_preamble);
-                line[54..56].fill(Self::VIDEO_GUARD);
+                let (preamble, guard) = trailer.split_at_mut(PREAMBLE_LEN as usize);
+                preamble.fill(vid_preamble);
+                guard.fill(Self::VIDEO_GUARD);
             }
             _ => {
-                line[46..56].fill(sync_off);
+                trailer.fill(sync_off);
             }
         }
+        h_bit + (v_bit << 1)
     }
 }
 
-pub const VGA_TIMING: DviTiming = DviTiming {
-    h_sync_polarity: false,
-    h_front_porch: 16,
-    h_sync_width: 96,
-    h_back_porch: 48,
-    h_active_pixels: 640,
+/// As [`DviTiming::make_err_line`], for a line `h_active_pixels` wide.
+///
+/// This is for building the line without the timing at hand; the width
+/// must be that of a valid timing.
+pub const fn err_line(h_active_pixels: u32, word: u32) -> [u32; SYNC_LINE_ONLY_WORDS] {
+    const TAIL: u32 = SYNC_TRAILING_RAW as u32 * PIXELS_PER_WORD;
+    CommandList::new()
+        .tmds_repeat(h_active_pixels - TAIL, word)
+        .tmds(TAIL, &[word; SYNC_TRAILING_RAW])
+        .build_pixels(h_active_pixels)
+}
 
-    v_sync_polarity: false,
-    v_front_porch: 10,
-    v_sync_width: 2,
-    v_back_porch: 33,
-    v_active_lines: 480,
+const fn max(a: u32, b: u32) -> u32 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// A sync pulse carrying a data island, as made by
+/// [`DviTiming::data_island_line`].
+#[cfg(feature = "audio")]
+pub struct DataIslandLine {
+    words: [u32; SYNC_DATA_ISLAND_LEN],
+    // Indices of the words that change from line to line
+    front_porch: usize,
+    preamble: usize,
+    island: usize,
+    sync: usize,
+    back_porch: usize,
+    trailer: usize,
+}
+
+#[cfg(feature = "audio")]
+impl DataIslandLine {
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+}
+
+const VGA_H: SyncTiming = SyncTiming {
+    sync_polarity: false,
+    front_porch: 16,
+    sync_width: 96,
+    back_porch: 48,
+    active: 640,
+};
 
-    bit_clk: KilohertzU32::kHz(252000),
+const VGA_V: SyncTiming = SyncTiming {
+    sync_polarity: false,
+    front_porch: 10,
+    sync_width: 2,
+    back_porch: 33,
+    active: 480,
+};
+
+pub const VGA_TIMING: DviTiming = match DviTiming::new(VGA_H, VGA_V, KilohertzU32::kHz(252000)) {
+    Ok(timing) => timing,
+    Err(_) => panic!("VGA timing should be valid"),
 };
 
 pub struct DviTimingState {
@@ -229,3 +509,154 @@ pub enum DviTimingLineState {
     BackPorch,
     Active,
 }
+
+#[cfg(test)]
+mod test {
+    use fugit::{HertzU32, KilohertzU32};
+
+    use super::{
+        DviTiming, SyncTiming, TimingError, MIN_H_PORCHES, SYNC_TRAILING_RAW, VGA_H, VGA_TIMING,
+        VGA_V,
+    };
+    use crate::dvi::command::{Command, PIXELS_PER_WORD};
+
+    #[test]
+    fn derived() {
+        assert_eq!(VGA_TIMING.h_total_pixels(), 800);
+        assert_eq!(VGA_TIMING.total_lines(), 525);
+        assert_eq!(
+            VGA_TIMING.pixel_clock(),
+            HertzU32::MHz(25) + HertzU32::kHz(200)
+        );
+        assert_eq!(VGA_TIMING.line_time().to_nanos(), 31746);
+        assert_eq!(VGA_TIMING.frame_rate_millihertz(), 60_000);
+        let sys_clk = HertzU32::MHz(126);
+        assert_eq!(VGA_TIMING.line_cycles(sys_clk), 4000);
+        assert_eq!(VGA_TIMING.h_blank_cycles(sys_clk), 800);
+        // The standard 25.175 MHz pixel clock is a little slower.
+        let standard = DviTiming::new(VGA_H, VGA_V, KilohertzU32::kHz(251750)).unwrap();
+        assert_eq!(standard.frame_rate_millihertz(), 59_940);
+    }
+
+    #[test]
+    fn validate() {
+        let bit_clk = VGA_TIMING.bit_clk();
+        assert!(DviTiming::new(VGA_H, VGA_V, bit_clk).is_ok());
+        let [min_front_porch, min_sync_width, min_back_porch] = MIN_H_PORCHES;
+        let h = |h| DviTiming::new(h, VGA_V, bit_clk);
+        let cases = [
+            (
+                h(SyncTiming {
+                    front_porch: min_front_porch - 1,
+                    ..VGA_H
+                }),
+                TimingError::FrontPorch {
+                    min: min_front_porch,
+                },
+            ),
+            (
+                h(SyncTiming {
+                    sync_width: min_sync_width - 1,
+                    ..VGA_H
+                }),
+                TimingError::SyncWidth {
+                    min: min_sync_width,
+                },
+            ),
+            (
+                h(SyncTiming {
+                    back_porch: min_back_porch - 1,
+                    ..VGA_H
+                }),
+                TimingError::BackPorch {
+                    min: min_back_porch,
+                },
+            ),
+            (
+                h(SyncTiming {
+                    active: 641,
+                    ..VGA_H
+                }),
+                TimingError::ActiveWidth {
+                    min: SYNC_TRAILING_RAW as u32 * PIXELS_PER_WORD + 1,
+                },
+            ),
+            (
+                h(SyncTiming {
+                    active: 4096,
+                    ..VGA_H
+                }),
+                TimingError::TooWide,
+            ),
+            (
+                DviTiming::new(
+                    VGA_H,
+                    SyncTiming {
+                        sync_width: 0,
+                        ..VGA_V
+                    },
+                    bit_clk,
+                ),
+                TimingError::Vertical,
+            ),
+            (
+                DviTiming::new(VGA_H, VGA_V, KilohertzU32::MHz(126)),
+                TimingError::BitClock,
+            ),
+        ];
+        for (timing, error) in cases {
+            assert_eq!(timing.err(), Some(error));
+        }
+    }
+
+    #[test]
+    fn sync_pulse() {
+        let off = VGA_TIMING.tmds3_for_sync(false, true);
+        let on = VGA_TIMING.tmds3_for_sync(true, true);
+        let line = VGA_TIMING.make_sync_pulse(true);
+        assert_eq!(
+            line[..7],
+            [
+                Command::RawRepeat.word(16),
+                off,
+                Command::RawRepeat.word(96),
+                on,
+                Command::RawRepeat.word(40),
+                off,
+                Command::Raw.word(8),
+            ]
+        );
+        assert!(line[7..].iter().all(|&w| w == off));
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn data_island_line() {
+        use super::{DviTimingLineState::*, ISLAND_SYMBOLS};
+        use crate::dvi::{data_island::DataPacket, tmds::decode_island};
+
+        let line = VGA_TIMING.data_island_line();
+        let layout = (
+            line.front_porch,
+            line.preamble,
+            line.island,
+            line.sync,
+            line.back_porch,
+            line.trailer,
+        );
+        assert_eq!(layout, (1, 3, 5, 42, 44, 46));
+        for state in [FrontPorch, Sync, BackPorch, Active] {
+            let mut line = VGA_TIMING.data_island_line();
+            VGA_TIMING.encode_data_island(&mut line, state, &DataPacket::default());
+            let mut empty = VGA_TIMING.data_island_line();
+            VGA_TIMING.encode_data_island_empty(&mut empty, state);
+            assert_eq!(empty.words(), line.words());
+            let island = line.words[line.island..line.island + ISLAND_SYMBOLS]
+                .try_into()
+                .unwrap();
+            let (_, packet) = decode_island(island).unwrap();
+            assert_eq!(packet.header, [0; 4]);
+            assert_eq!(packet.subpacket, [[0; 8]; 4]);
+        }
+    }
+}
@@ -1,5 +1,6 @@
-/// [TMDS] encoding for DVI
-/// [TMDS]: https://en.wikipedia.org/wiki/Transition-minimized_differential_signaling
+//! [TMDS] encoding for DVI.
+//!
+//! [TMDS]: https://en.wikipedia.org/wiki/Transition-minimized_differential_signaling
 
 /// A single [TMDS] symbol.
 ///
@@ -23,6 +24,10 @@ impl TmdsSymbol {
     pub const C2: Self = TmdsSymbol(0x154);
     pub const C3: Self = TmdsSymbol(0x2ab);
 
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+
     pub const fn encode(discrepancy: i32, byte: u8) -> (i32, Self) {
         let byte_ones = byte.count_ones();
 
@@ -74,6 +79,86 @@ impl TmdsSymbol {
 
         (discrepancy, TmdsSymbol(byte_encoded))
     }
+
+    pub const fn from_raw(raw: u32) -> Self {
+        TmdsSymbol(raw & 0x3ff)
+    }
+
+    /// Decode a video data symbol.
+    ///
+    /// Every 10 bit value decodes to something, so this can't detect
+    /// errors; see [`DcBalance`] for that.
+    pub const fn decode(self) -> u8 {
+        let mut q = self.0;
+        if q & 0x200 != 0 {
+            q ^= 0xff;
+        }
+        // The inverse of the carry-less multiplication in `encode`.
+        let mut byte = q ^ (q << 1);
+        if q & 0x100 == 0 {
+            byte ^= 0xfe;
+        }
+        byte as u8
+    }
+
+    /// The 2 bit value carried, if this is a control symbol.
+    pub fn control(self) -> Option<u8> {
+        [Self::C0, Self::C1, Self::C2, Self::C3]
+            .iter()
+            .position(|c| c.0 == self.0)
+            .map(|ix| ix as u8)
+    }
+
+    /// The 4 bit value carried, if this is a TERC4 symbol.
+    pub fn terc4(self) -> Option<u8> {
+        TERC4_SYMBOLS
+            .iter()
+            .position(|&s| s as u32 == self.0)
+            .map(|ix| ix as u8)
+    }
+
+    /// How much the symbol moves the running disparity, in the units
+    /// `encode` tracks it: half the excess of ones over zeros.
+    pub const fn disparity(self) -> i32 {
+        self.0.count_ones() as i32 - 5
+    }
+}
+
+/// The furthest the running disparity of [`TmdsSymbol::encode`] can drift
+/// from zero.
+pub const MAX_DISPARITY: i32 = 4;
+
+/// Running disparity of a lane's video data, for checking DC balance.
+#[derive(Clone, Copy, Default)]
+pub struct DcBalance {
+    running: i32,
+    peak: i32,
+}
+
+impl DcBalance {
+    /// Account for a received video data symbol.
+    ///
+    /// Returns false if the running disparity has drifted further than a
+    /// conforming encoder allows.
+    pub fn push(&mut self, symbol: TmdsSymbol) -> bool {
+        self.running += symbol.disparity();
+        self.peak = self.peak.max(self.running.abs());
+        self.running.abs() <= MAX_DISPARITY
+    }
+
+    /// Start over at the end of a video period, as the encoder does.
+    pub fn reset(&mut self) {
+        self.running = 0;
+    }
+
+    pub fn running(&self) -> i32 {
+        self.running
+    }
+
+    /// The largest running disparity seen, in either direction.
+    pub fn peak(&self) -> i32 {
+        self.peak
+    }
 }
 
 impl TmdsPair {
@@ -120,11 +205,230 @@ impl TmdsPair {
     }
 }
 
-// TODO: https://lib.rs/crates/defmt-test
-// TODO: generate test cases from known working implementation???
+/// TERC4 symbols for each 4 bit value, as carried by data islands.
+#[link_section = ".data"]
+pub static TERC4_SYMBOLS: [u16; 16] = [
+    0b1010011100,
+    0b1001100011,
+    0b1011100100,
+    0b1011100010,
+    0b0101110001,
+    0b0100011110,
+    0b0110001110,
+    0b0100111100,
+    0b1011001100,
+    0b0100111001,
+    0b0110011100,
+    0b1011000110,
+    0b1010001110,
+    0b1001110001,
+    0b0101100011,
+    0b1011000011,
+];
+
+/// BCH parity of data island header or subpacket bytes.
+///
+/// This is the ECC of the HDMI spec, with generator polynomial
+/// `1 + x^6 + x^7 + x^8`, taken a bit at a time with the least
+/// significant bit first.
+pub fn bch_parity(data: &[u8]) -> u8 {
+    let mut parity = 0u8;
+    for &byte in data {
+        for i in 0..8 {
+            let feedback = ((byte >> i) ^ parity) & 1;
+            parity >>= 1;
+            if feedback != 0 {
+                parity ^= 0x83;
+            }
+        }
+    }
+    parity
+}
+
+/// Guard band symbols of lanes 1 and 2 around a data island.
+pub const ISLAND_GUARD: u32 = 0x133;
+
+/// The contents of a data island packet, as decoded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct IslandPacket {
+    /// Header bytes, the last being the BCH parity.
+    pub header: [u8; 4],
+    /// Subpackets, the last byte of each being the BCH parity.
+    pub subpacket: [[u8; 8]; 4],
+}
+
+/// A problem found decoding a data island.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum IslandError {
+    /// Symbol `ix` of the island should have been a guard band symbol.
+    GuardBand { ix: u8 },
+    /// Lane `lane` of symbol `ix` isn't a TERC4 symbol.
+    Terc4 { ix: u8, lane: u8 },
+    /// Bit 3 of lane 0 should be clear for the first packet symbol only,
+    /// and wasn't at symbol `ix`.
+    PacketStart { ix: u8 },
+    /// The sync bits changed at symbol `ix`.
+    Sync { ix: u8 },
+    /// The header failed its BCH check.
+    HeaderParity,
+    /// Subpacket `n` failed its BCH check.
+    SubpacketParity { n: u8 },
+}
+
+/// Decode a data island holding a single packet.
+///
+/// The symbols are 3 lanes packed into 30 bits, as in the HSTX raw
+/// commands: two guard band symbols, the 32 packet symbols, and two
+/// more guard band symbols. Returns the sync bits carried on lane 0
+/// along with the packet. The decoding follows the HDMI spec rather than
+/// the encoder's tables, so it can check them.
+pub fn decode_island(symbols: &[u32; 36]) -> Result<(u8, IslandPacket), IslandError> {
+    let lane = |word: u32, lane: usize| TmdsSymbol::from_raw(word >> (10 * lane));
+    let mut hv = None;
+    let mut packet = IslandPacket::default();
+    for (ix, &word) in symbols.iter().enumerate() {
+        let terc4 = |l: usize| {
+            lane(word, l).terc4().ok_or(IslandError::Terc4 {
+                ix: ix as u8,
+                lane: l as u8,
+            })
+        };
+        let c0 = terc4(0)?;
+        if *hv.get_or_insert(c0 & 3) != c0 & 3 {
+            return Err(IslandError::Sync { ix: ix as u8 });
+        }
+        if !(2..34).contains(&ix) {
+            let guard = lane(word, 1).0 == ISLAND_GUARD && lane(word, 2).0 == ISLAND_GUARD;
+            if !guard || c0 >> 2 != 0b11 {
+                return Err(IslandError::GuardBand { ix: ix as u8 });
+            }
+            continue;
+        }
+        let (c1, c2) = (terc4(1)?, terc4(2)?);
+        let k = ix - 2;
+        if (c0 >> 3 != 0) != (k != 0) {
+            return Err(IslandError::PacketStart { ix: ix as u8 });
+        }
+        packet.header[k / 8] |= ((c0 >> 2) & 1) << (k % 8);
+        // Lane 1 carries the even bits of each subpacket, lane 2 the odd.
+        for (i, subpacket) in packet.subpacket.iter_mut().enumerate() {
+            for (bit, nibble) in [(2 * k, c1), (2 * k + 1, c2)] {
+                subpacket[bit / 8] |= ((nibble >> i) & 1) << (bit % 8);
+            }
+        }
+    }
+    if bch_parity(&packet.header[..3]) != packet.header[3] {
+        return Err(IslandError::HeaderParity);
+    }
+    for (n, subpacket) in packet.subpacket.iter().enumerate() {
+        if bch_parity(&subpacket[..7]) != subpacket[7] {
+            return Err(IslandError::SubpacketParity { n: n as u8 });
+        }
+    }
+    Ok((hv.unwrap(), packet))
+}
+
 #[cfg(test)]
-#[defmt_test::tests]
 mod test {
+    use super::{bch_parity, DcBalance, TmdsSymbol, MAX_DISPARITY, TERC4_SYMBOLS};
+
+    /// The encoder of the DVI 1.0 specification, bit by bit. `cnt` is the
+    /// running disparity as there, the excess of ones over zeros.
+    fn encode_spec(cnt: i32, d: u8) -> (i32, u32) {
+        let bit = |x: u32, i: u32| (x >> i) & 1;
+        let n1_d = d.count_ones();
+        let xnor = n1_d > 4 || (n1_d == 4 && bit(d as u32, 0) == 0);
+        let mut q_m = d as u32 & 1;
+        for i in 1..8 {
+            let b = bit(q_m, i - 1) ^ bit(d as u32, i) ^ xnor as u32;
+            q_m |= b << i;
+        }
+        q_m |= (!xnor as u32) << 8;
+        let n1 = (q_m & 0xff).count_ones() as i32;
+        let n0 = 8 - n1;
+        let q_m8 = bit(q_m, 8) as i32;
+        if cnt == 0 || n1 == n0 {
+            if q_m8 == 1 {
+                (cnt + n1 - n0, q_m)
+            } else {
+                (cnt + n0 - n1, (q_m ^ 0xff) | (1 << 9))
+            }
+        } else if (cnt > 0 && n1 > n0) || (cnt < 0 && n0 > n1) {
+            (cnt + 2 * q_m8 + n0 - n1, (q_m ^ 0xff) | (1 << 9))
+        } else {
+            (cnt - 2 * (1 - q_m8) + n1 - n0, q_m)
+        }
+    }
+
+    #[test]
+    fn encode() {
+        for discrepancy in -MAX_DISPARITY..=MAX_DISPARITY {
+            for byte in 0..=255 {
+                let (next, symbol) = TmdsSymbol::encode(discrepancy, byte);
+                let (cnt, expected) = encode_spec(2 * discrepancy, byte);
+                assert_eq!(symbol.raw(), expected, "byte {byte:#04x} at {discrepancy}");
+                assert_eq!(2 * next, cnt, "byte {byte:#04x} at {discrepancy}");
+            }
+        }
+    }
+
+    #[test]
+    fn decode() {
+        for discrepancy in -MAX_DISPARITY..=MAX_DISPARITY {
+            for byte in 0..=255 {
+                let (_, symbol) = TmdsSymbol::encode(discrepancy, byte);
+                assert_eq!(symbol.decode(), byte);
+            }
+        }
+    }
+
     #[test]
-    fn encode() {}
+    fn classify() {
+        for (ix, c) in [
+            TmdsSymbol::C0,
+            TmdsSymbol::C1,
+            TmdsSymbol::C2,
+            TmdsSymbol::C3,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(c.control(), Some(ix as u8));
+            assert_eq!(c.terc4(), None);
+        }
+        for (ix, &s) in TERC4_SYMBOLS.iter().enumerate() {
+            assert_eq!(TmdsSymbol::from_raw(s as u32).terc4(), Some(ix as u8));
+        }
+    }
+
+    #[test]
+    fn dc_balance() {
+        let mut balance = DcBalance::default();
+        let mut discrepancy = 0;
+        let mut seed = 1u32;
+        for _ in 0..4096 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let symbol;
+            (discrepancy, symbol) = TmdsSymbol::encode(discrepancy, (seed >> 24) as u8);
+            assert!(balance.push(symbol));
+            assert_eq!(balance.running(), discrepancy);
+        }
+        assert_eq!(balance.peak(), MAX_DISPARITY);
+        // A run of unbalanced symbols is caught.
+        balance.reset();
+        let heavy = TmdsSymbol::from_raw(0x03f);
+        assert!((0..MAX_DISPARITY).all(|_| balance.push(heavy)));
+        assert!(!balance.push(heavy));
+    }
+
+    #[test]
+    fn bch() {
+        assert_eq!(bch_parity(&[0; 7]), 0);
+        assert_eq!(bch_parity(&[1]), 0xd9);
+        // AVI InfoFrame header, version 2, length 13
+        let header = [0x82, 2, 13];
+        let parity = bch_parity(&header);
+        // The parity makes the whole codeword divisible by the generator.
+        assert_eq!(bch_parity(&[header[0], header[1], header[2], parity]), 0);
+    }
 }
@@ -0,0 +1,44 @@
+//! DVI video output on the RP2350, using HSTX.
+//!
+//! Display lists are built and checked here, along with the video timing
+//! and clock setup. All of this also builds for the host, where display
+//! lists can be run by a reference interpreter instead of scanout. The
+//! scanout runtime, its interrupt handler and asm kernels are in the
+//! binary.
+//!
+//! The tests also run on the host, with
+//! `cargo test --lib --all-features --target <host triple>`.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "audio")]
+pub mod audio;
+pub mod clock;
+pub mod dvi;
+pub mod render;
+pub mod scanlist;
+
+use rp235x_hal as hal;
+
+/// The library logs through defmt; host tests discard its output.
+#[cfg(test)]
+mod host_defmt {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        core::panic!("defmt panic")
+    }
+}
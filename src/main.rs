@@ -6,7 +6,6 @@ extern crate alloc;
 use core::{arch::global_asm, cell::UnsafeCell, mem::MaybeUninit};
 
 use defmt_rtt as _;
-use dvi::core1_main;
 use panic_probe as _; // TODO: remove if you need 5kb of space, since panicking + formatting machinery is huge
 
 use defmt::info;
@@ -18,30 +17,30 @@ use hal::{
     sio::Sio,
     watchdog::Watchdog,
 };
-use render::{init_display_swapcell, Palette4bppFast};
-use rp235x_hal as hal;
-
-use crate::{
-    clock::init_clocks,
+use pico_dvi_rs::{
+    clock::{init_clocks, ClockConfig, HstxClockSource, RATED_SYS_CLOCK, SAFE_CLOCK_LIMITS},
     dvi::{
+        self,
         pinout::{DviPinout, DviPolarity},
         timing::VGA_TIMING,
-        DviInst, DviOut,
+        HstxEdges, HstxMultiple,
     },
+    render::Palette4bppFast,
 };
+use rp235x_hal as hal;
+use scanout::init_display_swapcell;
+use video::{core1_main, DviInst, DviOut};
 
-mod clock;
 mod demo;
-mod dvi;
 mod link;
-mod render;
-mod scanlist;
+mod scanout;
+mod video;
 
-/// The number of HSTX bits per system clock.
+/// Whether to clock HSTX from PLL_USB.
 ///
-/// Ordinarily this is 2 so the system doesn't need to be overclocked, but
-/// can be 1 to provide more CPU horsepower per pixel.
-const HSTX_MULTIPLE: u32 = 2;
+/// This leaves the system clock at its rated speed whatever the video mode,
+/// instead of tying it to the bit clock, at the cost of USB.
+const HSTX_FROM_PLL_USB: bool = false;
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -115,6 +114,12 @@ fn entry() -> ! {
     let single_cycle_io = Sio::new(peripherals.SIO);
 
     let timing = VGA_TIMING;
+    // Two keeps the system clock down; One gives the CPU more cycles per
+    // pixel.
+    let hstx_multiple = HstxMultiple::Two;
+    // Single edge output needs HSTX clocked at the full bit clock, past its
+    // rating, so it also needs max_hstx_clock raised in the limits.
+    let hstx_edges = HstxEdges::Both;
 
     // External high-speed crystal on the pico board is 12Mhz
     let _clocks = init_clocks(
@@ -123,11 +128,25 @@ fn entry() -> ! {
         peripherals.CLOCKS,
         peripherals.PLL_SYS,
         peripherals.PLL_USB,
+        peripherals.POWMAN,
+        peripherals.QMI,
         &mut peripherals.RESETS,
         &mut watchdog,
-        timing.bit_clk / HSTX_MULTIPLE,
-        2 / HSTX_MULTIPLE,
-    );
+        &ClockConfig {
+            hstx_clock: hstx_edges.hstx_clock(timing.bit_clk()),
+            hstx_source: if HSTX_FROM_PLL_USB {
+                HstxClockSource::PllUsb {
+                    sys_clock: RATED_SYS_CLOCK,
+                }
+            } else {
+                HstxClockSource::SystemClock {
+                    divisor: hstx_multiple.sys_divisor(hstx_edges),
+                }
+            },
+            limits: SAFE_CLOCK_LIMITS,
+        },
+    )
+    .expect("system clock should be within tolerance of the bit clock");
 
     let pins = hal::gpio::Pins::new(
         peripherals.IO_BANK0,
@@ -142,7 +161,8 @@ fn entry() -> ! {
 
     let _dma = peripherals.DMA.split(&mut peripherals.RESETS);
 
-    let width = timing.h_active_pixels;
+    let width = timing.h_active_pixels();
+    let height = timing.v_active_lines() / dvi::VERTICAL_REPEAT as u32;
 
     unsafe {
         (*DVI_INST.0.get()).write(DviInst::new(timing, gpio_pin));
@@ -156,8 +176,8 @@ fn entry() -> ! {
         let pinout = DviPinout::new([D2, Clk, D1, D0], DviPolarity::Pos);
         // Pinout for Olimex RP2350pc
         //let pinout = DviPinout::new([D0, Clk, D2, D1], DviPolarity::Pos);
-        dvi::setup_hstx(&periphs.HSTX_CTRL, pinout);
-        dvi::setup_dma(&periphs.DMA, &periphs.HSTX_FIFO);
+        dvi::setup_hstx(&periphs.HSTX_CTRL, pinout, hstx_edges);
+        video::setup_dma(&periphs.DMA, &periphs.HSTX_FIFO);
         periphs
             .BUSCTRL
             .bus_priority()
@@ -165,14 +185,24 @@ fn entry() -> ! {
         dvi::setup_pins(&periphs.PADS_BANK0, &periphs.IO_BANK0);
     }
 
-    init_display_swapcell(width);
+    // Derive audio timing from the clock we actually got.
+    #[cfg(feature = "audio")]
+    unsafe {
+        use hal::Clock;
+        let bit_clk = _clocks.hstx_clock.freq() * hstx_edges as u32;
+        (*DVI_INST.0.get()).assume_init_mut().set_bit_clock(bit_clk);
+    }
+
+    init_display_swapcell(width, height);
 
     let mut fifo = single_cycle_io.fifo;
     let mut mc = Multicore::new(&mut peripherals.PSM, &mut peripherals.PPB, &mut fifo);
     let cores = mc.cores();
     let core1 = &mut cores[1];
     core1
-        .spawn(unsafe { CORE1_STACK.take().unwrap() }, move || core1_main())
+        .spawn(unsafe { CORE1_STACK.take().unwrap() }, move || {
+            core1_main(width, height)
+        })
         .unwrap();
 
     demo::demo(led_pin);
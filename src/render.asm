@@ -131,3 +131,28 @@ render_blit_64_straddle:
     lsrs r3, r7
     ldmia r0!, {r4, r5, r6, r7}
     bx r4
+
+// Copy whole words, for line buffer formats of 8bpp and up.
+// The output must be word aligned, with nothing pending in r3.
+// args: input, stride, count (words)
+.global render_blit_words
+.type render_blit_words,%function
+.thumb_func
+render_blit_words:
+    muls r6, r2
+    adds r5, r6
+    lsrs r7, #1
+    bcc 1f
+    ldmia r5!, {r4}
+    stmia r1!, {r4}
+1:
+    beq 3f
+2:
+    ldmia r5!, {r3, r4}
+    stmia r1!, {r3, r4}
+    subs r7, #1
+    bne 2b
+    movs r3, #0
+3:
+    ldmia r0!, {r4, r5, r6, r7}
+    bx r4
@@ -1,81 +1,69 @@
+mod fade;
 mod font;
+mod gradient;
 mod palette;
 mod queue;
+#[cfg(not(target_os = "none"))]
+mod reference;
 mod renderlist;
+mod sprite;
 mod swapcell;
 
-pub use font::FONT_HEIGHT;
-
-pub use palette::{Palette1bpp, Palette4bppFast, BW_PALETTE_1BPP};
+pub use fade::{fade_level, fade_to, Fade, FadeState, FADE_MAX};
 
-pub use queue::Queue;
+pub use font::FONT_HEIGHT;
 
-use crate::dvi::BPP;
+pub use gradient::DITHER_ROWS;
+pub(crate) use gradient::{gradient_state, GRADIENT_STATE_LEN};
 
-use crate::{
-    dvi::VERTICAL_REPEAT,
-    scanlist::{Scanlist, ScanlistBuilder},
+pub use palette::{
+    AnimatedPalette4bpp, ColorCycle, Palette1bpp, Palette2bpp, Palette4bppFast, Palette8bpp,
+    SpritePalette, BW_PALETTE_1BPP,
 };
 
-use self::{
-    renderlist::{Renderlist, RenderlistBuilder},
-    swapcell::SwapCell,
-};
+pub use queue::Queue;
 
-pub struct ScanRender {
-    display_list: DisplayList,
-    stripe_remaining: u32,
-    scan_ptr: *const u32,
-    scan_next: *const u32,
-    render_ptr: *const u32,
-    render_y: u32,
-    last_y: u32,
-}
+#[cfg(not(target_os = "none"))]
+pub use reference::{render_frame, Frame};
 
-/// Size of a line buffer in u32 units.
-const LINE_BUF_SIZE: usize = 256;
+pub use renderlist::{Renderlist, RenderlistBuilder};
 
-static mut LINE_BUF: LineBuf = LineBuf::zero();
+pub(crate) use sprite::latch_layer;
+pub use sprite::{Sprite, SpriteLayer};
 
-/// A complete display list.
-///
-/// This can be seen as an expression that generates the pixels for a frame.
-pub struct DisplayList {
-    render: Renderlist,
-    scan: Scanlist,
-}
+pub use swapcell::SwapCell;
 
-static DISPLAY_LIST_SWAPCELL: SwapCell<DisplayList> = SwapCell::new();
+use crate::dvi::{BPP, COLOR_MODE};
 
-#[derive(Clone, Copy)]
-pub struct LineBuf {
-    buf: [u32; LINE_BUF_SIZE],
-}
+use crate::scanlist::{Scanlist, ScanlistBuilder};
 
-impl LineBuf {
-    const fn zero() -> Self {
-        let buf = [0; LINE_BUF_SIZE];
-        LineBuf { buf }
-    }
-}
+/// Widest scanline supported, in pixels.
+pub const MAX_LINE_WIDTH: usize = 640;
 
-core::arch::global_asm! {
-    include_str!("scan.asm"),
-    include_str!("render.asm"),
-    options(raw)
-}
+/// Deepest line buffer format, in bits per pixel.
+pub const MAX_LINE_BPP: usize = 16;
 
-extern "C" {
-    fn video_scan(scan_list: *const u32, input: *const u32, output: *mut u32) -> *const u32;
+/// Size of a line buffer in u32 units.
+pub const LINE_BUF_SIZE: usize = MAX_LINE_WIDTH * MAX_LINE_BPP / 32;
 
-    fn render_engine(render_list: *const u32, output: *mut u32, y: u32);
+/// A complete display list.
+///
+/// This can be seen as an expression that generates the pixels for a frame.
+pub struct DisplayList {
+    pub render: Renderlist,
+    pub scan: Scanlist,
 }
 
+/// Creates a packed color from 8 bit RGB channels.
+///
+/// The result is encoded for the link according to [`COLOR_MODE`], so
+/// limited range and YCbCr output need no changes to application code.
 pub const fn rgb(r: u8, g: u8, b: u8) -> u32 {
-    if BPP == 16 {
-        (b as u32 >> 3) | ((g as u32 & 0xf8) << 2) | ((r as u32 & 0xf8) << 7)
-    } else {
-        panic!("unsupported color depth")
+    let [c2, c1, c0] = COLOR_MODE.encode(r, g, b);
+    match BPP {
+        16 => (c0 as u32 >> 3) | ((c1 as u32 & 0xf8) << 2) | ((c2 as u32 & 0xf8) << 7),
+        32 => c0 as u32 | ((c1 as u32) << 8) | ((c2 as u32) << 16),
+        _ => panic!("unsupported color depth"),
     }
 }
 
@@ -85,68 +73,15 @@ pub const fn rgb(r: u8, g: u8, b: u8) -> u32 {
 ///
 /// If each channel is already separated out, use [`rgb`] instead.
 pub const fn xrgb(color: u32) -> u32 {
-    if BPP == 32 {
-        color
-    } else {
-        rgb((color >> 16) as u8, (color >> 8) as u8, color as u8)
-    }
+    rgb((color >> 16) as u8, (color >> 8) as u8, color as u8)
 }
 
-impl ScanRender {
-    pub fn new() -> Self {
-        let stripe_remaining = 0;
-        let scan_ptr = core::ptr::null();
-        let scan_next = core::ptr::null();
-        let render_ptr = core::ptr::null();
-        let render_y = 0;
-        let display_list = DisplayList::new(640, 480 / VERTICAL_REPEAT as u32);
-        ScanRender {
-            stripe_remaining,
-            scan_ptr,
-            scan_next,
-            render_ptr,
-            render_y,
-            display_list,
-            last_y: 0,
-        }
-    }
-
-    /// Render one scanline from the line buffer into the provided video buffer.
-    #[link_section = ".data"]
-    // TODO: probably should be inline-able, this was probably for inspecting disasm
-    #[inline(never)]
-    pub fn render_scanline(&mut self, video_buf: &mut [u32], y: u32) {
-        unsafe {
-            if y <= self.last_y {
-                DISPLAY_LIST_SWAPCELL.try_swap_by_system(&mut self.display_list);
-
-                self.render_ptr = self.display_list.render.get().as_ptr();
-                self.render_y = 0;
-                self.scan_next = self.display_list.scan.get().as_ptr();
-                self.stripe_remaining = 0;
-            }
-            if self.stripe_remaining == 0 {
-                self.stripe_remaining = self.scan_next.read();
-                self.scan_ptr = self.scan_next.add(1);
-                // TODO: set desperate scan_next
-            }
-            // we could just stack allocate the tmp, as we're currently
-            // completely synchronous.
-            let line_buf_ptr = LINE_BUF.buf.as_mut_ptr();
-            let render_ptr = self.render_ptr.add(2);
-            render_engine(render_ptr, line_buf_ptr, self.render_y);
-            self.render_y += 1;
-            let stripe_height = self.render_ptr.read();
-            // TODO: this assumes we don't miss; can be made much more robust.
-            if self.render_y == stripe_height {
-                let jump = self.render_ptr.add(1).read() as usize;
-                self.render_ptr = self.display_list.render.get().as_ptr().add(jump);
-                self.render_y = 0;
-            }
-            self.scan_next = video_scan(self.scan_ptr, line_buf_ptr, video_buf.as_mut_ptr());
-            self.stripe_remaining -= 1;
-            self.last_y = y;
-        }
+impl core::fmt::Debug for DisplayList {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "renderlist:")?;
+        write!(f, "{:?}", self.render)?;
+        writeln!(f, "scanlist:")?;
+        write!(f, "{:?}", self.scan)
     }
 }
 
@@ -158,33 +93,18 @@ impl DisplayList {
         rb.end_stripe();
         sb.begin_stripe(height);
         sb.solid(width, rgb(0, 0, 0));
-        sb.end_stripe();
+        let stripe = sb.end_stripe();
         let render = rb.build();
-        let scan = sb.build();
+        let (Ok(()), Ok(scan)) = (stripe, sb.build()) else {
+            unreachable!("a single solid stripe covers the display")
+        };
         DisplayList { render, scan }
     }
-}
-
-/// The system assumes that this is called before [`start_display_list`]
-pub fn init_display_swapcell(width: u32) {
-    // The display list doesn't have to be usable.
-    DISPLAY_LIST_SWAPCELL.set_for_client(DisplayList::new(width, 0));
-}
 
-/// Start building a display list. This blocks until a free display
-/// list is available.
-///
-/// The user must have called [`init_display_swapcell`] before calling this.
-pub fn start_display_list() -> (RenderlistBuilder, ScanlistBuilder) {
-    let display_list = DISPLAY_LIST_SWAPCELL.take_blocking();
-    let rb = RenderlistBuilder::recycle(display_list.render);
-    let sb = ScanlistBuilder::recycle(display_list.scan);
-    (rb, sb)
-}
-
-pub fn end_display_list(rb: RenderlistBuilder, sb: ScanlistBuilder) {
-    let render = rb.build();
-    let scan = sb.build();
-    let display_list = DisplayList { render, scan };
-    DISPLAY_LIST_SWAPCELL.set_for_system(display_list);
+    /// Start building a new display list in the storage of this one.
+    pub fn recycle(self) -> (RenderlistBuilder, ScanlistBuilder) {
+        let rb = RenderlistBuilder::recycle(self.render);
+        let sb = ScanlistBuilder::recycle(self.scan);
+        (rb, sb)
+    }
 }
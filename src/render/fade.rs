@@ -0,0 +1,233 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    dvi::{ColorMode, COLOR_MODE},
+    scanlist::rgb555_to_rgb888,
+};
+
+use super::xrgb;
+
+/// Brightness level with no fade applied.
+pub const FADE_MAX: u32 = 32;
+
+/// Longest fade, in frames.
+const MAX_FADE_FRAMES: u32 = 0x7ff;
+
+/// Value of [`FADE_REQUEST`] when there is no new request.
+const NO_REQUEST: u32 = !0;
+
+/// A fade requested by the application, picked up at the next frame.
+///
+/// This packs the 16bpp color in bits 0-14, the level in bits 15-20 and
+/// the number of frames in bits 21-31.
+static FADE_REQUEST: AtomicU32 = AtomicU32::new(NO_REQUEST);
+
+/// The level applied to the current frame.
+static FADE_LEVEL: AtomicU32 = AtomicU32::new(FADE_MAX);
+
+/// Fade state, advanced by scanout once per frame.
+pub struct FadeState {
+    /// Current level, with 16 fractional bits.
+    level: u32,
+    step: i32,
+    remaining: u32,
+    target: u32,
+    /// The color faded to, 16bpp.
+    color: u32,
+    /// The fade applied to this frame.
+    applied: Fade,
+}
+
+/// A fade level and color, as applied to a frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Fade {
+    level: u32,
+    /// The color faded to, 16bpp.
+    color: u32,
+    /// The color scaled by the complement of `level`, for a pair of pixels.
+    color_term: u32,
+}
+
+/// Fade the whole display toward a 0xRRGGBB color.
+///
+/// `level` is the brightness to end at, from 0 (entirely `color`) up to
+/// [`FADE_MAX`] (no fade), reached in a straight line over `frames` frames,
+/// at most 2047. Use black or white for screen transitions. The fade starts
+/// from the current level, so a new request smoothly takes over from one
+/// in progress.
+///
+/// The fade is applied once per frame, to the solid colors, palettes,
+/// gradients and sprite palettes of the scanlist, so it costs no scanout
+/// time per line. RGB555 pixels, whether from the line buffer or read from
+/// images, are not faded, as that would cost time per pixel.
+///
+/// Colors are blended as RGB, so fades only work with
+/// [`ColorMode::RgbFull`]; in other modes this does nothing.
+pub fn fade_to(color: u32, level: u32, frames: u32) {
+    if COLOR_MODE != ColorMode::RgbFull {
+        return;
+    }
+    let level = level.min(FADE_MAX);
+    let request = xrgb(color) | (level << 15) | (frames.min(MAX_FADE_FRAMES) << 21);
+    FADE_REQUEST.store(request, Ordering::Relaxed);
+}
+
+/// The fade level of the frame being displayed.
+///
+/// An application can wait for this to reach its target before switching
+/// scenes under cover of a fade to black.
+pub fn fade_level() -> u32 {
+    FADE_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Scale both RGB555 pixels of a word by `level / FADE_MAX`.
+///
+/// The channels are split into two sets with room for the product between
+/// them, so each set takes a single multiply.
+pub const fn scale_pair(pixels: u32, level: u32) -> u32 {
+    const MASK_A: u32 = 0x03e0_7c1f;
+    const MASK_B: u32 = 0x03e0_f81f;
+    let a = (((pixels & MASK_A) * level) >> 5) & MASK_A;
+    let b = ((((pixels >> 5) & MASK_B) * level) >> 5) & MASK_B;
+    a | (b << 5)
+}
+
+impl Fade {
+    /// No fade.
+    pub const NONE: Fade = Fade::new(0, FADE_MAX);
+
+    /// Fade toward a 16bpp `color`, to brightness `level` out of
+    /// [`FADE_MAX`].
+    pub const fn new(color: u32, level: u32) -> Self {
+        let level = if level < FADE_MAX { level } else { FADE_MAX };
+        let color = color & 0x7fff;
+        Fade {
+            level,
+            color,
+            color_term: scale_pair(color | (color << 16), FADE_MAX - level),
+        }
+    }
+
+    /// Whether the fade changes any colors.
+    pub fn is_active(self) -> bool {
+        self.level < FADE_MAX
+    }
+
+    /// Fade both 16bpp pixels of a word.
+    pub fn pixels(self, pixels: u32) -> u32 {
+        scale_pair(pixels, self.level) + self.color_term
+    }
+
+    /// Fade a 0xRRGGBB color, such as the end of a gradient.
+    pub fn rgb888(self, color: u32) -> u32 {
+        let target = rgb555_to_rgb888(self.color);
+        [0, 8, 16].into_iter().fold(0, |faded, shift| {
+            let c = (color >> shift) & 0xff;
+            let t = (target >> shift) & 0xff;
+            let c = (c * self.level + t * (FADE_MAX - self.level)) / FADE_MAX;
+            faded | (c << shift)
+        })
+    }
+}
+
+impl Default for FadeState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FadeState {
+    pub const fn new() -> Self {
+        FadeState {
+            level: FADE_MAX << 16,
+            step: 0,
+            remaining: 0,
+            target: FADE_MAX << 16,
+            color: 0,
+            applied: Fade::NONE,
+        }
+    }
+
+    /// Take up any new request and advance the fade by a frame.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn next_frame(&mut self) {
+        let request = FADE_REQUEST.swap(NO_REQUEST, Ordering::Relaxed);
+        if request != NO_REQUEST {
+            self.color = request & 0x7fff;
+            self.target = ((request >> 15) & 0x3f) << 16;
+            self.remaining = request >> 21;
+            if self.remaining == 0 {
+                self.level = self.target;
+            } else {
+                let delta = self.target as i32 - self.level as i32;
+                self.step = delta / self.remaining as i32;
+            }
+        }
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.level = if self.remaining == 0 {
+                self.target
+            } else {
+                self.level.wrapping_add_signed(self.step)
+            };
+        }
+        self.applied = Fade::new(self.color, (self.level + 0x8000) >> 16);
+        FADE_LEVEL.store(self.applied.level, Ordering::Relaxed);
+    }
+
+    /// The fade to apply to this frame, with [`Scanlist::set_fade`].
+    ///
+    /// [`Scanlist::set_fade`]: crate::scanlist::Scanlist::set_fade
+    pub fn fade(&self) -> Fade {
+        self.applied
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fade_level, fade_to, scale_pair, Fade, FadeState, FADE_MAX};
+
+    #[test]
+    fn scale() {
+        for level in [0, 1, 7, 16, 31, FADE_MAX] {
+            for c in 0..32 {
+                let pixel = c | ((31 - c) << 5) | (c << 10);
+                let pair = pixel | ((pixel ^ 0x7fff) << 16);
+                let scaled = scale_pair(pair, level);
+                for (pixel, shift) in [(pair, 0), (pair >> 16, 16)] {
+                    for channel in [0, 5, 10] {
+                        let expected = ((pixel >> channel) & 0x1f) * level / FADE_MAX;
+                        assert_eq!((scaled >> (shift + channel)) & 0x1f, expected);
+                    }
+                }
+                assert_eq!(scaled & 0x8000_8000, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn rgb888() {
+        assert_eq!(Fade::new(0, 16).rgb888(0xff8040), 0x7f4020);
+        assert_eq!(Fade::new(0x7fff, 0).rgb888(0x123456), 0xffffff);
+        assert_eq!(Fade::NONE.rgb888(0x123456), 0x123456);
+    }
+
+    #[test]
+    fn animate() {
+        let mut state = FadeState::new();
+        state.next_frame();
+        assert_eq!(fade_level(), FADE_MAX);
+        fade_to(0x000000, 0, 4);
+        let mut levels = [0; 4];
+        for level in &mut levels {
+            state.next_frame();
+            *level = fade_level();
+        }
+        assert_eq!(levels, [24, 16, 8, 0]);
+        state.next_frame();
+        assert_eq!(fade_level(), 0);
+        fade_to(0x000000, FADE_MAX, 0);
+        state.next_frame();
+        assert_eq!(fade_level(), FADE_MAX);
+    }
+}
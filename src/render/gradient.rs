@@ -0,0 +1,82 @@
+use crate::dvi::COLOR_MODE;
+
+/// Rows in the ordered dither pattern.
+pub const DITHER_ROWS: u32 = 4;
+
+/// Thresholds for 4x4 ordered (Bayer) dithering, in sixteenths.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// One step of a 5 bit output channel, in the fixed point of the kernel.
+const LEVEL: i32 = 1 << 11;
+
+/// Words of scanout state of a gradient op.
+pub(crate) const GRADIENT_STATE_LEN: usize = 10 + 2 * DITHER_ROWS as usize;
+
+/// Compute the scanout state of a horizontal gradient.
+///
+/// The gradient runs from `start` at the first pixel to `end` at pixel
+/// `count - 1`, and starts at display position (`x`, `y`). Colors are
+/// 0xRRGGBB.
+///
+/// The kernel generates the pixels as it goes. It keeps each channel in
+/// 5.11 fixed point, in 16 bit lanes holding a pair of pixels as in the
+/// output word. For each word, it adds a threshold with unsigned
+/// saturation and keeps the top 5 bits of the lanes, then advances them
+/// by two pixels. Lane arithmetic wraps, so a step of any size works as
+/// long as the channels themselves stay in range. The threshold rounds,
+/// or gives ordered dithering aligned to the display.
+///
+/// The state is `{offset, stride, reset, end, r, g, b, dr, dg, db,
+/// thresholds}`, where `offset` steps through the rows of thresholds, in
+/// bytes of 4 byte words. Each row is a pair of threshold words, for
+/// alternate words of output starting at the first aligned one. If `x`
+/// is odd, the first word is for pixels -1 and 0 of the gradient, and
+/// only its upper half is output.
+#[cfg_attr(target_os = "none", link_section = ".data")]
+pub(crate) fn gradient_state(
+    x: u32,
+    y: u32,
+    count: u32,
+    start: u32,
+    end: u32,
+    dither: bool,
+) -> [usize; GRADIENT_STATE_LEN] {
+    let encode = |c: u32| COLOR_MODE.encode((c >> 16) as u8, (c >> 8) as u8, c as u8);
+    let (start, end) = (encode(start), encode(end));
+    let span = count.saturating_sub(1).max(1) as i32;
+    let first = -((x % 2) as i32);
+    let lanes = |lo: i32, hi: i32| (lo as u16 as u32 | (hi as u16 as u32) << 16) as usize;
+
+    let row_stride = 2 * size_of::<u32>();
+    let reset = (y % DITHER_ROWS) as usize * row_stride;
+    let mut state = [0; GRADIENT_STATE_LEN];
+    state[..4].copy_from_slice(&[reset, row_stride, reset, DITHER_ROWS as usize * row_stride]);
+    for channel in 0..3 {
+        // An 8 bit channel is a 5 bit one with 3 fractional bits.
+        let a = (start[channel] as i32) << 8;
+        let b = (end[channel] as i32) << 8;
+        // Rounded towards zero, so the channel stays between the ends and
+        // lane arithmetic doesn't wrap; the remainder is split between the
+        // ends. The error is then below span / 2, under a sixth of a level.
+        let step = (b - a) / span;
+        let a = a + (b - a) % span / 2;
+        let value = |i: i32| a + i * step;
+        state[4 + channel] = lanes(value(first), value(first + 1));
+        state[7 + channel] = lanes(2 * step, 2 * step);
+    }
+    let threshold = |row: u32, x: u32| match dither {
+        // Centered in each sixteenth, so that the thresholds average to
+        // half a level, as for rounding.
+        true => BAYER_4X4[row as usize][x as usize % 4] as i32 * (LEVEL / 16) + LEVEL / 32,
+        false => LEVEL / 2,
+    };
+    let aligned = x + x % 2;
+    for row in 0..DITHER_ROWS {
+        for word in 0..2 {
+            let x = aligned + 2 * word;
+            state[10 + 2 * row as usize + word as usize] =
+                lanes(threshold(row, x), threshold(row, x + 1));
+        }
+    }
+    state
+}
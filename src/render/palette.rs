@@ -4,10 +4,29 @@ use super::{rgb, xrgb};
 #[derive(Clone, Copy)]
 pub struct Palette1bpp([u32; 4]);
 
+/// A 4 color palette, as a table of pixel pairs.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Palette2bpp([u32; 16]);
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Palette4bppFast([u32; 256]);
 
+/// A 256 color palette, with one 16 bit entry per color.
+///
+/// Entries are stored in pairs so the table is word aligned.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Palette8bpp([u32; 128]);
+
+/// A 16 color palette for sprites, with one 16 bit entry per color.
+///
+/// Entry 0 is transparent, so its color is ignored.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SpritePalette([u32; 8]);
+
 impl Palette1bpp {
     // Arguments are 16bpp; use `rgb`
     pub const fn new(bg: u32, fg: u32) -> Self {
@@ -23,11 +42,68 @@ impl Palette1bpp {
     pub const fn new_rgb(bg: u32, fg: u32) -> Self {
         Self::new(xrgb(bg), xrgb(fg))
     }
+
+    pub(crate) fn as_words(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Change one color. The argument is 0xRRGGBB.
+    pub fn set_color(&mut self, index: usize, color: u32) {
+        set_pair_color(&mut self.0, 2, index, xrgb(color));
+    }
+
+    /// Get one color, as a 16bpp value.
+    pub fn color(&self, index: usize) -> u32 {
+        pair_color(&self.0, 2, index)
+    }
+}
+
+/// Set color `index` in a table of pixel pairs over `n` colors.
+///
+/// Only the entries containing the color are touched: `2n - 1` words.
+fn set_pair_color(table: &mut [u32], n: usize, index: usize, color: u32) {
+    for j in 0..n {
+        let lo = &mut table[index + n * j];
+        *lo = (*lo & 0xffff_0000) | color;
+        let hi = &mut table[index * n + j];
+        *hi = (*hi & 0xffff) | (color << 16);
+    }
+}
+
+/// Get color `index` from a table of pixel pairs over `n` colors.
+fn pair_color(table: &[u32], n: usize, index: usize) -> u32 {
+    table[index * (n + 1)] & 0xffff
 }
 
 #[link_section = ".scratch_x"]
 pub static BW_PALETTE_1BPP: Palette1bpp = Palette1bpp::new(rgb(0, 0, 0), rgb(255, 255, 255));
 
+impl Palette2bpp {
+    pub const fn new(colors: &[u32; 4]) -> Self {
+        let mut a = [0; 16];
+        let mut i = 0;
+        while i < 16 {
+            a[i] = xrgb(colors[i % 4]) | (xrgb(colors[i / 4]) << 16);
+            i += 1;
+        }
+        Self(a)
+    }
+
+    pub(crate) fn as_words(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Change one color. The argument is 0xRRGGBB.
+    pub fn set_color(&mut self, index: usize, color: u32) {
+        set_pair_color(&mut self.0, 4, index, xrgb(color));
+    }
+
+    /// Get one color, as a 16bpp value.
+    pub fn color(&self, index: usize) -> u32 {
+        pair_color(&self.0, 4, index)
+    }
+}
+
 impl Palette4bppFast {
     pub const fn new(colors: &[u32; 16]) -> Self {
         let mut a = [0; 256];
@@ -38,4 +114,193 @@ impl Palette4bppFast {
         }
         Self(a)
     }
+
+    pub(crate) fn as_words(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Change one color. The argument is 0xRRGGBB.
+    pub fn set_color(&mut self, index: usize, color: u32) {
+        set_pair_color(&mut self.0, 16, index, xrgb(color));
+    }
+
+    /// Get one color, as a 16bpp value.
+    pub fn color(&self, index: usize) -> u32 {
+        pair_color(&self.0, 16, index)
+    }
+}
+
+impl Palette8bpp {
+    // Arguments are 0xRRGGBB
+    pub const fn new(colors: &[u32; 256]) -> Self {
+        let mut a = [0; 128];
+        let mut i = 0;
+        while i < 128 {
+            a[i] = xrgb(colors[i * 2]) | (xrgb(colors[i * 2 + 1]) << 16);
+            i += 1;
+        }
+        Self(a)
+    }
+
+    pub(crate) fn as_words(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Change one color. The argument is 0xRRGGBB.
+    pub fn set_color(&mut self, index: usize, color: u32) {
+        let shift = (index % 2) * 16;
+        let word = &mut self.0[index / 2];
+        *word = (*word & !(0xffff << shift)) | (xrgb(color) << shift);
+    }
+
+    /// Get one color, as a 16bpp value.
+    pub fn color(&self, index: usize) -> u32 {
+        (self.0[index / 2] >> ((index % 2) * 16)) & 0xffff
+    }
+}
+
+impl SpritePalette {
+    // Arguments are 0xRRGGBB
+    pub const fn new(colors: &[u32; 16]) -> Self {
+        let mut a = [0; 8];
+        let mut i = 0;
+        while i < 8 {
+            a[i] = xrgb(colors[i * 2]) | (xrgb(colors[i * 2 + 1]) << 16);
+            i += 1;
+        }
+        Self(a)
+    }
+
+    pub(crate) fn as_words(&self) -> &[u32; 8] {
+        &self.0
+    }
+}
+
+/// A range of palette colors rotated by one step every `period` frames.
+///
+/// Colors move towards higher indices, the last wrapping to `first`.
+#[derive(Clone, Copy)]
+pub struct ColorCycle {
+    pub first: u8,
+    pub len: u8,
+    pub period: u16,
+}
+
+/// A 4bpp palette animated by color cycling.
+///
+/// Call [`AnimatedPalette4bpp::next_frame`] once per frame, then copy the
+/// palette into the display list with [`ScanlistBuilder::pal_4bpp_owned`].
+/// The palette then changes exactly when the display list is swapped, so
+/// no frame shows a half-updated palette. Individual colors can also be
+/// changed with [`AnimatedPalette4bpp::set_color`].
+///
+/// [`ScanlistBuilder::pal_4bpp_owned`]: crate::scanlist::ScanlistBuilder::pal_4bpp_owned
+pub struct AnimatedPalette4bpp<const N: usize> {
+    palette: Palette4bppFast,
+    cycles: [ColorCycle; N],
+    frame: u32,
+}
+
+impl<const N: usize> AnimatedPalette4bpp<N> {
+    /// Panics if a cycle runs past the 16 colors of the palette.
+    pub const fn new(palette: Palette4bppFast, cycles: [ColorCycle; N]) -> Self {
+        let mut i = 0;
+        while i < N {
+            assert!(
+                cycles[i].first as usize + cycles[i].len as usize <= 16,
+                "color cycle runs past the end of the palette"
+            );
+            i += 1;
+        }
+        AnimatedPalette4bpp {
+            palette,
+            cycles,
+            frame: 0,
+        }
+    }
+
+    pub fn palette(&self) -> &Palette4bppFast {
+        &self.palette
+    }
+
+    /// Change one color. The argument is 0xRRGGBB.
+    pub fn set_color(&mut self, index: usize, color: u32) {
+        self.palette.set_color(index, color);
+    }
+
+    /// Advance the animation by a frame, rotating any cycles that are due.
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+        for cycle in &self.cycles {
+            if cycle.len < 2 || !self.frame.is_multiple_of(cycle.period.max(1) as u32) {
+                continue;
+            }
+            let first = cycle.first as usize;
+            let last = first + cycle.len as usize - 1;
+            let wrapped = self.palette.color(last);
+            for index in (first..last).rev() {
+                let color = self.palette.color(index);
+                set_pair_color(&mut self.palette.0, 16, index + 1, color);
+            }
+            set_pair_color(&mut self.palette.0, 16, first, wrapped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AnimatedPalette4bpp, ColorCycle, Palette2bpp, Palette4bppFast, Palette8bpp};
+    use crate::render::xrgb;
+
+    const COLORS: [u32; 16] = [
+        0x000000, 0x111111, 0x222222, 0x333333, 0x444444, 0x555555, 0x666666, 0x777777, 0x888888,
+        0x999999, 0xaaaaaa, 0xbbbbbb, 0xcccccc, 0xdddddd, 0xeeeeee, 0xffffff,
+    ];
+
+    #[test]
+    fn set_color_matches_new() {
+        let mut colors = COLORS;
+        colors[5] = 0xff0000;
+        let mut palette = Palette4bppFast::new(&COLORS);
+        palette.set_color(5, 0xff0000);
+        assert!(palette.as_words() == Palette4bppFast::new(&colors).as_words());
+        assert_eq!(palette.color(5), xrgb(0xff0000));
+
+        let mut palette = Palette2bpp::new(&[0, 0x0000ff, 0x00ff00, 0xffffff]);
+        palette.set_color(0, 0xff0000);
+        let expected = Palette2bpp::new(&[0xff0000, 0x0000ff, 0x00ff00, 0xffffff]);
+        assert!(palette.as_words() == expected.as_words());
+
+        let mut palette = Palette8bpp::new(&[0; 256]);
+        palette.set_color(201, 0x00ff00);
+        assert_eq!(palette.color(201), xrgb(0x00ff00));
+        assert_eq!(palette.color(200), 0);
+    }
+
+    #[test]
+    fn cycle() {
+        let cycle = ColorCycle {
+            first: 2,
+            len: 3,
+            period: 2,
+        };
+        let mut animated = AnimatedPalette4bpp::new(Palette4bppFast::new(&COLORS), [cycle]);
+        animated.next_frame();
+        assert!(animated.palette().as_words() == Palette4bppFast::new(&COLORS).as_words());
+        animated.next_frame();
+        let mut colors = COLORS;
+        colors[2..5].copy_from_slice(&[0x444444, 0x222222, 0x333333]);
+        assert!(animated.palette().as_words() == Palette4bppFast::new(&colors).as_words());
+    }
+
+    #[test]
+    #[should_panic(expected = "color cycle runs past the end of the palette")]
+    fn color_cycle_bounds() {
+        let cycle = ColorCycle {
+            first: 14,
+            len: 3,
+            period: 1,
+        };
+        AnimatedPalette4bpp::new(Palette4bppFast::new(&COLORS), [cycle]);
+    }
 }
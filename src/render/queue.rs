@@ -12,13 +12,18 @@ pub struct Queue<const SIZE: usize> {
     buf: [AtomicU32; SIZE],
 }
 
+impl<const SIZE: usize> Default for Queue<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const SIZE: usize> Queue<SIZE> {
     pub const fn new() -> Self {
-        const ZERO: AtomicU32 = AtomicU32::new(0);
         Queue {
-            rd_ix: ZERO,
-            wr_ix: ZERO,
-            buf: [ZERO; SIZE],
+            rd_ix: AtomicU32::new(0),
+            wr_ix: AtomicU32::new(0),
+            buf: [const { AtomicU32::new(0) }; SIZE],
         }
     }
 
@@ -67,9 +72,25 @@ impl<const SIZE: usize> Queue<SIZE> {
         item
     }
 
+    /// Take an item if one is available.
+    pub fn try_take(&self) -> Option<u32> {
+        let rd_ix = self.rd_ix.load(Ordering::Relaxed);
+        if rd_ix == self.wr_ix.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = self.buf[rd_ix as usize].load(Ordering::Relaxed);
+        let next = (rd_ix + 1) % SIZE as u32;
+        self.rd_ix.store(next, Ordering::Release);
+        Some(item)
+    }
+
     pub fn len(&self) -> usize {
         let rd_ix = self.rd_ix.load(Ordering::Acquire);
         let wr_ix = self.wr_ix.load(Ordering::Relaxed);
         (wr_ix as usize + SIZE - rd_ix as usize) % SIZE
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
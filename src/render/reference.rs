@@ -0,0 +1,681 @@
+//! Reference interpreter for display lists.
+//!
+//! This is a pure Rust implementation of every renderlist and scanlist
+//! op, following `render.asm` and `scan.asm`, so a display list can be
+//! run into an image for golden image tests on the host.
+
+use core::{mem::offset_of, sync::atomic::Ordering};
+
+use alloc::{vec, vec::Vec};
+
+use crate::scanlist::{rgb555_to_rgb888, ScanOp};
+
+use super::{
+    renderlist::RenderOp,
+    sprite::{SpriteLayer, SpriteSlot},
+    DisplayList, LINE_BUF_SIZE,
+};
+
+/// A frame produced by the reference interpreter.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels as 0xRRGGBB, row by row, assuming 16bpp RGB output.
+    pub pixels: Vec<u32>,
+}
+
+impl Frame {
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Encode as an RGB PNG file.
+    ///
+    /// The image data is stored uncompressed, which keeps this small and
+    /// is fine for test output.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(((3 * self.width + 1) * self.height) as usize);
+        for row in self.pixels.chunks(self.width as usize) {
+            raw.push(0); // filter: none
+            for &p in row {
+                raw.extend_from_slice(&p.to_be_bytes()[1..]);
+            }
+        }
+        // zlib stream of stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(blocks.peek().is_none() as u8);
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB
+        let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        for (kind, data) in [(b"IHDR", &ihdr), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let crc = crc32(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        }
+        png
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Run a display list for one frame, as scanout would.
+///
+/// Cursors, copper writes and any fade set on the scanlist behave as on
+/// hardware.
+pub fn render_frame(display_list: &mut DisplayList) -> Frame {
+    let DisplayList { render, scan } = display_list;
+    let (width, height) = (scan.width(), scan.height());
+    let render = render.get();
+    let mut line_buf = [0; LINE_BUF_SIZE];
+    let mut line = vec![0; width as usize];
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    let (mut render_ix, mut render_y) = (0, 0);
+    let (mut scan_ix, mut scan_next, mut stripe_remaining) = (0, 0, 0);
+    scan.rewind();
+    for y in 0..height {
+        if stripe_remaining == 0 {
+            stripe_remaining = scan.get()[scan_next];
+            scan_ix = scan_next + 1;
+        }
+        scan.copper(y);
+        render_line(&render[render_ix + 2..], render_y, &mut line_buf);
+        render_y += 1;
+        if render_y as usize == render[render_ix] {
+            render_ix = render[render_ix + 1];
+            render_y = 0;
+        }
+        scan_next = scan_ix + scan_line(&scan.get()[scan_ix..], &line_buf, &mut line);
+        stripe_remaining -= 1;
+        pixels.extend(line.iter().map(|&p| rgb555_to_rgb888(p as u32)));
+    }
+    Frame {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Read the word at `addr`, which ops hold as a pointer.
+fn load(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read() }
+}
+
+// Shifts by register use the bottom byte, and shift everything out from 32.
+fn lsl(x: u32, shift: u32) -> u32 {
+    x.checked_shl(shift & 0xff).unwrap_or(0)
+}
+
+fn lsr(x: u32, shift: u32) -> u32 {
+    x.checked_shr(shift & 0xff).unwrap_or(0)
+}
+
+/// Add halfword lanes, as `sadd16` (or `uadd16`) without the flags.
+fn add16(a: u32, b: u32) -> u32 {
+    let lo = (a as u16).wrapping_add(b as u16);
+    let hi = ((a >> 16) as u16).wrapping_add((b >> 16) as u16);
+    lo as u32 | (hi as u32) << 16
+}
+
+/// Add halfword lanes with unsigned saturation, as `uqadd16`.
+fn uqadd16(a: u32, b: u32) -> u32 {
+    let lo = (a as u16).saturating_add(b as u16);
+    let hi = ((a >> 16) as u16).saturating_add((b >> 16) as u16);
+    lo as u32 | (hi as u32) << 16
+}
+
+/// Run the renderlist ops of a stripe for line `y` of the stripe.
+fn render_line(ops: &[usize], y: u32, out: &mut [u32]) {
+    let mut out = out.iter_mut();
+    let mut emit = |word| *out.next().expect("renderlist overruns the line buffer") = word;
+    let mut pending = 0;
+    let mut ix = 0;
+    loop {
+        let Some(op) = RenderOp::from_word(ops[ix]) else {
+            panic!("unknown renderlist op {:#x}", ops[ix]);
+        };
+        if op == RenderOp::Stop {
+            return;
+        }
+        let arg = |i: usize| ops.get(ix + i).copied().unwrap_or(0);
+        let (input, stride, shifts) = (arg(1), arg(2), arg(3) as u32);
+        let row = input.wrapping_add(stride.wrapping_mul(y as usize));
+        ix += op.words();
+        match op {
+            RenderOp::Stop => unreachable!(),
+            RenderOp::BlitSimple | RenderOp::BlitOut => {
+                let word = load(row);
+                pending |= lsr(lsl(lsr(word, shifts), shifts >> 8), shifts >> 16);
+                if op == RenderOp::BlitOut {
+                    emit(pending);
+                    pending = 0;
+                }
+            }
+            RenderOp::BlitStraddle | RenderOp::BlitStraddleOut => {
+                let word = load(row);
+                emit(pending | lsl(lsr(word, shifts), shifts >> 8));
+                pending = lsr(lsl(word, shifts >> 16), shifts >> 24);
+                if op == RenderOp::BlitStraddleOut {
+                    emit(pending);
+                    pending = 0;
+                }
+            }
+            RenderOp::Blit64Aligned => {
+                emit(load(row));
+                emit(load(row + 4));
+            }
+            RenderOp::Blit64Straddle => {
+                let (a, b) = (load(row), load(row + 4));
+                emit(lsl(a, shifts) | pending);
+                emit(lsl(b, shifts) | lsr(a, shifts >> 8));
+                pending = lsr(b, shifts >> 8);
+            }
+            RenderOp::BlitWords => {
+                for i in 0..shifts as usize {
+                    emit(load(row + i * 4));
+                }
+                if shifts >= 2 {
+                    pending = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Run the scanlist ops of a stripe for one line.
+///
+/// Returns the number of words of ops, including the stop.
+fn scan_line(ops: &[usize], input: &[u32], out: &mut [u16]) -> usize {
+    let mut x = 0;
+    let mut input_ix = 0;
+    let mut ix = 0;
+    loop {
+        let Some(op) = ScanOp::from_word(ops[ix]) else {
+            panic!("unknown scanlist op {:#x}", ops[ix]);
+        };
+        if op == ScanOp::Stop {
+            return ix + 1;
+        }
+        let (count, arg) = (ops[ix + 1], ops[ix + 2]);
+        ix += 3;
+        if op == ScanOp::Sprites {
+            // {y, layer, y0}
+            let state = arg as *mut usize;
+            let (y, layer) = unsafe { (state.read(), state.add(1).read()) };
+            unsafe { state.write(y + 1) };
+            overlay_sprites(layer as *const u32, y as u32, &mut out[x - count..x]);
+            continue;
+        }
+        let start = x;
+        let pixels = &mut out[x..x + count];
+        x += count;
+        let words = &input[input_ix..];
+        let mut line_buf = |bits: usize, color: &dyn Fn(u32) -> u32| {
+            unpack(pixels, bits as u32, |i| words[i], color);
+            (count * bits).div_ceil(32)
+        };
+        let pair = |c| load(arg + c as usize * 4);
+        match op {
+            ScanOp::Solid => pixels.fill(arg as u16),
+            ScanOp::Pal1bpp => input_ix += line_buf(1, &pair),
+            ScanOp::Pal2bpp => input_ix += line_buf(2, &pair),
+            ScanOp::Pal4bpp => input_ix += line_buf(4, &pair),
+            ScanOp::Pal8bpp => {
+                let half = |c| unsafe { (arg as *const u16).add(c as usize).read() as u32 };
+                input_ix += line_buf(8, &half);
+            }
+            ScanOp::Rgb555 => input_ix += line_buf(16, &|c| c),
+            ScanOp::Rgb555Rows => {
+                // {row, stride, base}
+                let cursor = arg as *mut usize;
+                let row = unsafe { cursor.read() };
+                unsafe { cursor.write(row + cursor.add(1).read()) };
+                unpack(pixels, 16, |i| load(row + i * 4), |c| c);
+            }
+            ScanOp::Gradient => {
+                // {offset, stride, reset, end, r, g, b, dr, dg, db, thresholds},
+                // with offsets in bytes of the device's 4 byte words.
+                let state = arg as *mut usize;
+                let read = |i: usize| unsafe { state.add(i).read() };
+                let (offset, stride, end) = (read(0), read(1), read(3));
+                let next = offset + stride;
+                unsafe { state.write(if next == end { 0 } else { next }) };
+                let thresholds = [read(10 + offset / 4), read(11 + offset / 4)].map(|t| t as u32);
+                let mut lanes = [read(4), read(5), read(6)].map(|c| c as u32);
+                let steps = [read(7), read(8), read(9)].map(|c| c as u32);
+                let mut word = |threshold: u32| {
+                    let mut out = 0;
+                    for (lane, shift) in lanes.iter().zip([1, 6, 11]) {
+                        out |= (uqadd16(*lane, threshold) & 0xf800_f800) >> shift;
+                    }
+                    for (lane, step) in lanes.iter_mut().zip(steps) {
+                        *lane = add16(*lane, step);
+                    }
+                    out
+                };
+                let mut pixels = pixels;
+                if start % 2 == 1 {
+                    // The first word is for pixels -1 and 0.
+                    pixels[0] = (word(thresholds[1]) >> 16) as u16;
+                    pixels = &mut pixels[1..];
+                }
+                for (i, pair) in pixels.chunks_mut(2).enumerate() {
+                    let out = word(thresholds[i % 2]);
+                    pair[0] = out as u16;
+                    if let Some(pixel) = pair.get_mut(1) {
+                        *pixel = (out >> 16) as u16;
+                    }
+                }
+            }
+            ScanOp::Sprites | ScanOp::Stop => unreachable!(),
+        }
+    }
+}
+
+/// Fill pixels from fields of `bits` each, low bits first, mapped by `color`.
+fn unpack(pixels: &mut [u16], bits: u32, word: impl Fn(usize) -> u32, color: impl Fn(u32) -> u32) {
+    let mask = (1 << bits) - 1;
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let bit = i as u32 * bits;
+        *pixel = color((word(bit as usize / 32) >> (bit % 32)) & mask) as u16;
+    }
+}
+
+/// Draw the sprites of a layer over display line `y`.
+fn overlay_sprites(layer: *const u32, y: u32, line: &mut [u16]) {
+    let n = unsafe { layer.read() } as usize;
+    let slots_offset = offset_of!(SpriteLayer<1>, slots);
+    let slots = layer.wrapping_byte_add(slots_offset) as *const SpriteSlot;
+    for ix in 0..n {
+        let slot = unsafe { &*slots.add(ix) };
+        let Some(sprite) = (unsafe { slot.sprite.load(Ordering::Acquire).as_ref() }) else {
+            continue;
+        };
+        let pos = slot.pos.load(Ordering::Relaxed);
+        let (x0, y0) = (pos as i16 as i32, (pos >> 16) as i16 as i32);
+        let row = y as i32 - y0;
+        if row < 0 || row >= sprite.height() as i32 {
+            continue;
+        }
+        let image = sprite
+            .image
+            .wrapping_byte_add((row as u32 * sprite.stride) as usize);
+        let palette = slot.palette.load(Ordering::Relaxed) as *const u16;
+        for col in 0..sprite.width() as i32 {
+            let Some(pixel) = usize::try_from(x0 + col).ok().and_then(|x| line.get_mut(x)) else {
+                continue;
+            };
+            let word = unsafe { image.add(col as usize / 8).read() };
+            let c = (word >> (col % 8 * 4)) & 15;
+            if c != 0 {
+                *pixel = unsafe { palette.add(c as usize).read() };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{overlay_sprites, render_frame};
+    use crate::{
+        render::{
+            font::{FONT_BITS, FONT_HEIGHT, FONT_STRIDE, FONT_X_OFFSETS, FONT_X_WIDTHS},
+            renderlist::RenderlistBuilder,
+            xrgb, DisplayList, Fade, Palette4bppFast, Palette8bpp, Sprite, SpriteLayer,
+            SpritePalette, BW_PALETTE_1BPP,
+        },
+        scanlist::{rgb555_to_rgb888, ScanlistBuilder},
+    };
+
+    fn build(rb: RenderlistBuilder, sb: ScanlistBuilder) -> DisplayList {
+        let Ok(scan) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        DisplayList {
+            render: rb.build(),
+            scan,
+        }
+    }
+
+    /// Expected output for a 0xRRGGBB color, after the trip through RGB555.
+    fn color(rrggbb: u32) -> u32 {
+        rgb555_to_rgb888(xrgb(rrggbb))
+    }
+
+    #[test]
+    fn text() {
+        let text = "Hello, world!";
+        let mut rb = RenderlistBuilder::new(640);
+        rb.begin_stripe(FONT_HEIGHT);
+        let width = rb.text(text);
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(640, FONT_HEIGHT);
+        sb.begin_stripe(FONT_HEIGHT);
+        sb.pal_1bpp(640, &BW_PALETTE_1BPP);
+        sb.end_stripe().unwrap();
+        let frame = render_frame(&mut build(rb, sb));
+
+        let mut x = 0;
+        for c in text.bytes() {
+            let glyph = (c - b' ') as usize;
+            let offset = FONT_X_OFFSETS[glyph] as u32;
+            for gx in 0..FONT_X_WIDTHS[glyph] as u32 {
+                for y in 0..FONT_HEIGHT {
+                    let bit = offset + gx;
+                    let word = FONT_BITS[(y * FONT_STRIDE / 4 + bit / 32) as usize];
+                    let expected = if (word >> (bit % 32)) & 1 != 0 {
+                        color(0xffffff)
+                    } else {
+                        color(0)
+                    };
+                    assert_eq!(frame.pixel(x, y), expected);
+                }
+                x += 1;
+            }
+        }
+        assert_eq!(x, width);
+        assert!(frame.pixels[width as usize..640]
+            .iter()
+            .all(|&p| p == color(0)));
+    }
+
+    #[test]
+    fn tiles() {
+        static TILE: [u32; 16] = [
+            0x76543210, 0xfedcba98, 0x01234567, 0x89abcdef, 0x11111111, 0x22222222, 0x12345678,
+            0x9abcdef0, 0x0f0f0f0f, 0xf0f0f0f0, 0x00ff00ff, 0xff00ff00, 0x13579bdf, 0x02468ace,
+            0xfdb97531, 0xeca86420,
+        ];
+        const COLORS: [u32; 16] = [
+            0x000000, 0x800000, 0x008000, 0x808000, 0x000080, 0x800080, 0x008080, 0xc0c0c0,
+            0x808080, 0xff0000, 0x00ff00, 0xffff00, 0x0000ff, 0xff00ff, 0x00ffff, 0xffffff,
+        ];
+        static PALETTE: Palette4bppFast = Palette4bppFast::new(&COLORS);
+        // Left clipped tiles, and a whole tile off the word grid, ending on
+        // a word boundary.
+        let slices = [(13, 16), (0, 16), (5, 16), (14, 16)];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(8);
+        for (start, end) in slices {
+            rb.tile64(&TILE, start, end);
+        }
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(32, 8);
+        sb.begin_stripe(8);
+        sb.pal_4bpp(32, &PALETTE);
+        sb.end_stripe().unwrap();
+        let frame = render_frame(&mut build(rb, sb));
+
+        for y in 0..8 {
+            let mut x = 0;
+            for (start, end) in slices {
+                for col in start..end {
+                    let word = TILE[(y * 2 + col / 8) as usize];
+                    let c = (word >> (col % 8 * 4)) & 15;
+                    assert_eq!(frame.pixel(x, y), color(COLORS[c as usize]));
+                    x += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn palettes_rows_and_copper() {
+        static PIXELS: [u32; 8] = [
+            0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c, 0x13121110, 0x17161514, 0x1b1a1918,
+            0x1f1e1d1c,
+        ];
+        static IMAGE: [u32; 16] = {
+            let mut image = [0; 16];
+            let mut i = 0;
+            while i < 16 {
+                image[i] = (i as u32 * 2) | ((i as u32 * 2 + 1) << 16);
+                i += 1;
+            }
+            image
+        };
+        let colors = core::array::from_fn(|i| i as u32 * 0x080808);
+        let mut rb = RenderlistBuilder::new(16);
+        rb.begin_stripe(4);
+        rb.blit_8bpp(&PIXELS, 8, 8);
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(16, 4);
+        sb.begin_stripe(4);
+        let op = sb.pal_8bpp_owned(8, &Palette8bpp::new(&colors));
+        sb.rgb555_rows(8, &IMAGE, 16);
+        sb.end_stripe().unwrap();
+        sb.copper_color(2, op, 9, xrgb(0xff0000)).unwrap();
+        let mut display_list = build(rb, sb);
+
+        // Twice, to check that the frame is rewound.
+        for _ in 0..2 {
+            let frame = render_frame(&mut display_list);
+            for y in 0..4 {
+                for x in 0..8 {
+                    let c = y * 8 + x;
+                    let expected = if c == 9 && y >= 2 {
+                        color(0xff0000)
+                    } else {
+                        color(colors[c as usize])
+                    };
+                    assert_eq!(frame.pixel(x, y), expected);
+                    let rgb555 = y * 8 + x;
+                    assert_eq!(frame.pixel(8 + x, y), rgb555_to_rgb888(rgb555));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gradient() {
+        // 5 bit levels of the channels of an output pixel.
+        fn levels(pixel: u32) -> [u32; 3] {
+            [16, 8, 0].map(|shift| (pixel >> (shift + 3)) & 0x1f)
+        }
+
+        // An odd start and count, and a stripe not starting on a dither row.
+        let (x0, count) = (3, 301);
+        for (start, end) in [
+            (0x000000, 0xffffff),
+            (0xff8000, 0x0040ff),
+            (0x102030, 0xf0e0d0),
+        ] {
+            for dither in [false, true] {
+                let mut rb = RenderlistBuilder::new(320);
+                rb.begin_stripe(10);
+                rb.end_stripe();
+                let mut sb = ScanlistBuilder::new(320, 10);
+                sb.begin_stripe(1);
+                sb.solid(320, 0);
+                sb.end_stripe().unwrap();
+                sb.begin_stripe(9);
+                sb.solid(x0, 0);
+                sb.gradient(count, start, end, dither);
+                sb.solid(320 - x0 - count, 0);
+                sb.end_stripe().unwrap();
+                let mut display_list = build(rb, sb);
+
+                // Twice, to check that the frame is rewound.
+                for _ in 0..2 {
+                    let frame = render_frame(&mut display_list);
+                    for y in 1..10 {
+                        assert_eq!(frame.pixel(x0 - 1, y), 0);
+                        assert_eq!(frame.pixel(x0 + count, y), 0);
+                        for i in 0..count {
+                            let out = levels(frame.pixel(x0 + i, y));
+                            let t = i as f64 / (count - 1) as f64;
+                            for (c, shift) in [16, 8, 0].into_iter().enumerate() {
+                                let a = ((start >> shift) & 0xff) as f64;
+                                let b = ((end >> shift) & 0xff) as f64;
+                                let ideal = ((a + (b - a) * t) / 8.0).min(31.0);
+                                // Rounding or dithering, and the error of
+                                // the fixed point steps.
+                                let tolerance = if dither { 1.2 } else { 0.7 };
+                                let error = (out[c] as f64 - ideal).abs();
+                                assert!(error < tolerance, "pixel {i} of {start:06x}..{end:06x}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Over a cell of the dither pattern, a solid color averages out to
+        // exactly its 8 bit value.
+        for value in [0x00, 0x01, 0x44, 0x84, 0x87, 0xf7] {
+            let color = value * 0x010101;
+            let mut rb = RenderlistBuilder::new(8);
+            rb.begin_stripe(4);
+            rb.end_stripe();
+            let mut sb = ScanlistBuilder::new(8, 4);
+            sb.begin_stripe(4);
+            sb.solid(1, 0);
+            sb.gradient(7, color, color, true);
+            sb.end_stripe().unwrap();
+            let frame = render_frame(&mut build(rb, sb));
+            let mut sum = [0; 3];
+            for y in 0..4 {
+                for x in 2..6 {
+                    for (sum, level) in sum.iter_mut().zip(levels(frame.pixel(x, y))) {
+                        *sum += level;
+                    }
+                }
+            }
+            // 16 pixels of levels of 8 steps of the 8 bit value.
+            assert_eq!(sum, [2 * value; 3]);
+        }
+    }
+
+    #[test]
+    fn sprites() {
+        // A 3x2 sprite: a ring of color 1 around a transparent center.
+        static IMAGE: [u32; 2] = [0x111, 0x101];
+        static PALETTE: SpritePalette = SpritePalette::new(&[0xffffff; 16]);
+        static SPRITE: Sprite = Sprite::new(&IMAGE, 3, 2, &PALETTE);
+        static LAYER: SpriteLayer<2> = SpriteLayer::new();
+        LAYER.show(0, &SPRITE, -1, 1);
+        LAYER.show(1, &SPRITE, 14, 2);
+        let mut rb = RenderlistBuilder::new(16);
+        rb.begin_stripe(4);
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(16, 4);
+        sb.begin_stripe(4);
+        sb.solid(16, xrgb(0x0000ff));
+        sb.sprites(&LAYER);
+        sb.end_stripe().unwrap();
+        let frame = render_frame(&mut build(rb, sb));
+
+        let (bg, fg) = (color(0x0000ff), color(0xffffff));
+        let expected: [(u32, u32, u32); 8] = [
+            (0, 0, bg),
+            (0, 1, fg),
+            (1, 1, fg),
+            (0, 2, bg),
+            (1, 2, fg),
+            (14, 2, fg),
+            (15, 2, fg),
+            (15, 3, bg),
+        ];
+        for (x, y, expected) in expected {
+            assert_eq!(frame.pixel(x, y), expected);
+        }
+    }
+
+    #[test]
+    fn sprite_moved_mid_frame() {
+        // A 1x2 sprite.
+        static IMAGE: [u32; 2] = [1, 1];
+        static PALETTE: SpritePalette = SpritePalette::new(&[0xffffff; 16]);
+        static SPRITE: Sprite = Sprite::new(&IMAGE, 1, 2, &PALETTE);
+        static LAYER: SpriteLayer<1> = SpriteLayer::new();
+        LAYER.show(0, &SPRITE, 0, 0);
+        let mut rb = RenderlistBuilder::new(8);
+        rb.begin_stripe(2);
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(8, 2);
+        sb.begin_stripe(2);
+        sb.solid(8, 0);
+        sb.sprites(&LAYER);
+        sb.end_stripe().unwrap();
+        let mut display_list = build(rb, sb);
+
+        // Scan out a frame by hand, moving the sprite after the first line.
+        let layer = &LAYER as *const _ as *const u32;
+        let fg = xrgb(0xffffff) as u16;
+        display_list.scan.rewind();
+        for y in 0..2 {
+            let mut line = [0; 8];
+            overlay_sprites(layer, y, &mut line);
+            assert_eq!((line[0], line[4]), (fg, 0), "line {y}");
+            LAYER.set_position(0, 4, 0);
+        }
+        let frame = render_frame(&mut display_list);
+        for y in 0..2 {
+            assert_eq!((frame.pixel(0, y), frame.pixel(4, y)), (0, color(0xffffff)));
+        }
+    }
+
+    #[test]
+    fn fade_to_black() {
+        static IMAGE: [u32; 1] = [0x1111];
+        static PALETTE: SpritePalette = SpritePalette::new(&[0xffffff; 16]);
+        static SPRITE: Sprite = Sprite::new(&IMAGE, 4, 1, &PALETTE);
+        static LAYER: SpriteLayer<1> = SpriteLayer::new();
+        LAYER.show(0, &SPRITE, 12, 0);
+        let mut rb = RenderlistBuilder::new(16);
+        rb.begin_stripe(2);
+        rb.end_stripe();
+        let mut sb = ScanlistBuilder::new(16, 2);
+        sb.begin_stripe(2);
+        sb.solid(4, xrgb(0xff0000));
+        sb.gradient(12, 0x00ff00, 0xffffff, true);
+        sb.sprites(&LAYER);
+        sb.end_stripe().unwrap();
+        let mut display_list = build(rb, sb);
+
+        display_list.scan.set_fade(Fade::new(0, 0));
+        let frame = render_frame(&mut display_list);
+        assert!(frame.pixels.iter().all(|&pixel| pixel == 0));
+
+        // Back at full brightness, everything is as built.
+        display_list.scan.set_fade(Fade::NONE);
+        let frame = render_frame(&mut display_list);
+        assert_eq!(frame.pixel(0, 0), color(0xff0000));
+        assert_eq!(frame.pixel(4, 1), color(0x00ff00));
+        assert_eq!(frame.pixel(15, 1), color(0xffffff));
+    }
+}
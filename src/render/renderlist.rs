@@ -1,31 +1,115 @@
-use core::cmp;
+use core::{cmp, fmt};
 
 use alloc::vec::Vec;
 
 use super::font::{FONT_BITS, FONT_STRIDE, FONT_X_OFFSETS, FONT_X_WIDTHS};
 
-extern "C" {
-    fn render_stop();
+/// A render op, from `render.asm`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum RenderOp {
+    Stop,
+    BlitSimple,
+    BlitOut,
+    BlitStraddle,
+    BlitStraddleOut,
+    Blit64Aligned,
+    Blit64Straddle,
+    BlitWords,
+}
+
+impl RenderOp {
+    const ALL: [RenderOp; 8] = [
+        RenderOp::Stop,
+        RenderOp::BlitSimple,
+        RenderOp::BlitOut,
+        RenderOp::BlitStraddle,
+        RenderOp::BlitStraddleOut,
+        RenderOp::Blit64Aligned,
+        RenderOp::Blit64Straddle,
+        RenderOp::BlitWords,
+    ];
+
+    /// The word for the op in a renderlist.
+    ///
+    /// As for scanlists, this is the address of the op on the device, and
+    /// elsewhere just tells the ops apart.
+    #[cfg(target_os = "none")]
+    pub(crate) fn word(self) -> usize {
+        extern "C" {
+            fn render_stop();
 
-    fn render_blit_simple();
+            fn render_blit_simple();
 
-    fn render_blit_out();
+            fn render_blit_out();
 
-    fn render_blit_straddle();
+            fn render_blit_straddle();
 
-    fn render_blit_straddle_out();
+            fn render_blit_straddle_out();
 
-    fn render_blit_64_aligned();
+            fn render_blit_64_aligned();
+
+            fn render_blit_64_straddle();
+
+            fn render_blit_words();
+        }
+        let op: unsafe extern "C" fn() = match self {
+            RenderOp::Stop => render_stop,
+            RenderOp::BlitSimple => render_blit_simple,
+            RenderOp::BlitOut => render_blit_out,
+            RenderOp::BlitStraddle => render_blit_straddle,
+            RenderOp::BlitStraddleOut => render_blit_straddle_out,
+            RenderOp::Blit64Aligned => render_blit_64_aligned,
+            RenderOp::Blit64Straddle => render_blit_64_straddle,
+            RenderOp::BlitWords => render_blit_words,
+        };
+        op as usize
+    }
+
+    #[cfg(not(target_os = "none"))]
+    pub(crate) fn word(self) -> usize {
+        self as usize
+    }
+
+    pub(crate) fn from_word(word: usize) -> Option<RenderOp> {
+        RenderOp::ALL.into_iter().find(|op| op.word() == word)
+    }
+
+    /// The number of words the op takes in a renderlist, including itself.
+    pub(crate) fn words(self) -> usize {
+        match self {
+            RenderOp::Stop => 1,
+            RenderOp::Blit64Aligned => 3,
+            _ => 4,
+        }
+    }
 
-    fn render_blit_64_straddle();
+    fn name(self) -> &'static str {
+        match self {
+            RenderOp::Stop => "stop",
+            RenderOp::BlitSimple => "blit_simple",
+            RenderOp::BlitOut => "blit_out",
+            RenderOp::BlitStraddle => "blit_straddle",
+            RenderOp::BlitStraddleOut => "blit_straddle_out",
+            RenderOp::Blit64Aligned => "blit_64_aligned",
+            RenderOp::Blit64Straddle => "blit_64_straddle",
+            RenderOp::BlitWords => "blit_words",
+        }
+    }
 }
 
-pub struct Renderlist(Vec<u32>, u32);
+/// A display list for rendering into the line buffer.
+///
+/// As with [`Scanlist`](crate::scanlist::Scanlist), words are pointer
+/// sized.
+pub struct Renderlist(Vec<usize>, u32);
 
 pub struct RenderlistBuilder {
-    v: Vec<u32>,
+    v: Vec<usize>,
     width: u32,
     x: u32,
+    /// Position in the line buffer, in bits. Ops of different depths
+    /// advance `x` by their own pixels, so alignment is checked on this.
+    bits: u32,
     stripe_start: usize,
 }
 
@@ -35,6 +119,7 @@ impl RenderlistBuilder {
             v: alloc::vec![],
             width,
             x: 0,
+            bits: 0,
             stripe_start: 0,
         }
     }
@@ -45,30 +130,32 @@ impl RenderlistBuilder {
             v: renderlist.0,
             width: renderlist.1,
             x: 0,
+            bits: 0,
             stripe_start: 0,
         }
     }
 
     pub fn begin_stripe(&mut self, height: u32) {
-        self.v.extend([height, 0]);
+        self.v.extend([height as usize, 0]);
     }
 
     pub fn end_stripe(&mut self) {
-        self.v.push(render_stop as u32);
+        self.v.push(RenderOp::Stop.word());
         let len = self.v.len();
-        self.v[self.stripe_start + 1] = len as u32;
+        self.v[self.stripe_start + 1] = len;
         self.stripe_start = len;
         self.x = 0;
+        self.bits = 0;
     }
 
     fn tile_slice(&mut self, tile: &[u32], stride: u32, start: u32, end: u32) {
         let next = self.x % 8 + 8 - start;
         let op = match next.cmp(&8) {
-            cmp::Ordering::Greater => render_blit_straddle,
-            cmp::Ordering::Equal => render_blit_out,
-            cmp::Ordering::Less => render_blit_simple,
+            cmp::Ordering::Greater => RenderOp::BlitStraddle,
+            cmp::Ordering::Equal => RenderOp::BlitOut,
+            cmp::Ordering::Less => RenderOp::BlitSimple,
         };
-        let tile_ptr = tile.as_ptr() as u32;
+        let tile_ptr = tile.as_ptr() as usize;
         let mut shifts = start * 4;
         if next > 8 {
             shifts |= (self.x % 8) << 10;
@@ -78,8 +165,10 @@ impl RenderlistBuilder {
             shifts |= (8 - (end - start)) << 10;
             shifts |= (8 - next) << 18;
         }
-        self.v.extend([op as u32, tile_ptr, stride, shifts]);
+        self.v
+            .extend([op.word(), tile_ptr, stride as usize, shifts as usize]);
         self.x += end - start;
+        self.bits += (end - start) * 4;
     }
 
     // Note: this is currently set up for 4bpp, but could be adapted
@@ -92,19 +181,23 @@ impl RenderlistBuilder {
         if start == 0 && end == 16 {
             let offset = self.x % 8;
             if offset == 0 {
-                self.v
-                    .extend([render_blit_64_aligned as u32, tile.as_ptr() as u32, stride]);
+                self.v.extend([
+                    RenderOp::Blit64Aligned.word(),
+                    tile.as_ptr() as usize,
+                    stride as usize,
+                ]);
             } else {
                 let off4 = offset * 4;
                 let shift = off4 + ((32 - off4) << 8);
                 self.v.extend([
-                    render_blit_64_straddle as u32,
-                    tile.as_ptr() as u32,
-                    stride,
-                    shift,
+                    RenderOp::Blit64Straddle.word(),
+                    tile.as_ptr() as usize,
+                    stride as usize,
+                    shift as usize,
                 ]);
             }
             self.x += start + end;
+            self.bits += 64;
         } else {
             if start < 8 {
                 self.tile_slice(tile, stride, start, end.min(8));
@@ -127,9 +220,9 @@ impl RenderlistBuilder {
             let offset = FONT_X_OFFSETS[glyph as usize] as u32;
             let next = x % 32 + width;
             let op = match next.cmp(&32) {
-                cmp::Ordering::Greater => render_blit_straddle,
-                cmp::Ordering::Equal => render_blit_out,
-                cmp::Ordering::Less => render_blit_simple,
+                cmp::Ordering::Greater => RenderOp::BlitStraddle,
+                cmp::Ordering::Equal => RenderOp::BlitOut,
+                cmp::Ordering::Less => RenderOp::BlitSimple,
             };
             let mut shifts = offset & 31;
             if next > 32 {
@@ -141,29 +234,90 @@ impl RenderlistBuilder {
                 shifts |= (32 - next) << 16;
             }
             let font_ptr = unsafe { FONT_BITS.as_ptr().add(offset as usize / 32) };
-            self.v
-                .extend([op as u32, font_ptr as u32, FONT_STRIDE, shifts]);
+            self.v.extend([
+                op.word(),
+                font_ptr as usize,
+                FONT_STRIDE as usize,
+                shifts as usize,
+            ]);
             x += width;
         }
         if !text.is_empty() {
             let op_ix = self.v.len() - 4;
-            if self.v[op_ix] == render_blit_simple as u32 {
-                self.v[op_ix] = render_blit_out as u32;
-            } else if self.v[op_ix] == render_blit_straddle as u32 {
-                self.v[op_ix] = render_blit_straddle_out as u32;
+            if self.v[op_ix] == RenderOp::BlitSimple.word() {
+                self.v[op_ix] = RenderOp::BlitOut.word();
+            } else if self.v[op_ix] == RenderOp::BlitStraddle.word() {
+                self.v[op_ix] = RenderOp::BlitStraddleOut.word();
             }
         }
+        // The last op stores the partial word, so the text ends on a word.
+        self.bits = (self.bits + x).next_multiple_of(32);
         x
     }
 
     pub fn blit_1bpp(&mut self, array: &[u32], words: usize, stride: u32) {
         // FIXME: new renderlist instruction?
         self.v.extend(
-            core::iter::repeat(render_blit_out as u32)
-                .take(words)
+            core::iter::repeat_n(RenderOp::BlitOut.word(), words)
                 .enumerate()
-                .flat_map(|(word, op)| [op, array[word..].as_ptr() as u32, stride, 0]), // FIXME: some way to make sure we pass the right amount of arguments to these functions?
+                .flat_map(|(word, op)| [op, array[word..].as_ptr() as usize, stride as usize, 0]), // FIXME: some way to make sure we pass the right amount of arguments to these functions?
+        );
+        self.bits += words as u32 * 32;
+    }
+
+    /// Copy rows of an 8bpp image into the line buffer.
+    ///
+    /// `stride` is the distance between rows in bytes, and `width` is in
+    /// pixels, a multiple of 4. The line buffer position must be word
+    /// aligned, which it is after any op that ends on a word boundary.
+    pub fn blit_8bpp(&mut self, image: &[u32], stride: u32, width: u32) {
+        assert!(
+            width.is_multiple_of(4),
+            "8bpp blit width must be a multiple of 4"
+        );
+        self.blit_words(image, stride, width, 4);
+    }
+
+    /// Copy rows of a 16bpp RGB555 image into the line buffer.
+    ///
+    /// As [`RenderlistBuilder::blit_8bpp`], but `width` must be even.
+    pub fn blit_16bpp(&mut self, image: &[u32], stride: u32, width: u32) {
+        assert!(width.is_multiple_of(2), "16bpp blit width must be even");
+        self.blit_words(image, stride, width, 2);
+    }
+
+    /// Copy `width` pixels of whole words, `per_word` pixels to a word.
+    fn blit_words(&mut self, image: &[u32], stride: u32, width: u32, per_word: u32) {
+        assert!(
+            self.bits.is_multiple_of(32),
+            "blit must start on a word boundary"
         );
+        assert!(
+            self.x + width <= self.width,
+            "blit runs past the end of the line"
+        );
+        let words = width / per_word;
+        let Some(&height) = self.v.get(self.stripe_start) else {
+            panic!("blit must be inside a stripe");
+        };
+        let height = height as u32;
+        let len = (stride as usize / 4)
+            .checked_mul(height.saturating_sub(1) as usize)
+            .and_then(|len| len.checked_add(words as usize));
+        assert!(
+            len.is_some_and(|len| len <= image.len()),
+            "blit reads past end of image"
+        );
+        if words > 0 {
+            self.v.extend([
+                RenderOp::BlitWords.word(),
+                image.as_ptr() as usize,
+                stride as usize,
+                words as usize,
+            ]);
+        }
+        self.x += width;
+        self.bits += words * 32;
     }
 
     pub fn build(self) -> Renderlist {
@@ -172,7 +326,153 @@ impl RenderlistBuilder {
 }
 
 impl Renderlist {
-    pub fn get(&self) -> &[u32] {
+    pub fn get(&self) -> &[usize] {
         &self.0
     }
 }
+
+/// Disassembles the renderlist, one op per line.
+impl fmt::Debug for Renderlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let font = FONT_BITS.as_ptr_range();
+        let (font_start, font_end) = (font.start as usize, font.end as usize);
+        let v = &self.0;
+        let mut ix = 0;
+        while ix + 1 < v.len() {
+            writeln!(f, "stripe {} next={}", v[ix], v[ix + 1])?;
+            ix += 2;
+            loop {
+                let Some(&op) = v.get(ix) else {
+                    return writeln!(f, "  <truncated>");
+                };
+                let render_op = RenderOp::from_word(op);
+                if render_op == Some(RenderOp::Stop) {
+                    writeln!(f, "  stop")?;
+                    ix += 1;
+                    break;
+                }
+                let arg = |i: usize| v.get(ix + i).copied().unwrap_or(0);
+                let (src, stride, arg) = (arg(1), arg(2), arg(3));
+                // Unknown words are shown as ops with all three arguments.
+                ix += render_op.map_or(4, RenderOp::words);
+                match render_op {
+                    Some(render_op) => write!(f, "  {} ", render_op.name())?,
+                    None => write!(f, "  {op:#010x} ")?,
+                }
+                if (font_start..font_end).contains(&src) {
+                    write!(f, "font+{}", (src - font_start) / size_of::<u32>())?;
+                } else {
+                    write!(f, "{src:#010x}")?;
+                }
+                match render_op {
+                    Some(RenderOp::Blit64Aligned) => writeln!(f, " stride={stride}")?,
+                    Some(RenderOp::BlitWords) => writeln!(f, " stride={stride} words={arg}")?,
+                    _ => writeln!(f, " stride={stride} shifts={arg:#010x}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl defmt::Format for Renderlist {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", defmt::Debug2Format(self));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+
+    use super::RenderlistBuilder;
+
+    #[test]
+    fn disassemble_tiles() {
+        let tile = [0u32; 16];
+        let mut rb = RenderlistBuilder::new(48);
+        rb.begin_stripe(8);
+        rb.tile64(&tile, 0, 16);
+        rb.tile64(&tile, 4, 16);
+        rb.tile64(&tile, 0, 16);
+        rb.end_stripe();
+        let renderlist = rb.build();
+        let t = tile.as_ptr() as usize;
+        let expected = format!(
+            "stripe 8 next=18\n\
+             \x20 blit_64_aligned {t:#010x} stride=8\n\
+             \x20 blit_simple {t:#010x} stride=8 shifts=0x00101010\n\
+             \x20 blit_straddle {:#010x} stride=8 shifts=0x10001000\n\
+             \x20 blit_64_straddle {t:#010x} stride=8 shifts=0x00001010\n\
+             \x20 stop\n",
+            t + 4,
+        );
+        assert_eq!(format!("{renderlist:?}"), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "blit runs past the end of the line")]
+    fn blit_past_line() {
+        // The first blit must advance the position for the second to fail.
+        let image = [0u32; 4];
+        let mut rb = RenderlistBuilder::new(12);
+        rb.begin_stripe(1);
+        rb.blit_8bpp(&image, 16, 8);
+        rb.blit_8bpp(&image[2..], 16, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "blit must start on a word boundary")]
+    fn blit_unaligned() {
+        let image = [0u32; 2];
+        let tile = [0u32; 16];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(1);
+        rb.tile64(&tile, 0, 6);
+        rb.blit_8bpp(&image, 8, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "blit must start on a word boundary")]
+    fn blit_after_half_word() {
+        // Four 4bpp pixels are a multiple of the 8bpp pixels in a word, but
+        // only half a word.
+        let image = [0u32; 2];
+        let tile = [0u32; 16];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(1);
+        rb.tile64(&tile, 0, 4);
+        rb.blit_8bpp(&image, 8, 8);
+    }
+
+    #[test]
+    fn blit_after_word() {
+        let image = [0u32; 2];
+        let tile = [0u32; 16];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(1);
+        rb.tile64(&tile, 0, 8);
+        rb.blit_8bpp(&image, 8, 8);
+        rb.end_stripe();
+    }
+
+    #[test]
+    #[should_panic(expected = "blit reads past end of image")]
+    fn blit_huge_stride() {
+        // A stride whose row offsets would wrap around in 32 bits.
+        let image = [0u32; 2];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(480);
+        rb.blit_8bpp(&image, 0xffff_fffc, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "blit must be inside a stripe")]
+    fn blit_outside_stripe() {
+        let image = [0u32; 2];
+        let mut rb = RenderlistBuilder::new(32);
+        rb.begin_stripe(1);
+        rb.end_stripe();
+        rb.blit_8bpp(&image, 8, 8);
+    }
+}
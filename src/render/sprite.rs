@@ -0,0 +1,155 @@
+use core::{
+    mem::offset_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+};
+
+use super::{Fade, SpritePalette};
+
+/// A 4bpp sprite image.
+///
+/// Rows are packed with the leftmost pixel in the low nibble, each row
+/// padded to a whole number of words. Pixel value 0 is transparent.
+#[repr(C)]
+pub struct Sprite {
+    pub(super) image: *const u32,
+    pub(super) palette: &'static SpritePalette,
+    /// Width in the low half, height in the high half.
+    size: u32,
+    /// Distance between rows, in bytes.
+    pub(super) stride: u32,
+}
+
+// The image is immutable and `'static`.
+unsafe impl Sync for Sprite {}
+
+/// A slot of a sprite layer.
+///
+/// Scanout reads the first three fields, which are latched from the pending
+/// ones at the start of each frame.
+#[repr(C)]
+pub(super) struct SpriteSlot {
+    /// x in the low half, y in the high half, both signed.
+    pub(super) pos: AtomicU32,
+    pub(super) sprite: AtomicPtr<Sprite>,
+    /// The palette of the sprite, or `faded` while a fade is applied.
+    pub(super) palette: AtomicPtr<u32>,
+    next_pos: AtomicU32,
+    next_sprite: AtomicPtr<Sprite>,
+    faded: [AtomicU32; 8],
+}
+
+/// A set of sprites overlaid by scanout.
+///
+/// The layer is shared with the video core, so sprites can be moved,
+/// changed or hidden without rebuilding the display list. Changes take
+/// effect from the next frame, so sprites don't tear when moved during
+/// scanout. Later slots are drawn on top.
+#[repr(C)]
+pub struct SpriteLayer<const N: usize> {
+    len: u32,
+    pub(super) slots: [SpriteSlot; N],
+}
+
+impl Sprite {
+    pub const fn new(
+        image: &'static [u32],
+        width: u16,
+        height: u16,
+        palette: &'static SpritePalette,
+    ) -> Self {
+        let stride = (width as u32).div_ceil(8) * 4;
+        assert!(
+            image.len() >= (height as u32 * stride / 4) as usize,
+            "sprite image is too small"
+        );
+        Sprite {
+            image: image.as_ptr(),
+            palette,
+            size: width as u32 | ((height as u32) << 16),
+            stride,
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.size as u16
+    }
+
+    pub fn height(&self) -> u16 {
+        (self.size >> 16) as u16
+    }
+}
+
+impl<const N: usize> Default for SpriteLayer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SpriteLayer<N> {
+    pub const fn new() -> Self {
+        SpriteLayer {
+            len: N as u32,
+            slots: [const {
+                SpriteSlot {
+                    pos: AtomicU32::new(0),
+                    sprite: AtomicPtr::new(null_mut()),
+                    palette: AtomicPtr::new(null_mut()),
+                    next_pos: AtomicU32::new(0),
+                    next_sprite: AtomicPtr::new(null_mut()),
+                    faded: [const { AtomicU32::new(0) }; 8],
+                }
+            }; N],
+        }
+    }
+
+    /// Show a sprite in a slot, at the given position in display coordinates.
+    pub fn show(&self, ix: usize, sprite: &'static Sprite, x: i16, y: i16) {
+        self.set_position(ix, x, y);
+        let ptr = sprite as *const Sprite as *mut Sprite;
+        self.slots[ix].next_sprite.store(ptr, Ordering::Release);
+    }
+
+    /// Move a sprite. Positions may be partly or entirely offscreen.
+    pub fn set_position(&self, ix: usize, x: i16, y: i16) {
+        let pos = x as u16 as u32 | ((y as u16 as u32) << 16);
+        self.slots[ix].next_pos.store(pos, Ordering::Relaxed);
+    }
+
+    pub fn hide(&self, ix: usize) {
+        self.slots[ix]
+            .next_sprite
+            .store(null_mut(), Ordering::Relaxed);
+    }
+}
+
+/// Latch the pending changes of a layer of any size, for a new frame, and
+/// apply the frame's fade to the palettes of its sprites.
+///
+/// # Safety
+///
+/// `layer` must point to a [`SpriteLayer`].
+#[cfg_attr(target_os = "none", link_section = ".data")]
+pub(crate) unsafe fn latch_layer(layer: usize, fade: Fade) {
+    let layer = layer as *const u32;
+    let n = layer.read() as usize;
+    let slots = layer.byte_add(offset_of!(SpriteLayer<1>, slots)) as *const SpriteSlot;
+    for ix in 0..n {
+        let slot = &*slots.add(ix);
+        let sprite = slot.next_sprite.load(Ordering::Acquire);
+        slot.pos
+            .store(slot.next_pos.load(Ordering::Relaxed), Ordering::Relaxed);
+        slot.sprite.store(sprite, Ordering::Relaxed);
+        let Some(sprite) = sprite.as_ref() else {
+            continue;
+        };
+        let mut palette = sprite.palette.as_words().as_ptr() as *mut u32;
+        if fade.is_active() {
+            for (faded, &pixels) in slot.faded.iter().zip(sprite.palette.as_words()) {
+                faded.store(fade.pixels(pixels), Ordering::Relaxed);
+            }
+            palette = slot.faded.as_ptr() as *mut u32;
+        }
+        slot.palette.store(palette, Ordering::Relaxed);
+    }
+}
@@ -21,6 +21,12 @@ const STATE_TAKEN: u32 = 0;
 const STATE_READY_FOR_CLIENT: u32 = 1;
 const STATE_READY_FOR_SYSTEM: u32 = 2;
 
+impl<T> Default for SwapCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> SwapCell<T> {
     pub const fn new() -> Self {
         SwapCell {
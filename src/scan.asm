@@ -474,21 +474,290 @@ video_scan_4bpp_pal_16:
     ldmia r0!, {r5, r6}
     bx r4
 
-// TODO finish
-.global sprite_4bpp
-.type sprite_4bpp,%function
+// args: count pal
+// palette has 16 4 byte entries, each two pixels
+// the start position must be even
+.global video_scan_2bpp_pal_16
+.type video_scan_2bpp_pal_16,%function
 .thumb_func
-sprite_4bpp:
+video_scan_2bpp_pal_16:
+    subs r5, #16
+    blo 3f
+2:
     ldmia r1!, {r4}
+    subs r5, #16
     ubfx r3, r4, #0, #4
-    lsls r3, r3, #1
-    itt ne
-    ldrhne r3, [r6, r3]
-    strhne r3, [r2]
-    // 6 elided
-    ubfx r3, r4, #28, #4
-    lsls r3, r3, #1
-    itt ne
-    ldrhne r3, [r6, r3]
-    strhne r3, [r2, #14]
-    bx lr
+    ubfx r7, r4, #4, #4
+    ldr r3, [r6, r3, lsl #2]
+    ldr r7, [r6, r7, lsl #2]
+    stmia r2!, {r3, r7}
+    ubfx r3, r4, #8, #4
+    ubfx r7, r4, #12, #4
+    ldr r3, [r6, r3, lsl #2]
+    ldr r7, [r6, r7, lsl #2]
+    stmia r2!, {r3, r7}
+    ubfx r3, r4, #16, #4
+    ubfx r7, r4, #20, #4
+    ldr r3, [r6, r3, lsl #2]
+    ldr r7, [r6, r7, lsl #2]
+    stmia r2!, {r3, r7}
+    ubfx r3, r4, #24, #4
+    ubfx r7, r4, #28, #4
+    ldr r3, [r6, r3, lsl #2]
+    ldr r7, [r6, r7, lsl #2]
+    stmia r2!, {r3, r7}
+    bhs 2b
+3:
+    adds r5, #16 // r5 = count % 16
+    beq 5f
+    ldmia r1!, {r4}
+4:
+    subs r5, #2
+    ubfx r3, r4, #0, #4
+    lsr r4, #4
+    ldr r3, [r6, r3, lsl #2]
+    stmia r2!, {r3}
+    bhi 4b
+    it ne
+    subne r2, #2
+5:
+    ldmia r0!, {r4, r5, r6}
+    bx r4
+
+// args: count pal
+// palette has 256 2 byte entries
+// the start position must be even
+.global video_scan_8bpp_pal_16
+.type video_scan_8bpp_pal_16,%function
+.thumb_func
+video_scan_8bpp_pal_16:
+    subs r5, #4
+    blo 3f
+2:
+    ldmia r1!, {r4}
+    subs r5, #4
+    uxtb r3, r4
+    ubfx r7, r4, #8, #8
+    ldrh r3, [r6, r3, lsl #1]
+    ldrh r7, [r6, r7, lsl #1]
+    orr r3, r3, r7, lsl #16
+    ubfx r7, r4, #16, #8
+    lsr r4, #24
+    ldrh r7, [r6, r7, lsl #1]
+    ldrh r4, [r6, r4, lsl #1]
+    orr r7, r7, r4, lsl #16
+    stmia r2!, {r3, r7}
+    bhs 2b
+3:
+    adds r5, #4 // r5 = count % 4
+    beq 5f
+    ldmia r1!, {r4}
+4:
+    uxtb r3, r4
+    lsr r4, #8
+    ldrh r3, [r6, r3, lsl #1]
+    strh r3, [r2], #2
+    subs r5, #1
+    bne 4b
+5:
+    ldmia r0!, {r4, r5, r6}
+    bx r4
+
+// args: count (unused)
+// copies RGB555 pixels from the line buffer
+// count and the start position must be even
+.global video_scan_16bpp
+.type video_scan_16bpp,%function
+.thumb_func
+video_scan_16bpp:
+    lsrs r5, #2 // r5 = pairs of words, C = odd word
+    bcc 1f
+    ldmia r1!, {r4}
+    stmia r2!, {r4}
+1:
+    beq 3f
+2:
+    ldmia r1!, {r3, r4}
+    stmia r2!, {r3, r4}
+    subs r5, #1
+    bne 2b
+3:
+    ldmia r0!, {r4, r5, r6}
+    bx r4
+
+// args: count cursor
+// cursor is {row, stride, base}; row advances by stride (bytes) each line
+// count and the start position must be even
+.global video_scan_16bpp_rows
+.type video_scan_16bpp_rows,%function
+.thumb_func
+video_scan_16bpp_rows:
+    ldmia r6, {r3, r7}
+    add r7, r3
+    str r7, [r6]
+    lsrs r5, #2 // r5 = pairs of words, C = odd word
+    bcc 1f
+    ldmia r3!, {r4}
+    stmia r2!, {r4}
+1:
+    beq 3f
+2:
+    ldmia r3!, {r4, r6}
+    stmia r2!, {r4, r6}
+    subs r5, #1
+    bne 2b
+3:
+    ldmia r0!, {r4, r5, r6}
+    bx r4
+
+// Output one word of gradient, pixels with channels in lanes of r3, r4
+// and r7, then advance the lanes by r8, r9 and r10.
+// \t is the dither threshold for the word. Result in r6, clobbers ip.
+.macro video_scan_gradient_16_word t
+    uqadd16 ip, r3, \t
+    and ip, ip, #0xf800f800
+    lsr r6, ip, #1
+    uqadd16 ip, r4, \t
+    and ip, ip, #0xf800f800
+    orr r6, r6, ip, lsr #6
+    uqadd16 ip, r7, \t
+    and ip, ip, #0xf800f800
+    orr r6, r6, ip, lsr #11
+    sadd16 r3, r3, r8
+    sadd16 r4, r4, r9
+    sadd16 r7, r7, r10
+.endm
+
+// args: count state
+// state is {offset, stride, reset, end, r, g, b, dr, dg, db} followed by
+// rows of two threshold words; offset (bytes) selects the row, advancing
+// by stride each line and wrapping to 0 at end
+// Channels are 5.11 fixed point in 16 bit lanes, a pixel pair per word;
+// see gradient_state. About 7 cycles per pixel.
+.global video_scan_gradient_16
+.type video_scan_gradient_16,%function
+.thumb_func
+video_scan_gradient_16:
+    push {r0, r1}
+    ldrd r3, r7, [r6]
+    ldr r4, [r6, #12]
+    adds r7, r3
+    cmp r7, r4
+    it eq
+    moveq r7, #0
+    str r7, [r6]
+    add r4, r6, r3
+    ldrd r0, r1, [r4, #40] // r0, r1 = thresholds
+    adds r6, #16
+    ldmia r6, {r3, r4, r7, r8, r9, r10}
+    tst r2, #2
+    beq 1f
+    // the first word is for pixels -1 and 0
+    video_scan_gradient_16_word r1
+    lsrs r6, #16
+    strh r6, [r2], #2
+    subs r5, #1
+1:
+    subs r5, #4
+    blo 3f
+2:
+    video_scan_gradient_16_word r0
+    str r6, [r2], #4
+    video_scan_gradient_16_word r1
+    str r6, [r2], #4
+    subs r5, #4
+    bhs 2b
+3:
+    adds r5, #4 // r5 = count % 4
+    beq 5f
+    video_scan_gradient_16_word r0
+    subs r5, #2
+    blo 4f
+    str r6, [r2], #4
+    beq 5f
+    video_scan_gradient_16_word r1
+4:
+    strh r6, [r2], #2
+5:
+    pop {r0, r1}
+    ldmia r0!, {r4, r5, r6}
+    bx r4
+
+// args: width header
+// header is {y, layer, y0}; y advances each line
+// layer is {n, n * {pos, sprite}}; pos is x and y as i16, sprite may be null
+// sprite is {image, palette, width | height << 16, stride}
+// Overlays 4bpp sprites on the line just output, rewinding r2 by width.
+// Pixel 0 is transparent, and later sprites are drawn on top.
+.global video_scan_sprites_4bpp
+.type video_scan_sprites_4bpp,%function
+.thumb_func
+video_scan_sprites_4bpp:
+    push {r0, r1}
+    ldr r3, [r6] // r3 = y
+    adds r4, r3, #1
+    str r4, [r6]
+    ldr r7, [r6, #4]
+    sub r2, r2, r5, lsl #1 // r2 = start of line
+    ldmia r7!, {r4}
+    cmp r4, #0
+    beq 8f
+    mov r10, r4 // r10 = sprites remaining
+1:
+    ldr r4, [r7], #4 // r4 = pos
+    ldr r1, [r7], #4 // r1 = sprite
+    ldr r8, [r7], #44 // r8 = palette, skipping the rest of the slot
+    cmp r1, #0
+    beq 7f
+    asr r6, r4, #16
+    subs r6, r3, r6 // r6 = row within sprite
+    bmi 7f
+    ldr r0, [r1, #8]
+    cmp r6, r0, lsr #16
+    bhs 7f
+    sxth r4, r4 // r4 = x
+    uxth r0, r0 // r0 = sprite width
+    ldr ip, [r1, #12]
+    mul r6, r6, ip
+    ldr ip, [r1]
+    add ip, r6 // ip = row of image
+    // clip to [max(0, -x), min(sprite width, width - x))
+    negs r1, r4
+    bic r1, r1, r1, asr #31 // r1 = first pixel
+    subs r6, r5, r4
+    cmp r6, r0
+    it gt
+    movgt r6, r0
+    subs r6, r1 // r6 = pixel count
+    ble 7f
+    add r9, r4, r1
+    add r9, r2, r9, lsl #1 // r9 = output
+    lsrs r4, r1, #3
+    add ip, ip, r4, lsl #2
+    ldr r0, [ip], #4
+    and r4, r1, #7
+    lsls r4, #2
+    lsrs r0, r4
+2:
+    ands r4, r0, #15
+    beq 3f
+    ldrh r4, [r8, r4, lsl #1]
+    strh r4, [r9]
+3:
+    add r9, #2
+    lsr r0, #4
+    adds r1, #1
+    subs r6, #1
+    beq 7f
+    tst r1, #7
+    bne 2b
+    ldr r0, [ip], #4
+    b 2b
+7:
+    subs r10, #1
+    bne 1b
+8:
+    add r2, r2, r5, lsl #1
+    pop {r0, r1}
+    ldmia r0!, {r4, r5, r6}
+    bx r4
@@ -1,15 +1,111 @@
-use alloc::vec::Vec;
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-use crate::render::{Palette1bpp, Palette4bppFast};
+use alloc::{boxed::Box, vec::Vec};
 
-extern "C" {
-    fn video_scan_solid_16();
+use crate::render::{
+    gradient_state, latch_layer, Fade, Palette1bpp, Palette2bpp, Palette4bppFast, Palette8bpp,
+    SpriteLayer, GRADIENT_STATE_LEN,
+};
 
-    fn video_scan_1bpp_pal_16();
+/// A scanout kernel, from `scan.asm`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ScanOp {
+    Solid,
+    Pal1bpp,
+    Pal2bpp,
+    Pal4bpp,
+    Pal8bpp,
+    Rgb555,
+    Rgb555Rows,
+    Gradient,
+    Sprites,
+    Stop,
+}
+
+impl ScanOp {
+    const ALL: [ScanOp; 10] = [
+        ScanOp::Solid,
+        ScanOp::Pal1bpp,
+        ScanOp::Pal2bpp,
+        ScanOp::Pal4bpp,
+        ScanOp::Pal8bpp,
+        ScanOp::Rgb555,
+        ScanOp::Rgb555Rows,
+        ScanOp::Gradient,
+        ScanOp::Sprites,
+        ScanOp::Stop,
+    ];
+
+    /// The word for the op in a scanlist.
+    ///
+    /// On the device, this is the address of the kernel, which scanout
+    /// jumps to. Elsewhere the asm isn't built and scanlists are only
+    /// interpreted, so the word just tells the ops apart.
+    #[cfg(target_os = "none")]
+    pub(crate) fn word(self) -> usize {
+        extern "C" {
+            fn video_scan_solid_16();
+
+            fn video_scan_1bpp_pal_16();
+
+            fn video_scan_2bpp_pal_16();
+
+            fn video_scan_4bpp_pal_16();
+
+            fn video_scan_8bpp_pal_16();
+
+            fn video_scan_16bpp();
 
-    fn video_scan_4bpp_pal_16();
+            fn video_scan_gradient_16();
+
+            fn video_scan_16bpp_rows();
+
+            fn video_scan_sprites_4bpp();
+
+            fn video_scan_stop();
+        }
+        let kernel: unsafe extern "C" fn() = match self {
+            ScanOp::Solid => video_scan_solid_16,
+            ScanOp::Pal1bpp => video_scan_1bpp_pal_16,
+            ScanOp::Pal2bpp => video_scan_2bpp_pal_16,
+            ScanOp::Pal4bpp => video_scan_4bpp_pal_16,
+            ScanOp::Pal8bpp => video_scan_8bpp_pal_16,
+            ScanOp::Rgb555 => video_scan_16bpp,
+            ScanOp::Rgb555Rows => video_scan_16bpp_rows,
+            ScanOp::Gradient => video_scan_gradient_16,
+            ScanOp::Sprites => video_scan_sprites_4bpp,
+            ScanOp::Stop => video_scan_stop,
+        };
+        kernel as usize
+    }
+
+    #[cfg(not(target_os = "none"))]
+    pub(crate) fn word(self) -> usize {
+        self as usize
+    }
 
-    fn video_scan_stop();
+    pub(crate) fn from_word(word: usize) -> Option<ScanOp> {
+        ScanOp::ALL.into_iter().find(|op| op.word() == word)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScanOp::Solid => "solid",
+            ScanOp::Pal1bpp => "pal_1bpp",
+            ScanOp::Pal2bpp => "pal_2bpp",
+            ScanOp::Pal4bpp => "pal_4bpp",
+            ScanOp::Pal8bpp => "pal_8bpp",
+            ScanOp::Rgb555 => "rgb555",
+            ScanOp::Rgb555Rows => "rgb555_rows",
+            ScanOp::Gradient => "gradient",
+            ScanOp::Sprites => "sprites",
+            ScanOp::Stop => "stop",
+        }
+    }
 }
 
 /// A display list for video scanout.
@@ -21,12 +117,45 @@ extern "C" {
 /// There are a number of safety requirements, as the scanlist is
 /// interpreted by an unsafe virtual machine. The width of each scanline
 /// must match the actual buffer provided, and the total height must
-/// also be the number of scanlines.
+/// also be the number of scanlines. [`ScanlistBuilder`] checks these,
+/// at a modest cost while building (none during scanout).
 ///
-/// One potential direction is to make the scanlist builder enforce the
-/// safety requirements. This would have a modest runtime cost (none
-/// during scanout).
-pub struct Scanlist(Vec<u32>);
+/// Palettes referenced by the scanlist are either `'static`, or copied
+/// into storage owned by the scanlist, so they live as long as it does.
+/// Images read directly by scanout are likewise `'static`.
+///
+/// Words of the scanlist are pointer sized, so they can hold pointers on
+/// a 64 bit host too.
+pub struct Scanlist {
+    v: Vec<usize>,
+    arena: Vec<u32>,
+    arena_fixups: Vec<usize>,
+    state: Vec<usize>,
+    state_fixups: Vec<usize>,
+    /// Indices in `state` of cursors advanced by scanout. At the start of
+    /// a frame, each is reset from the word two after it.
+    cursors: Vec<usize>,
+    /// Sprite layers, whose pending changes are latched at the start of
+    /// a frame.
+    layers: Vec<usize>,
+    /// Writes applied before scanlines, sorted by line.
+    copper: Vec<CopperWrite>,
+    /// Index of the next copper write to apply in this frame.
+    copper_next: usize,
+    fade_solids: Vec<FadeSolid>,
+    fade_palettes: Vec<FadePalette>,
+    fade_gradients: Vec<FadeGradient>,
+    /// Faded copies of palettes, for ops and copper writes to use instead
+    /// while a fade is applied. Empty until the first fade.
+    faded: Vec<u32>,
+    faded_len: usize,
+    /// The fade applied from the next frame.
+    fade: Fade,
+    /// The fade applied to the current frame.
+    applied: Fade,
+    width: u32,
+    height: u32,
+}
 
 /// A builder for scanlists.
 ///
@@ -34,74 +163,1531 @@ pub struct Scanlist(Vec<u32>);
 /// system for scanout. Typically it is double-buffered, so one is being
 /// scanned out, the other is built by the app.
 pub struct ScanlistBuilder {
-    v: Vec<u32>,
+    /// Distinguishes this builder, and each use of it after recycling, so
+    /// that palette ops from elsewhere can be rejected.
+    id: u32,
+    v: Vec<usize>,
+    /// Palettes owned by the scanlist.
+    arena: Vec<u32>,
+    /// Indices in `v` holding offsets into `arena`, which are resolved
+    /// to pointers once `arena` has stopped growing.
+    arena_fixups: Vec<usize>,
+    /// Per-line state of ops, which scanout updates, and may hold pointers.
+    state: Vec<usize>,
+    /// Indices in `v` holding offsets into `state`, resolved as for
+    /// `arena_fixups`.
+    state_fixups: Vec<usize>,
+    cursors: Vec<usize>,
+    layers: Vec<usize>,
+    copper: Vec<CopperWrite>,
+    /// Solid colors, faded by rewriting them.
+    fade_solids: Vec<FadeSolid>,
+    /// Palettes, faded by switching ops to faded copies of them.
+    fade_palettes: Vec<FadePalette>,
+    /// Gradients, faded by recomputing their state.
+    fade_gradients: Vec<FadeGradient>,
+    faded: Vec<u32>,
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    /// Height of the stripe being built, if any.
+    stripe: Option<u32>,
+    /// The first error encountered, reported by [`ScanlistBuilder::build`].
+    error: Option<ScanlistError>,
+    /// The first error since the last stripe ended, reported by
+    /// [`ScanlistBuilder::end_stripe`].
+    stripe_error: Option<ScanlistError>,
+}
+
+/// An error in the structure of a scanlist.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ScanlistError {
+    /// The operations in the stripe starting at `y` don't add up to the
+    /// scanline width.
+    StripeWidth { y: u32, expected: u32, actual: u32 },
+    /// The stripes don't add up to the display height.
+    Height { expected: u32, actual: u32 },
+    /// At `y`, a stripe was begun without ending the previous one, an
+    /// operation was added outside a stripe, or a stripe was ended
+    /// without being begun.
+    Unbalanced { y: u32 },
+    /// At `y`, an op that only copies whole words of 16bpp pixels was
+    /// given an odd `count`.
+    OddCount { y: u32, count: u32 },
+    /// At `y`, an op that only stores whole words of output was started at
+    /// an odd `x`, after an op of odd width.
+    OddStart { y: u32, x: u32 },
+    /// At `y`, rows read directly from an image were given a `stride`
+    /// that isn't a whole number of words.
+    RowStride { y: u32, stride: u32 },
+    /// At `y`, rows read directly from an image would run past its end.
+    ImageBounds { y: u32 },
+    /// A copper write for line `y` was given a palette op from another
+    /// builder, or from before this one was recycled.
+    ForeignOp { y: u32 },
+    /// A copper color change for line `y` was given an `index` outside the
+    /// palette.
+    PaletteIndex { y: u32, index: u8 },
+}
+
+/// Source of builder ids.
+static NEXT_BUILDER_ID: AtomicU32 = AtomicU32::new(0);
+
+/// A palette op in a scanlist, as a target for copper palette switches.
+///
+/// The type parameter is the palette type, so palettes can only be
+/// swapped for ones of the same format. The op is only valid for the
+/// builder that made it, until that is built.
+pub struct PaletteOp<P> {
+    /// Id of the builder the op belongs to.
+    builder: u32,
+    /// Index in `v` of the palette argument.
+    arg_ix: usize,
+    _palette: PhantomData<P>,
+}
+
+impl<P> Clone for PaletteOp<P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P> Copy for PaletteOp<P> {}
+
+/// A palette op whose palette is owned by the scanlist, so its colors can
+/// also be changed by copper writes.
+///
+/// Only the `_owned` palette methods of [`ScanlistBuilder`] return these.
+pub struct OwnedPaletteOp<P> {
+    op: PaletteOp<P>,
+    /// Index in the arena of the palette.
+    arena_ix: usize,
+    layout: PaletteLayout,
+}
+
+impl<P> Clone for OwnedPaletteOp<P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P> Copy for OwnedPaletteOp<P> {}
+
+impl<P> OwnedPaletteOp<P> {
+    /// The op, for switching it to another palette.
+    pub fn op(self) -> PaletteOp<P> {
+        self.op
+    }
+}
+
+/// How colors are arranged in a palette table.
+#[derive(Clone, Copy)]
+enum PaletteLayout {
+    /// A table of pixel pairs, indexed by two colors out of `n`.
+    Pairs(u32),
+    /// One 16 bit entry per color.
+    Single,
+}
+
+/// A write into the scanlist made before a given scanline.
+///
+/// This is the "copper list", named after the Amiga coprocessor used for
+/// the same raster effects.
+#[derive(Clone, Copy)]
+struct CopperWrite {
+    y: u32,
+    target: CopperTarget,
+    value: usize,
+    /// Value of the target at the start of the frame.
+    initial: usize,
+    /// Value written while a fade is applied. For palette switches, this is
+    /// the index of the palette in `fade_palettes` until the scanlist is
+    /// built, then the offset of its faded copy until that is allocated.
+    faded: usize,
+}
+
+/// A solid color in a scanlist, faded by rewriting it.
+#[derive(Clone, Copy)]
+struct FadeSolid {
+    /// Index in `v` of the color.
+    ix: usize,
+    color: u32,
+}
+
+/// A palette in a scanlist, with a faded copy of it in `faded`.
+#[derive(Clone, Copy)]
+struct FadePalette {
+    /// Index in `v` of the palette argument, or `None` for a palette only
+    /// switched to by the copper.
+    arg_ix: Option<usize>,
+    /// The palette; for an owned one, its offset in the arena until the
+    /// scanlist is built.
+    source: usize,
+    owned: bool,
+    /// Offset of the faded copy in `faded`, in words. Owned palettes are
+    /// at the same offset as in the arena.
+    offset: usize,
+    len: usize,
+    /// Whether this makes the faded copy, rather than sharing that of an
+    /// earlier use of the same palette.
+    primary: bool,
+}
+
+/// A gradient in a scanlist, faded by recomputing its state from faded
+/// ends.
+#[derive(Clone, Copy)]
+struct FadeGradient {
+    /// Index in `state` of the gradient.
+    ix: usize,
     x: u32,
+    y: u32,
+    count: u32,
+    start: u32,
+    end: u32,
+    dither: bool,
+}
+
+#[derive(Clone, Copy)]
+enum CopperTarget {
+    /// A 16 bit entry in the arena, indexed in halfwords.
+    ArenaHalf(usize),
+    /// A word of the scanlist itself.
+    Op(usize),
 }
 
 impl ScanlistBuilder {
-    pub fn new(_width: u32, _height: u32) -> Self {
+    pub fn new(width: u32, height: u32) -> Self {
         ScanlistBuilder {
+            id: NEXT_BUILDER_ID.fetch_add(1, Ordering::Relaxed),
             v: alloc::vec![],
+            arena: alloc::vec![],
+            arena_fixups: alloc::vec![],
+            state: alloc::vec![],
+            state_fixups: alloc::vec![],
+            cursors: alloc::vec![],
+            layers: alloc::vec![],
+            copper: alloc::vec![],
+            fade_solids: alloc::vec![],
+            fade_palettes: alloc::vec![],
+            fade_gradients: alloc::vec![],
+            faded: alloc::vec![],
+            width,
+            height,
             x: 0,
+            y: 0,
+            stripe: None,
+            error: None,
+            stripe_error: None,
         }
     }
 
     pub fn recycle(mut scanlist: Scanlist) -> Self {
-        scanlist.0.clear();
+        scanlist.v.clear();
+        scanlist.arena.clear();
+        scanlist.arena_fixups.clear();
+        scanlist.state.clear();
+        scanlist.state_fixups.clear();
+        scanlist.cursors.clear();
+        scanlist.layers.clear();
+        scanlist.copper.clear();
+        scanlist.fade_solids.clear();
+        scanlist.fade_palettes.clear();
+        scanlist.fade_gradients.clear();
+        scanlist.faded.clear();
         ScanlistBuilder {
-            v: scanlist.0,
+            id: NEXT_BUILDER_ID.fetch_add(1, Ordering::Relaxed),
+            v: scanlist.v,
+            arena: scanlist.arena,
+            arena_fixups: scanlist.arena_fixups,
+            state: scanlist.state,
+            state_fixups: scanlist.state_fixups,
+            cursors: scanlist.cursors,
+            layers: scanlist.layers,
+            copper: scanlist.copper,
+            fade_solids: scanlist.fade_solids,
+            fade_palettes: scanlist.fade_palettes,
+            fade_gradients: scanlist.fade_gradients,
+            faded: scanlist.faded,
+            width: scanlist.width,
+            height: scanlist.height,
             x: 0,
+            y: 0,
+            stripe: None,
+            error: None,
+            stripe_error: None,
         }
     }
 
-    pub fn build(self) -> Scanlist {
-        // TODO: check width, do some kind of error?
-        Scanlist(self.v)
+    /// Finish the scanlist, checking that it exactly covers the display.
+    ///
+    /// On error, the scanlist is returned anyway so that its storage can
+    /// be reused, but it must not be scanned out.
+    pub fn build(mut self) -> Result<Scanlist, (ScanlistError, Box<Scanlist>)> {
+        if self.stripe.is_some() {
+            self.fail(ScanlistError::Unbalanced { y: self.y });
+        } else if self.y != self.height {
+            self.fail(ScanlistError::Height {
+                expected: self.height,
+                actual: self.y,
+            });
+        }
+        for &ix in &self.arena_fixups {
+            self.v[ix] = self.arena.as_mut_ptr().wrapping_add(self.v[ix]) as usize;
+        }
+        for &ix in &self.state_fixups {
+            self.v[ix] = self.state.as_mut_ptr().wrapping_add(self.v[ix]) as usize;
+        }
+        let mut faded_len = self.arena.len();
+        for ix in 0..self.fade_palettes.len() {
+            let palette = self.fade_palettes[ix];
+            let palette = if palette.owned {
+                let source = self.arena.as_ptr().wrapping_add(palette.source) as usize;
+                FadePalette { source, ..palette }
+            } else if let Some(first) = self.fade_palettes[..ix]
+                .iter()
+                .find(|first| !first.owned && first.source == palette.source)
+            {
+                FadePalette {
+                    offset: first.offset,
+                    primary: false,
+                    ..palette
+                }
+            } else {
+                faded_len += palette.len;
+                FadePalette {
+                    offset: faded_len - palette.len,
+                    ..palette
+                }
+            };
+            self.fade_palettes[ix] = palette;
+        }
+        self.copper.sort_by_key(|write| write.y);
+        for write in &mut self.copper {
+            write.initial = match write.target {
+                CopperTarget::ArenaHalf(ix) => {
+                    ((self.arena[ix / 2] >> ((ix % 2) * 16)) & 0xffff) as usize
+                }
+                CopperTarget::Op(ix) => self.v[ix],
+            };
+            if let CopperTarget::Op(_) = write.target {
+                write.faded = self.fade_palettes[write.faded].offset;
+            }
+        }
+        let scanlist = Scanlist {
+            v: self.v,
+            arena: self.arena,
+            arena_fixups: self.arena_fixups,
+            state: self.state,
+            state_fixups: self.state_fixups,
+            cursors: self.cursors,
+            layers: self.layers,
+            copper: self.copper,
+            copper_next: 0,
+            fade_solids: self.fade_solids,
+            fade_palettes: self.fade_palettes,
+            fade_gradients: self.fade_gradients,
+            faded: self.faded,
+            faded_len,
+            fade: Fade::NONE,
+            applied: Fade::NONE,
+            width: self.width,
+            height: self.height,
+        };
+        match self.error {
+            None => Ok(scanlist),
+            Some(err) => Err((err, Box::new(scanlist))),
+        }
+    }
+
+    /// Record an error, keeping only the first.
+    fn fail(&mut self, err: ScanlistError) {
+        self.error.get_or_insert(err);
+        self.stripe_error.get_or_insert(err);
+    }
+
+    fn check_even(&mut self, count: u32) {
+        if !count.is_multiple_of(2) {
+            self.fail(ScanlistError::OddCount { y: self.y, count });
+        }
+    }
+
+    /// Check that an op storing whole words of output starts on a word.
+    fn check_word_start(&mut self) {
+        if !self.x.is_multiple_of(2) {
+            self.fail(ScanlistError::OddStart {
+                y: self.y,
+                x: self.x,
+            });
+        }
+    }
+
+    fn add_pixels(&mut self, count: u32) {
+        if self.stripe.is_none() {
+            self.fail(ScanlistError::Unbalanced { y: self.y });
+        }
+        self.x += count;
     }
 
     pub fn begin_stripe(&mut self, height: u32) {
-        self.v.push(height);
+        if self.stripe.is_some() {
+            self.fail(ScanlistError::Unbalanced { y: self.y });
+        }
+        self.stripe = Some(height);
+        self.x = 0;
+        self.v.push(height as usize);
     }
 
-    pub fn end_stripe(&mut self) {
-        self.v.push(video_scan_stop as u32);
+    /// End a stripe, checking that it covers the scanline width.
+    ///
+    /// Returns the first error in the stripe, including those of its ops.
+    /// Errors are also remembered and reported by
+    /// [`ScanlistBuilder::build`], so it is fine to only check there.
+    pub fn end_stripe(&mut self) -> Result<(), ScanlistError> {
+        self.v.push(ScanOp::Stop.word());
+        match self.stripe.take() {
+            None => self.fail(ScanlistError::Unbalanced { y: self.y }),
+            Some(_) if self.x != self.width => self.fail(ScanlistError::StripeWidth {
+                y: self.y,
+                expected: self.width,
+                actual: self.x,
+            }),
+            Some(height) => self.y += height,
+        }
+        self.x = 0;
+        self.stripe_error.take().map_or(Ok(()), Err)
     }
 
     /// Generate a run of solid color.
     pub fn solid(&mut self, count: u32, color: u32) {
+        let ix = self.v.len() + 2;
+        self.fade_solids.push(FadeSolid { ix, color });
         self.v
-            .extend_from_slice(&[video_scan_solid_16 as u32, count, color]);
-        self.x += count;
+            .extend_from_slice(&[ScanOp::Solid.word(), count as usize, color as usize]);
+        self.add_pixels(count);
+    }
+
+    /// Add an op taking its argument from the scanlist's per-line state.
+    ///
+    /// Returns the index of the data in the state.
+    fn op_state(&mut self, op: ScanOp, count: u32, data: &[usize]) -> usize {
+        let ix = self.state.len();
+        self.state_fixups.push(self.v.len() + 2);
+        self.v.extend_from_slice(&[op.word(), count as usize, ix]);
+        self.state.extend_from_slice(data);
+        ix
+    }
+
+    /// Add an op taking a `'static` palette.
+    fn op_palette<P>(&mut self, op: ScanOp, count: u32, palette: &'static P) -> PaletteOp<P> {
+        let arg = palette as *const P as usize;
+        let arg_ix = self.v.len() + 2;
+        self.fade_palette::<P>(Some(arg_ix), arg, false);
+        self.v.extend_from_slice(&[op.word(), count as usize, arg]);
+        self.add_pixels(count);
+        PaletteOp {
+            builder: self.id,
+            arg_ix,
+            _palette: PhantomData,
+        }
+    }
+
+    /// Add an op taking a palette, copied into the arena from `words`.
+    fn op_palette_owned<P>(
+        &mut self,
+        op: ScanOp,
+        count: u32,
+        words: &[u32],
+        layout: PaletteLayout,
+    ) -> OwnedPaletteOp<P> {
+        let arena_ix = self.arena.len();
+        self.arena.extend_from_slice(words);
+        let arg_ix = self.v.len() + 2;
+        self.arena_fixups.push(arg_ix);
+        self.fade_palette::<P>(Some(arg_ix), arena_ix, true);
+        self.v
+            .extend_from_slice(&[op.word(), count as usize, arena_ix]);
+        self.add_pixels(count);
+        OwnedPaletteOp {
+            op: PaletteOp {
+                builder: self.id,
+                arg_ix,
+                _palette: PhantomData,
+            },
+            arena_ix,
+            layout,
+        }
+    }
+
+    /// Record a palette to fade, `source` being as for [`FadePalette`].
+    fn fade_palette<P>(&mut self, arg_ix: Option<usize>, source: usize, owned: bool) {
+        self.fade_palettes.push(FadePalette {
+            arg_ix,
+            source,
+            owned,
+            offset: if owned { source } else { 0 },
+            len: size_of::<P>() / size_of::<u32>(),
+            primary: true,
+        });
     }
 
-    /// Safety note: we take a reference to the palette, but the
-    /// lifetime must extend until it is used.
-    // TODO: this really should be marked as unsafe, or we should make a safe
-    // variant that stores the palette in the display list. Either way, it should
-    // be consistent with the 4bpp method.
-    pub fn pal_1bpp(&mut self, count: u32, palette: &Palette1bpp) {
-        self.v.extend_from_slice(&[
-            video_scan_1bpp_pal_16 as u32,
+    /// Generate pixels from a 1bpp line buffer.
+    pub fn pal_1bpp(
+        &mut self,
+        count: u32,
+        palette: &'static Palette1bpp,
+    ) -> PaletteOp<Palette1bpp> {
+        self.op_palette(ScanOp::Pal1bpp, count, palette)
+    }
+
+    /// Generate pixels from a 1bpp line buffer, copying the palette into
+    /// the scanlist.
+    ///
+    /// This is for palettes computed on the fly. Static palettes are
+    /// better passed to [`ScanlistBuilder::pal_1bpp`], which doesn't copy.
+    pub fn pal_1bpp_owned(
+        &mut self,
+        count: u32,
+        palette: &Palette1bpp,
+    ) -> OwnedPaletteOp<Palette1bpp> {
+        self.op_palette_owned(
+            ScanOp::Pal1bpp,
             count,
-            palette as *const _ as u32,
-        ]);
-        self.x += count;
+            palette.as_words(),
+            PaletteLayout::Pairs(2),
+        )
     }
 
-    /// Safety note: we take a reference to the palette, but the
-    /// lifetime must extend until it is used.
-    pub unsafe fn pal_4bpp(&mut self, count: u32, palette: &Palette4bppFast) {
-        self.v.extend_from_slice(&[
-            video_scan_4bpp_pal_16 as u32,
+    /// Generate pixels from a 2bpp line buffer.
+    ///
+    /// This reads half as much line buffer as 4bpp. The position in the
+    /// scanline must be even.
+    pub fn pal_2bpp(
+        &mut self,
+        count: u32,
+        palette: &'static Palette2bpp,
+    ) -> PaletteOp<Palette2bpp> {
+        self.check_word_start();
+        self.op_palette(ScanOp::Pal2bpp, count, palette)
+    }
+
+    /// Generate pixels from a 2bpp line buffer, copying the palette into
+    /// the scanlist.
+    pub fn pal_2bpp_owned(
+        &mut self,
+        count: u32,
+        palette: &Palette2bpp,
+    ) -> OwnedPaletteOp<Palette2bpp> {
+        self.check_word_start();
+        self.op_palette_owned(
+            ScanOp::Pal2bpp,
             count,
-            palette as *const _ as u32,
-        ]);
-        self.x += count;
+            palette.as_words(),
+            PaletteLayout::Pairs(4),
+        )
+    }
+
+    /// Generate pixels from a 4bpp line buffer.
+    ///
+    /// The position in the scanline must be even.
+    pub fn pal_4bpp(
+        &mut self,
+        count: u32,
+        palette: &'static Palette4bppFast,
+    ) -> PaletteOp<Palette4bppFast> {
+        self.check_word_start();
+        self.op_palette(ScanOp::Pal4bpp, count, palette)
+    }
+
+    /// Generate pixels from a 4bpp line buffer, copying the palette into
+    /// the scanlist.
+    ///
+    /// The fast palette is 1KiB, so this is best used sparingly.
+    pub fn pal_4bpp_owned(
+        &mut self,
+        count: u32,
+        palette: &Palette4bppFast,
+    ) -> OwnedPaletteOp<Palette4bppFast> {
+        self.check_word_start();
+        self.op_palette_owned(
+            ScanOp::Pal4bpp,
+            count,
+            palette.as_words(),
+            PaletteLayout::Pairs(16),
+        )
+    }
+
+    /// Generate pixels from an 8bpp line buffer.
+    ///
+    /// The position in the scanline must be even.
+    pub fn pal_8bpp(
+        &mut self,
+        count: u32,
+        palette: &'static Palette8bpp,
+    ) -> PaletteOp<Palette8bpp> {
+        self.check_word_start();
+        self.op_palette(ScanOp::Pal8bpp, count, palette)
+    }
+
+    /// Generate pixels from an 8bpp line buffer, copying the palette into
+    /// the scanlist.
+    pub fn pal_8bpp_owned(
+        &mut self,
+        count: u32,
+        palette: &Palette8bpp,
+    ) -> OwnedPaletteOp<Palette8bpp> {
+        self.check_word_start();
+        self.op_palette_owned(
+            ScanOp::Pal8bpp,
+            count,
+            palette.as_words(),
+            PaletteLayout::Single,
+        )
+    }
+
+    /// Generate a horizontal gradient between two 0xRRGGBB colors.
+    ///
+    /// With `dither`, ordered dithering hides the banding of 16bpp output.
+    /// Like solid color, the pixels are generated in scanout without the
+    /// line buffer, at about 7 cycles per pixel. The dither pattern is
+    /// [`DITHER_ROWS`] rows high, and aligned to the display.
+    ///
+    /// [`DITHER_ROWS`]: crate::render::DITHER_ROWS
+    pub fn gradient(&mut self, count: u32, start: u32, end: u32, dither: bool) {
+        if count == 0 {
+            return;
+        }
+        let state = gradient_state(self.x, self.y, count, start, end, dither);
+        let ix = self.op_state(ScanOp::Gradient, count, &state);
+        self.cursors.push(ix);
+        self.fade_gradients.push(FadeGradient {
+            ix,
+            x: self.x,
+            y: self.y,
+            count,
+            start,
+            end,
+            dither,
+        });
+        self.add_pixels(count);
+    }
+
+    /// Change one color of an owned palette, from scanline `y` onwards.
+    ///
+    /// The color is 16bpp; use `rgb`. Changes are undone at the start of
+    /// each frame, so a palette starts every frame as it was built.
+    ///
+    /// A rejected change is left out, without affecting the scanlist.
+    pub fn copper_color<P>(
+        &mut self,
+        y: u32,
+        op: OwnedPaletteOp<P>,
+        index: u8,
+        color: u32,
+    ) -> Result<(), ScanlistError> {
+        self.check_op(y, op.op)?;
+        let base = op.arena_ix * 2;
+        let n = match op.layout {
+            PaletteLayout::Pairs(n) => n as usize,
+            PaletteLayout::Single => 256,
+        };
+        if index as usize >= n {
+            return Err(ScanlistError::PaletteIndex { y, index });
+        }
+        let index = index as usize;
+        match op.layout {
+            PaletteLayout::Pairs(_) => {
+                for pair in 0..n * n {
+                    if pair % n == index {
+                        let target = CopperTarget::ArenaHalf(base + pair * 2);
+                        self.copper_write(y, target, color as usize);
+                    }
+                    if pair / n == index {
+                        let target = CopperTarget::ArenaHalf(base + pair * 2 + 1);
+                        self.copper_write(y, target, color as usize);
+                    }
+                }
+            }
+            PaletteLayout::Single => {
+                self.copper_write(y, CopperTarget::ArenaHalf(base + index), color as usize);
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch an op to another palette, from scanline `y` onwards.
+    ///
+    /// As for [`ScanlistBuilder::copper_color`], a rejected switch is left
+    /// out.
+    pub fn copper_palette<P>(
+        &mut self,
+        y: u32,
+        op: PaletteOp<P>,
+        palette: &'static P,
+    ) -> Result<(), ScanlistError> {
+        self.check_op(y, op)?;
+        let value = palette as *const P as usize;
+        let faded = self.fade_palettes.len();
+        self.fade_palette::<P>(None, value, false);
+        self.copper_write(y, CopperTarget::Op(op.arg_ix), value);
+        if let Some(write) = self.copper.last_mut() {
+            write.faded = faded;
+        }
+        Ok(())
+    }
+
+    /// Check that a copper write's op was made by this builder.
+    fn check_op<P>(&self, y: u32, op: PaletteOp<P>) -> Result<(), ScanlistError> {
+        if op.builder != self.id {
+            return Err(ScanlistError::ForeignOp { y });
+        }
+        Ok(())
+    }
+
+    fn copper_write(&mut self, y: u32, target: CopperTarget, value: usize) {
+        self.copper.push(CopperWrite {
+            y,
+            target,
+            value,
+            initial: 0,
+            faded: 0,
+        });
+    }
+
+    /// Copy RGB555 pixels from a 16bpp line buffer.
+    ///
+    /// `count` must be even, and so must the position in the scanline.
+    pub fn rgb555(&mut self, count: u32) {
+        self.check_word_start();
+        self.check_even(count);
+        self.v
+            .extend_from_slice(&[ScanOp::Rgb555.word(), count as usize, 0]);
+        self.add_pixels(count);
+    }
+
+    /// Copy RGB555 pixels directly from an image, bypassing the line buffer.
+    ///
+    /// Each scanline of the stripe reads the next row of `image`, starting
+    /// from the first. `stride` is the distance between rows in bytes.
+    /// `count` must be even, and so must the position in the scanline.
+    /// `stride` must be a multiple of 4, and every row read must lie within
+    /// `image`.
+    pub fn rgb555_rows(&mut self, count: u32, image: &'static [u32], stride: u32) {
+        self.check_word_start();
+        self.check_even(count);
+        if !stride.is_multiple_of(4) {
+            self.fail(ScanlistError::RowStride { y: self.y, stride });
+        }
+        let height = self.stripe.unwrap_or(1);
+        let len = (stride as usize / 4)
+            .checked_mul(height.saturating_sub(1) as usize)
+            .and_then(|len| len.checked_add(count.div_ceil(2) as usize));
+        if len.is_none_or(|len| len > image.len()) {
+            self.fail(ScanlistError::ImageBounds { y: self.y });
+        }
+        let base = image.as_ptr() as usize;
+        let cursor = [base, stride as usize, base];
+        let ix = self.op_state(ScanOp::Rgb555Rows, count, &cursor);
+        self.cursors.push(ix);
+        self.add_pixels(count);
+    }
+
+    /// Overlay a layer of sprites on the stripe.
+    ///
+    /// This must come after the ops covering the scanline width. Sprites are
+    /// clipped to the scanline, and may be combined with any background.
+    pub fn sprites<const N: usize>(&mut self, layer: &'static SpriteLayer<N>) {
+        if self.stripe.is_some() && self.x != self.width {
+            self.fail(ScanlistError::StripeWidth {
+                y: self.y,
+                expected: self.width,
+                actual: self.x,
+            });
+        }
+        let (y, layer) = (self.y as usize, layer as *const _ as usize);
+        let ix = self.op_state(ScanOp::Sprites, self.width, &[y, layer, y]);
+        self.cursors.push(ix);
+        self.layers.push(layer);
+        self.add_pixels(0);
     }
 }
 
 impl Scanlist {
-    pub fn get(&self) -> &[u32] {
-        &self.0
+    pub fn get(&self) -> &[usize] {
+        &self.v
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Set the fade applied to solid colors, palettes, gradients and
+    /// sprite palettes, from the next [`Scanlist::rewind`].
+    ///
+    /// Scanout sets this once per frame from [`FadeState`]. Changing the
+    /// fade costs time in proportion to the palettes, but none per line.
+    /// The faded copies of the palettes are allocated on the first fade.
+    ///
+    /// [`FadeState`]: crate::render::FadeState
+    pub fn set_fade(&mut self, fade: Fade) {
+        self.fade = fade;
+    }
+
+    /// Reset cursors advanced by scanout, undo copper writes and latch
+    /// sprite changes, for a new frame. Then apply the fade for the frame.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn rewind(&mut self) {
+        for &ix in &self.cursors {
+            self.state[ix] = self.state[ix + 2];
+        }
+        for &layer in &self.layers {
+            // Safety: layers are `'static`, and added by `sprites`.
+            unsafe { latch_layer(layer, self.fade) };
+        }
+        for ix in 0..self.copper_next {
+            let write = self.copper[ix];
+            match write.target {
+                // Color changes went to the faded copies.
+                CopperTarget::ArenaHalf(_) if self.applied.is_active() => {
+                    let initial = self.applied.pixels(write.initial as u32) as usize;
+                    Self::write(&mut self.v, &mut self.faded, write.target, initial);
+                }
+                _ => Self::write(&mut self.v, &mut self.arena, write.target, write.initial),
+            }
+        }
+        self.copper_next = 0;
+        self.apply_fade();
+    }
+
+    /// Point ops at faded colors and palettes, or back at the originals.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    fn apply_fade(&mut self) {
+        let fade = self.fade;
+        if !fade.is_active() {
+            if self.applied.is_active() {
+                for solid in &self.fade_solids {
+                    self.v[solid.ix] = solid.color as usize;
+                }
+                for palette in &self.fade_palettes {
+                    if let Some(ix) = palette.arg_ix {
+                        self.v[ix] = palette.source;
+                    }
+                }
+                for gradient in &self.fade_gradients {
+                    Self::fade_gradient(&mut self.state, gradient, |color| color);
+                }
+            }
+            self.applied = fade;
+            return;
+        }
+        if self.faded.len() != self.faded_len {
+            self.alloc_faded();
+        }
+        // An unchanged fade only needs the copper's palette switches undone.
+        let changed = fade != self.applied;
+        if changed {
+            for solid in &self.fade_solids {
+                self.v[solid.ix] = (fade.pixels(solid.color) & 0xffff) as usize;
+            }
+        }
+        let faded = self.faded.as_mut_ptr();
+        for palette in &self.fade_palettes {
+            if changed && palette.primary {
+                let source = palette.source as *const u32;
+                for i in 0..palette.len {
+                    // Safety: the source is a `'static` palette or in the
+                    // arena, and the copy was sized for it at build.
+                    unsafe {
+                        let pixels = source.add(i).read();
+                        faded.add(palette.offset + i).write(fade.pixels(pixels));
+                    }
+                }
+            }
+            if let Some(ix) = palette.arg_ix {
+                self.v[ix] = faded.wrapping_add(palette.offset) as usize;
+            }
+        }
+        if changed {
+            for gradient in &self.fade_gradients {
+                Self::fade_gradient(&mut self.state, gradient, |color| fade.rgb888(color));
+            }
+            for write in &mut self.copper {
+                if let CopperTarget::ArenaHalf(_) = write.target {
+                    write.faded = (fade.pixels(write.value as u32) & 0xffff) as usize;
+                }
+            }
+        }
+        self.applied = fade;
+    }
+
+    /// Allocate the faded copies of palettes, and point the copper's
+    /// palette switches at them.
+    fn alloc_faded(&mut self) {
+        self.faded.resize(self.faded_len, 0);
+        let faded = self.faded.as_ptr();
+        for write in &mut self.copper {
+            if let CopperTarget::Op(_) = write.target {
+                write.faded = faded.wrapping_add(write.faded) as usize;
+            }
+        }
+    }
+
+    /// Recompute the state of a gradient, with its ends passed through
+    /// `fade`.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    fn fade_gradient(state: &mut [usize], gradient: &FadeGradient, fade: impl Fn(u32) -> u32) {
+        let FadeGradient {
+            ix,
+            x,
+            y,
+            count,
+            start,
+            end,
+            dither,
+        } = *gradient;
+        let faded = gradient_state(x, y, count, fade(start), fade(end), dither);
+        state[ix..ix + GRADIENT_STATE_LEN].copy_from_slice(&faded);
+    }
+
+    /// Apply copper writes due by scanline `y`.
+    ///
+    /// While a fade is applied, color changes go to the faded copies of
+    /// the palettes, and palette switches to faded copies of the palettes.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    pub fn copper(&mut self, y: u32) {
+        while let Some(&write) = self.copper.get(self.copper_next) {
+            if write.y > y {
+                break;
+            }
+            if self.applied.is_active() {
+                Self::write(&mut self.v, &mut self.faded, write.target, write.faded);
+            } else {
+                Self::write(&mut self.v, &mut self.arena, write.target, write.value);
+            }
+            self.copper_next += 1;
+        }
+    }
+
+    /// Write to a copper target, with palettes in `palettes`.
+    #[cfg_attr(target_os = "none", link_section = ".data")]
+    fn write(v: &mut [usize], palettes: &mut [u32], target: CopperTarget, value: usize) {
+        match target {
+            CopperTarget::ArenaHalf(ix) => {
+                let shift = (ix % 2) * 16;
+                let word = &mut palettes[ix / 2];
+                *word = (*word & !(0xffff << shift)) | ((value as u32 & 0xffff) << shift);
+            }
+            CopperTarget::Op(ix) => v[ix] = value,
+        }
+    }
+}
+
+/// Disassembles the scanlist, one op per line, followed by the copper list.
+///
+/// Colors are shown as 0xRRGGBB, assuming 16bpp RGB output. Pointers into
+/// the scanlist's own storage are shown as offsets in words.
+impl fmt::Debug for Scanlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arena = self.arena.as_ptr_range();
+        let arena = arena.start as usize..arena.end as usize;
+        let state = self.state.as_ptr_range();
+        let state = state.start as usize..state.end as usize;
+        let faded = self.faded.as_ptr_range();
+        let faded = faded.start as usize..faded.end as usize;
+        let ptr = |f: &mut fmt::Formatter<'_>, p: usize| {
+            if arena.contains(&p) {
+                write!(f, "arena+{}", (p - arena.start) / size_of::<u32>())
+            } else if faded.contains(&p) {
+                write!(f, "faded+{}", (p - faded.start) / size_of::<u32>())
+            } else if state.contains(&p) {
+                write!(f, "state+{}", (p - state.start) / size_of::<usize>())
+            } else {
+                write!(f, "{p:#010x}")
+            }
+        };
+        let v = &self.v;
+        let mut ix = 0;
+        while ix < v.len() {
+            writeln!(f, "stripe {}", v[ix])?;
+            ix += 1;
+            loop {
+                let Some(&op) = v.get(ix) else {
+                    return writeln!(f, "  <truncated>");
+                };
+                let scan_op = ScanOp::from_word(op);
+                if scan_op == Some(ScanOp::Stop) {
+                    writeln!(f, "  stop")?;
+                    ix += 1;
+                    break;
+                }
+                let count = v.get(ix + 1).copied().unwrap_or(0);
+                let arg = v.get(ix + 2).copied().unwrap_or(0);
+                ix += 3;
+                let Some(scan_op) = scan_op else {
+                    writeln!(f, "  {op:#010x} {count:#x} {arg:#x}")?;
+                    continue;
+                };
+                write!(f, "  {} {count}", scan_op.name())?;
+                match scan_op {
+                    ScanOp::Solid => write!(f, " #{:06x}", rgb555_to_rgb888(arg as u32))?,
+                    ScanOp::Rgb555 => (),
+                    _ => {
+                        write!(f, " ")?;
+                        ptr(f, arg)?;
+                    }
+                }
+                writeln!(f)?;
+            }
+        }
+        for write in &self.copper {
+            write!(f, "copper {} ", write.y)?;
+            match write.target {
+                CopperTarget::ArenaHalf(ix) => write!(f, "arena+{}.{}", ix / 2, ix % 2)?,
+                CopperTarget::Op(ix) => write!(f, "op+{ix}")?,
+            }
+            writeln!(f, " = {:#x}", write.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl defmt::Format for Scanlist {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", defmt::Debug2Format(self));
+    }
+}
+
+/// Expand a 16bpp RGB555 color to 0xRRGGBB.
+pub(crate) fn rgb555_to_rgb888(color: u32) -> u32 {
+    let expand = |c: u32| (c << 3) | (c >> 2);
+    let b = expand(color & 0x1f);
+    let g = expand((color >> 5) & 0x1f);
+    let r = expand((color >> 10) & 0x1f);
+    (r << 16) | (g << 8) | b
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::{ScanlistBuilder, ScanlistError};
+    use crate::render::{
+        xrgb, Fade, Palette1bpp, Palette2bpp, Palette4bppFast, Palette8bpp, SpriteLayer,
+    };
+
+    #[test]
+    fn valid() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(400);
+        sb.solid(320, 0);
+        sb.solid(320, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(80);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        assert!(sb.build().is_ok());
+    }
+
+    #[test]
+    fn stripe_width() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        sb.solid(600, 0);
+        let err = ScanlistError::StripeWidth {
+            y: 240,
+            expected: 640,
+            actual: 600,
+        };
+        assert_eq!(sb.end_stripe(), Err(err));
+        // Each stripe reports its own error, but build only the first.
+        sb.begin_stripe(10);
+        let next = ScanlistError::StripeWidth {
+            y: 240,
+            expected: 640,
+            actual: 0,
+        };
+        assert_eq!(sb.end_stripe(), Err(next));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn height() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(470);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        let err = ScanlistError::Height {
+            expected: 480,
+            actual: 470,
+        };
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn unbalanced() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.solid(640, 0);
+        sb.begin_stripe(480);
+        sb.solid(640, 0);
+        let err = ScanlistError::Unbalanced { y: 0 };
+        // The op outside a stripe is reported when the next one ends.
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(640, 0);
+        let err = ScanlistError::Unbalanced { y: 0 };
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn odd_rgb555() {
+        static IMAGE: [u32; 320] = [0; 320];
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.rgb555(639);
+        sb.solid(1, 0);
+        let err = ScanlistError::OddCount { y: 0, count: 639 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        sb.begin_stripe(240);
+        sb.rgb555_rows(640, &IMAGE, 0);
+        sb.end_stripe().unwrap();
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.rgb555_rows(2, &IMAGE, 0);
+        sb.rgb555_rows(637, &IMAGE, 0);
+        sb.solid(1, 0);
+        let err = ScanlistError::OddCount { y: 0, count: 637 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn odd_start_rgb555() {
+        static IMAGE: [u32; 320] = [0; 320];
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(1, 0);
+        sb.rgb555(638);
+        sb.solid(1, 0);
+        let err = ScanlistError::OddStart { y: 0, x: 1 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        sb.solid(3, 0);
+        sb.rgb555_rows(636, &IMAGE, 0);
+        sb.solid(1, 0);
+        let err = ScanlistError::OddStart { y: 240, x: 3 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn odd_start_2bpp() {
+        static PALETTE: Palette2bpp = Palette2bpp::new(&[0, 1, 2, 3]);
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        // Odd counts are fine, as long as the next op can start anywhere.
+        sb.pal_2bpp(319, &PALETTE);
+        sb.solid(1, 0);
+        sb.pal_2bpp_owned(320, &PALETTE);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        sb.solid(5, 0);
+        sb.pal_2bpp_owned(635, &PALETTE);
+        let err = ScanlistError::OddStart { y: 240, x: 5 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.pal_2bpp(1, &PALETTE);
+        sb.pal_2bpp(639, &PALETTE);
+        let err = ScanlistError::OddStart { y: 0, x: 1 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn odd_start_4bpp() {
+        static PALETTE: Palette4bppFast = Palette4bppFast::new(&[0; 16]);
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.pal_4bpp(319, &PALETTE);
+        sb.solid(1, 0);
+        sb.pal_4bpp_owned(320, &PALETTE);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        sb.solid(1, 0);
+        sb.pal_4bpp(639, &PALETTE);
+        let err = ScanlistError::OddStart { y: 240, x: 1 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(3, 0);
+        sb.pal_4bpp_owned(637, &PALETTE);
+        let err = ScanlistError::OddStart { y: 0, x: 3 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn odd_start_8bpp() {
+        static PALETTE: Palette8bpp = Palette8bpp::new(&[0; 256]);
+        static IMAGE: [u32; 320] = [0; 320];
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(160);
+        sb.pal_8bpp(101, &PALETTE);
+        sb.solid(1, 0);
+        sb.rgb555(200);
+        sb.pal_8bpp_owned(337, &PALETTE);
+        sb.solid(1, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(160);
+        sb.solid(1, 0);
+        sb.pal_8bpp(1, &PALETTE);
+        sb.rgb555_rows(638, &IMAGE, 0);
+        let err = ScanlistError::OddStart { y: 160, x: 1 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        sb.begin_stripe(160);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(7, 0);
+        sb.pal_8bpp_owned(633, &PALETTE);
+        let err = ScanlistError::OddStart { y: 0, x: 7 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn rgb555_rows_bounds() {
+        static IMAGE: [u32; 4 * 320] = [0; 4 * 320];
+        let mut sb = ScanlistBuilder::new(640, 5);
+        sb.begin_stripe(4);
+        sb.rgb555_rows(640, &IMAGE, 1280);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(1);
+        sb.rgb555_rows(640, &IMAGE, 1282);
+        let err = ScanlistError::RowStride { y: 4, stride: 1282 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        let mut sb = ScanlistBuilder::new(640, 5);
+        sb.begin_stripe(5);
+        sb.rgb555_rows(640, &IMAGE, 1280);
+        let err = ScanlistError::ImageBounds { y: 0 };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+
+        // A length that would wrap around is caught too.
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.rgb555_rows(640, &IMAGE, 0xffff_fffc);
+        let err = ScanlistError::ImageBounds { y: 0 };
+        assert_eq!(sb.end_stripe(), Err(err));
+    }
+
+    #[test]
+    fn owned_palette() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.pal_1bpp_owned(320, &Palette1bpp::new(0, 1));
+        sb.pal_1bpp_owned(320, &Palette1bpp::new(2, 3));
+        sb.end_stripe().unwrap();
+        let Ok(scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let v = scanlist.get();
+        let palettes = scanlist.arena.as_ptr();
+        assert_eq!(v[3], palettes as usize);
+        assert_eq!(v[6], palettes.wrapping_add(4) as usize);
+        assert_eq!(scanlist.arena[4], 2 | (2 << 16));
+    }
+
+    #[test]
+    fn row_cursor_rewind() {
+        static IMAGE: [u32; 4 * 320] = [0; 4 * 320];
+        let mut sb = ScanlistBuilder::new(640, 4);
+        sb.begin_stripe(4);
+        sb.rgb555_rows(640, &IMAGE, 1280);
+        sb.end_stripe().unwrap();
+        let Ok(mut scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let base = IMAGE.as_ptr() as usize;
+        assert_eq!(scanlist.state[..], [base, 1280, base]);
+        // Scanout advances the cursor by one row per line.
+        scanlist.state[0] += 3 * 1280;
+        scanlist.rewind();
+        assert_eq!(scanlist.state[0], base);
+    }
+
+    #[test]
+    fn sprites() {
+        static LAYER: SpriteLayer<2> = SpriteLayer::new();
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        sb.solid(640, 0);
+        sb.sprites(&LAYER);
+        sb.end_stripe().unwrap();
+        let Ok(mut scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let layer = &LAYER as *const _ as usize;
+        assert_eq!(scanlist.state[..], [240, layer, 240]);
+        scanlist.state[0] += 100;
+        scanlist.rewind();
+        assert_eq!(scanlist.state[0], 240);
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(320, 0);
+        sb.sprites(&LAYER);
+        let err = ScanlistError::StripeWidth {
+            y: 0,
+            expected: 640,
+            actual: 320,
+        };
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
+    }
+
+    #[test]
+    fn copper() {
+        static OTHER: Palette1bpp = Palette1bpp::new(4, 5);
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        let op = sb.pal_1bpp_owned(320, &Palette1bpp::new(0, 1));
+        let swapped = sb.pal_1bpp_owned(320, &Palette1bpp::new(2, 3));
+        sb.end_stripe().unwrap();
+        sb.copper_palette(200, swapped.op(), &OTHER).unwrap();
+        sb.copper_color(100, op, 1, 7).unwrap();
+        let Ok(mut scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let initial = scanlist.arena.clone();
+        let palette_ptr = scanlist.v[6];
+        scanlist.copper(99);
+        assert_eq!(scanlist.arena, initial);
+        scanlist.copper(150);
+        assert_eq!(scanlist.arena[..4], [0, 7, 7 << 16, 7 | (7 << 16)]);
+        assert_eq!(scanlist.v[6], palette_ptr);
+        scanlist.copper(479);
+        assert_eq!(scanlist.v[6], &OTHER as *const _ as usize);
+        scanlist.rewind();
+        assert_eq!(scanlist.arena, initial);
+        assert_eq!(scanlist.v[6], palette_ptr);
+    }
+
+    #[test]
+    fn copper_color() {
+        let colors: [u32; 256] = core::array::from_fn(|i| i as u32 * 0x010203);
+        let palettes = |colors: &[u32; 256]| {
+            (
+                Palette2bpp::new(colors[..4].try_into().unwrap()),
+                Palette4bppFast::new(colors[..16].try_into().unwrap()),
+                Palette8bpp::new(colors),
+            )
+        };
+        let (pal2, pal4, pal8) = palettes(&colors);
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        let op2 = sb.pal_2bpp_owned(64, &pal2);
+        let op4 = sb.pal_4bpp_owned(64, &pal4);
+        let op8 = sb.pal_8bpp_owned(512, &pal8);
+        sb.end_stripe().unwrap();
+        let red = 0xff0000;
+        sb.copper_color(10, op2, 3, xrgb(red)).unwrap();
+        sb.copper_color(10, op4, 5, xrgb(red)).unwrap();
+        sb.copper_color(10, op8, 201, xrgb(red)).unwrap();
+        let Ok(mut scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        scanlist.copper(10);
+        // The same as changing the colors before building.
+        let (mut pal2, mut pal4, mut pal8) = palettes(&colors);
+        pal2.set_color(3, red);
+        pal4.set_color(5, red);
+        pal8.set_color(201, red);
+        let expected = [pal2.as_words(), pal4.as_words(), pal8.as_words()].concat();
+        assert_eq!(scanlist.arena, expected);
+    }
+
+    #[test]
+    fn copper_misuse() {
+        static STATIC: Palette2bpp = Palette2bpp::new(&[0, 1, 2, 3]);
+        let mut other = ScanlistBuilder::new(640, 480);
+        other.begin_stripe(480);
+        let foreign = other.pal_2bpp_owned(640, &STATIC);
+        other.end_stripe().unwrap();
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        let owned = sb.pal_2bpp_owned(320, &STATIC);
+        let shared = sb.pal_2bpp(320, &STATIC);
+        sb.end_stripe().unwrap();
+        let foreign_err = |y| Err(ScanlistError::ForeignOp { y });
+        assert_eq!(sb.copper_color(1, foreign, 0, 0), foreign_err(1));
+        assert_eq!(sb.copper_palette(2, foreign.op(), &STATIC), foreign_err(2));
+        let index_err = ScanlistError::PaletteIndex { y: 3, index: 4 };
+        assert_eq!(sb.copper_color(3, owned, 4, 0), Err(index_err));
+        // Rejected writes are left out, and don't spoil the scanlist.
+        let Ok(scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        assert!(scanlist.copper.is_empty());
+
+        // Ops from before recycling are stale.
+        let mut sb = ScanlistBuilder::recycle(scanlist);
+        sb.begin_stripe(480);
+        sb.pal_2bpp_owned(320, &STATIC);
+        sb.pal_2bpp(320, &STATIC);
+        sb.end_stripe().unwrap();
+        assert_eq!(sb.copper_color(4, owned, 0, 0), foreign_err(4));
+        assert_eq!(sb.copper_palette(5, shared, &STATIC), foreign_err(5));
+    }
+
+    #[test]
+    fn fade() {
+        static STATIC: Palette1bpp = Palette1bpp::new(0x7fff, 0x1234);
+        static OTHER: Palette1bpp = Palette1bpp::new(0x4210, 0x2108);
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(240);
+        sb.solid(64, 0x7c00);
+        sb.pal_1bpp(256, &STATIC);
+        let owned = sb.pal_1bpp_owned(320, &Palette1bpp::new(0x03e0, 0x001f));
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(240);
+        let swapped = sb.pal_1bpp(640, &STATIC);
+        sb.end_stripe().unwrap();
+        sb.copper_color(100, owned, 1, 0x7fff).unwrap();
+        sb.copper_palette(300, swapped, &OTHER).unwrap();
+        let Ok(mut scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let original = scanlist.v.clone();
+        assert!(scanlist.faded.is_empty());
+
+        let fade = Fade::new(0x0000, 16);
+        scanlist.set_fade(fade);
+        scanlist.rewind();
+        // The arena, then one copy of each static palette.
+        assert_eq!(scanlist.faded.len(), 3 * 4);
+        assert_eq!(scanlist.v[3], fade.pixels(0x7c00) as usize);
+        let faded = scanlist.faded.as_ptr();
+        assert_eq!(scanlist.v[6], faded.wrapping_add(4) as usize);
+        assert_eq!(scanlist.v[9], faded as usize);
+        assert_eq!(scanlist.v[14], scanlist.v[6]);
+        let faded_words = |words: &[u32]| words.iter().map(|&w| fade.pixels(w)).collect::<Vec<_>>();
+        assert_eq!(scanlist.faded[..4], faded_words(&scanlist.arena)[..]);
+        assert_eq!(scanlist.faded[4..8], faded_words(STATIC.as_words())[..]);
+        assert_eq!(scanlist.faded[8..], faded_words(OTHER.as_words())[..]);
+
+        // Copper writes go to the faded copies.
+        let arena = scanlist.arena.clone();
+        scanlist.copper(479);
+        assert_eq!(scanlist.arena, arena);
+        let white = fade.pixels(0x7fff) & 0xffff;
+        assert_eq!(scanlist.faded[3], white | (white << 16));
+        assert_eq!(scanlist.v[14], faded.wrapping_add(8) as usize);
+
+        // The next frame with the same fade undoes them.
+        let faded_frame = scanlist.faded.clone();
+        scanlist.rewind();
+        assert_eq!(scanlist.faded[..4], faded_words(&scanlist.arena)[..]);
+        assert_eq!(scanlist.faded[4..], faded_frame[4..]);
+        assert_eq!(scanlist.v[14], scanlist.v[6]);
+
+        // Without a fade, the ops are as built.
+        scanlist.set_fade(Fade::NONE);
+        scanlist.rewind();
+        assert_eq!(scanlist.v, original);
+        scanlist.copper(479);
+        assert_eq!(scanlist.arena[3], 0x7fff | (0x7fff << 16));
+        assert_eq!(scanlist.v[14], &OTHER as *const _ as usize);
+    }
+
+    #[test]
+    fn gradient() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(2);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        sb.begin_stripe(478);
+        sb.gradient(640, 0x000000, 0xffffff, true);
+        sb.end_stripe().unwrap();
+        let Ok(scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        // Offset, stride, reset and end of the threshold rows; starting at
+        // row 2.
+        assert_eq!(scanlist.state[..4], [2 * 8, 8, 2 * 8, 4 * 8]);
+        assert_eq!(scanlist.state.len(), 10 + 2 * 4);
+        // Pixels 0 and 1 of each channel, then steps of two pixels.
+        let (step, first) = ((255 << 8) / 639, (255 << 8) % 639 / 2);
+        assert_eq!(scanlist.state[4], first | (first + step) << 16);
+        let pair_step = 2 * step;
+        assert_eq!(scanlist.state[7], pair_step | pair_step << 16);
+    }
+
+    #[test]
+    fn disassemble() {
+        use alloc::format;
+
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(92, xrgb(0xc0c0c0));
+        sb.pal_1bpp_owned(548, &Palette1bpp::new(0, 1));
+        sb.end_stripe().unwrap();
+        let Ok(scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let text = format!("{scanlist:?}");
+        let expected = "stripe 480\n  solid 92 #c6c6c6\n  pal_1bpp 548 arena+0\n  stop\n";
+        assert_eq!(text.as_str(), expected);
+    }
+
+    #[test]
+    fn recycle_keeps_dimensions() {
+        let mut sb = ScanlistBuilder::new(640, 480);
+        sb.begin_stripe(480);
+        sb.solid(640, 0);
+        sb.end_stripe().unwrap();
+        let Ok(scanlist) = sb.build() else {
+            panic!("scanlist should be valid")
+        };
+        let mut sb = ScanlistBuilder::recycle(scanlist);
+        sb.begin_stripe(480);
+        sb.solid(320, 0);
+        let err = ScanlistError::StripeWidth {
+            y: 0,
+            expected: 640,
+            actual: 320,
+        };
+        assert_eq!(sb.end_stripe(), Err(err));
+        assert_eq!(sb.build().err().map(|(err, _)| err), Some(err));
     }
 }
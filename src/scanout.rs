@@ -0,0 +1,159 @@
+use cortex_m::peripheral::DWT;
+
+use pico_dvi_rs::{
+    dvi::STATS,
+    render::{DisplayList, FadeState, RenderlistBuilder, SwapCell, LINE_BUF_SIZE},
+    scanlist::{ScanlistBuilder, ScanlistError},
+};
+
+pub struct ScanRender {
+    display_list: DisplayList,
+    stripe_remaining: u32,
+    /// Index of the current scanlist stripe, for [`STATS`].
+    stripe_ix: usize,
+    scan_ptr: *const usize,
+    scan_next: *const usize,
+    render_ptr: *const usize,
+    render_y: u32,
+    last_y: u32,
+    fade: FadeState,
+}
+
+static mut LINE_BUF: LineBuf = LineBuf::zero();
+
+static DISPLAY_LIST_SWAPCELL: SwapCell<DisplayList> = SwapCell::new();
+
+#[derive(Clone, Copy)]
+pub struct LineBuf {
+    buf: [u32; LINE_BUF_SIZE],
+}
+
+impl LineBuf {
+    const fn zero() -> Self {
+        let buf = [0; LINE_BUF_SIZE];
+        LineBuf { buf }
+    }
+}
+
+core::arch::global_asm! {
+    include_str!("scan.asm"),
+    include_str!("render.asm"),
+    options(raw)
+}
+
+extern "C" {
+    fn video_scan(scan_list: *const usize, input: *const u32, output: *mut u32) -> *const usize;
+
+    fn render_engine(render_list: *const usize, output: *mut u32, y: u32);
+}
+
+impl ScanRender {
+    /// Set up scanout of `width` by `height` display lists, the size
+    /// [`init_display_swapcell`] was given.
+    pub fn new(width: u32, height: u32) -> Self {
+        let stripe_remaining = 0;
+        let scan_ptr = core::ptr::null();
+        let scan_next = core::ptr::null();
+        let render_ptr = core::ptr::null();
+        let render_y = 0;
+        let display_list = DisplayList::new(width, height);
+        ScanRender {
+            stripe_remaining,
+            stripe_ix: 0,
+            scan_ptr,
+            scan_next,
+            render_ptr,
+            render_y,
+            display_list,
+            last_y: 0,
+            fade: FadeState::new(),
+        }
+    }
+
+    /// Render one scanline from the line buffer into the provided video buffer.
+    #[link_section = ".data"]
+    // TODO: probably should be inline-able, this was probably for inspecting disasm
+    #[inline(never)]
+    pub fn render_scanline(&mut self, video_buf: &mut [u32], y: u32) {
+        let start = DWT::cycle_count();
+        unsafe {
+            if y <= self.last_y {
+                if DISPLAY_LIST_SWAPCELL.try_swap_by_system(&mut self.display_list) {
+                    STATS.swapped();
+                }
+                self.fade.next_frame();
+                self.display_list.scan.set_fade(self.fade.fade());
+                self.display_list.scan.rewind();
+
+                self.render_ptr = self.display_list.render.get().as_ptr();
+                self.render_y = 0;
+                self.scan_next = self.display_list.scan.get().as_ptr();
+                self.stripe_remaining = 0;
+                self.stripe_ix = 0;
+            }
+            if self.stripe_remaining == 0 {
+                self.stripe_remaining = self.scan_next.read() as u32;
+                self.scan_ptr = self.scan_next.add(1);
+                // TODO: set desperate scan_next
+            }
+            self.display_list.scan.copper(y);
+            // we could just stack allocate the tmp, as we're currently
+            // completely synchronous.
+            let line_buf_ptr = LINE_BUF.buf.as_mut_ptr();
+            let render_ptr = self.render_ptr.add(2);
+            render_engine(render_ptr, line_buf_ptr, self.render_y);
+            self.render_y += 1;
+            let stripe_height = self.render_ptr.read() as u32;
+            // TODO: this assumes we don't miss; can be made much more robust.
+            if self.render_y == stripe_height {
+                let jump = self.render_ptr.add(1).read();
+                self.render_ptr = self.display_list.render.get().as_ptr().add(jump);
+                self.render_y = 0;
+            }
+            self.scan_next = video_scan(self.scan_ptr, line_buf_ptr, video_buf.as_mut_ptr());
+            STATS.line_rendered(self.stripe_ix, DWT::cycle_count().wrapping_sub(start));
+            self.stripe_remaining -= 1;
+            if self.stripe_remaining == 0 {
+                self.stripe_ix += 1;
+            }
+            self.last_y = y;
+        }
+    }
+}
+
+/// The system assumes that this is called before [`start_display_list`]
+///
+/// The dimensions are those of the display lists the application will
+/// build, which are checked against them.
+pub fn init_display_swapcell(width: u32, height: u32) {
+    DISPLAY_LIST_SWAPCELL.set_for_client(DisplayList::new(width, height));
+}
+
+/// Start building a display list. This blocks until a free display
+/// list is available.
+///
+/// The user must have called [`init_display_swapcell`] before calling this.
+pub fn start_display_list() -> (RenderlistBuilder, ScanlistBuilder) {
+    DISPLAY_LIST_SWAPCELL.take_blocking().recycle()
+}
+
+/// Hand a finished display list to the system for scanout.
+///
+/// An invalid scanlist is refused: the previous display list stays on
+/// screen, and the storage is made available to [`start_display_list`]
+/// again.
+pub fn end_display_list(rb: RenderlistBuilder, sb: ScanlistBuilder) -> Result<(), ScanlistError> {
+    let render = rb.build();
+    match sb.build() {
+        Ok(scan) => {
+            STATS.submit();
+            DISPLAY_LIST_SWAPCELL.set_for_system(DisplayList { render, scan });
+            Ok(())
+        }
+        Err((err, scan)) => {
+            let scan = *scan;
+            DISPLAY_LIST_SWAPCELL.set_for_client(DisplayList { render, scan });
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{
+        AtomicBool, AtomicU32, AtomicUsize,
+        Ordering::{AcqRel, Acquire, Relaxed, Release},
+    },
+};
+use embedded_hal::digital::StatefulOutputPin;
+#[cfg(feature = "audio")]
+use fugit::HertzU32;
+use rp235x_hal::gpio::{bank0::Gpio10, FunctionSio, Pin, PullDown, SioOutput};
+
+#[cfg(feature = "audio")]
+use pico_dvi_rs::{
+    audio::{AudioClock, Resampler, AUDIO_QUEUE_SIZE, AUDIO_RATE},
+    dvi::{data_island, data_island::DataPacket, timing::DataIslandLine, COLOR_MODE},
+};
+use pico_dvi_rs::{
+    dvi::{
+        command::Command,
+        pack_color,
+        timing::{
+            err_line, DviTiming, DviTimingLineState, DviTimingState, SYNC_LINE_ONLY_WORDS,
+            SYNC_LINE_WORDS,
+        },
+        UnderrunPolicy, BPP, STATS, VERTICAL_REPEAT,
+    },
+    render::{rgb, Queue},
+};
+
+use crate::{
+    hal::pac::{interrupt, Interrupt, Peripherals, DMA, HSTX_FIFO},
+    scanout::ScanRender,
+    DVI_INST, DVI_OUT,
+};
+use cortex_m::peripheral::NVIC;
+
+/// The additional time (in scanlines) for the video encoding routine.
+///
+/// If video encoding can reliably happen in less than one scanline time,
+/// this should be 0. If there is variance that sometimes pushes it over
+/// the line, then a value of 1 may eliminate artifacts.
+///
+/// Note: currently only 0 is supported.
+const VIDEO_PIPELINE_SLACK: u32 = 0;
+
+const N_VIDEO_BUFFERS: usize = if VIDEO_PIPELINE_SLACK > 0 && VERTICAL_REPEAT == 1 {
+    3
+} else {
+    2
+};
+
+pub struct DviOut {
+    line_queue: Queue<LINE_QUEUE_SIZE>,
+    line_lent: [AtomicBool; N_VIDEO_BUFFERS],
+    /// The buffer in `video_lines` each line slot renders into. The spare
+    /// buffer outside the rotation holds the last line shown, for repeating.
+    line_bufs: [AtomicUsize; N_VIDEO_BUFFERS],
+    video_lines: [UnsafeCell<MaybeUninit<Box<[u32]>>>; N_VIDEO_BUFFERS + 1],
+    underrun_repeat: AtomicBool,
+    /// Active pixels per line, for building underrun lines. This is set
+    /// when the driver is set up, and 0 before.
+    line_width: AtomicU32,
+    /// Lines of the underrun color. One is sent while the other is rebuilt
+    /// for a new color.
+    underrun_lines: [UnsafeCell<[u32; SYNC_LINE_ONLY_WORDS]>; 2],
+    /// Bit 0 is the current one of `underrun_lines`; bits 1 and 2 are set
+    /// while the DMA reads the first or second.
+    underrun_state: AtomicU32,
+    #[cfg(feature = "audio")]
+    audio_queue: Queue<AUDIO_QUEUE_SIZE>,
+    // TODO: DviInst should go in here.
+}
+
+pub struct DviInst {
+    timing: DviTiming,
+    dma_pong: bool,
+    timing_state: DviTimingState,
+
+    // New state
+    sync_pulse_vsync_off: [u32; SYNC_LINE_WORDS],
+    sync_pulse_vsync_on: [u32; SYNC_LINE_WORDS],
+    sync_line_only_vsync_off: [u32; SYNC_LINE_ONLY_WORDS],
+    sync_line_only_vsync_on: [u32; SYNC_LINE_ONLY_WORDS],
+    /// The buffer in `video_lines` outside the render rotation.
+    spare: usize,
+    /// Whether `spare` holds a line that was scanned out as rendered.
+    spare_good: bool,
+    /// Slots whose line was scanned out as rendered since they were queued.
+    shown: [bool; N_VIDEO_BUFFERS],
+    /// Whether the last line set up was an underrun line.
+    underrun_sent: bool,
+
+    #[cfg(feature = "audio")]
+    data_island_sync: DataIslandLine,
+    #[cfg(feature = "audio")]
+    audio_clock: AudioClock,
+    #[cfg(feature = "audio")]
+    resampler: Resampler,
+    audio_buf: [[i16; 2]; 4],
+    audio_ix: usize,
+    frame_count: i32,
+
+    missed: [bool; N_VIDEO_BUFFERS],
+    pin: Pin<Gpio10, FunctionSio<SioOutput>, PullDown>,
+}
+
+pub struct LineGuard<'a> {
+    dvi_out: &'a DviOut,
+    slot: usize,
+    buf_ix: usize,
+}
+
+const ERROR_COLOR: u32 = rgb(0xff, 0, 0);
+
+const LINE_QUEUE_SIZE: usize = (N_VIDEO_BUFFERS + 1).next_power_of_two();
+
+impl DviOut {
+    pub const fn new() -> Self {
+        Self {
+            line_queue: Queue::new(),
+            line_lent: [const { AtomicBool::new(false) }; N_VIDEO_BUFFERS],
+            line_bufs: {
+                let mut bufs = [const { AtomicUsize::new(0) }; N_VIDEO_BUFFERS];
+                let mut i = 0;
+                while i < N_VIDEO_BUFFERS {
+                    bufs[i] = AtomicUsize::new(i);
+                    i += 1;
+                }
+                bufs
+            },
+            video_lines: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N_VIDEO_BUFFERS + 1],
+            underrun_repeat: AtomicBool::new(false),
+            line_width: AtomicU32::new(0),
+            underrun_lines: [const { UnsafeCell::new([0; SYNC_LINE_ONLY_WORDS]) }; 2],
+            underrun_state: AtomicU32::new(0),
+            #[cfg(feature = "audio")]
+            audio_queue: Queue::new(),
+        }
+    }
+
+    /// The queue of stereo audio frames, packed with [`pack_frame`].
+    ///
+    /// This is drained at [`AUDIO_RATE`] by the video interrupt; the
+    /// application should keep it topped up, for example with
+    /// [`Mixer::fill`].
+    ///
+    /// [`pack_frame`]: crate::audio::pack_frame
+    /// [`Mixer::fill`]: crate::audio::Mixer::fill
+    #[cfg(feature = "audio")]
+    pub fn audio_queue(&self) -> &Queue<AUDIO_QUEUE_SIZE> {
+        &self.audio_queue
+    }
+
+    /// Choose what is sent for lines that aren't rendered in time.
+    ///
+    /// This takes effect from the next line. The default is
+    /// [`UnderrunPolicy::ErrorLine`]. The line to send is built here rather
+    /// than in the interrupt, which can mean waiting up to a line for
+    /// scanout to finish with the one built last time. It should only be
+    /// called from one core, once the driver is set up with [`DviInst::new`].
+    pub fn set_underrun_policy(&self, policy: UnderrunPolicy) {
+        let width = self.line_width.load(Relaxed);
+        assert!(width != 0, "underrun policy set before the driver");
+        let (repeat, color) = match policy {
+            UnderrunPolicy::ErrorLine => (false, ERROR_COLOR),
+            UnderrunPolicy::BlackLine => (false, rgb(0, 0, 0)),
+            UnderrunPolicy::RepeatLine => (true, rgb(0, 0, 0)),
+            UnderrunPolicy::Color(color) => (false, color),
+        };
+        let next = (self.underrun_state.load(Relaxed) & 1) ^ 1;
+        while self.underrun_state.load(Acquire) & (2 << next) != 0 {
+            core::hint::spin_loop();
+        }
+        unsafe {
+            *self.underrun_lines[next as usize].get() = err_line(width, pack_color(color));
+        }
+        self.underrun_state.fetch_xor(1, Release);
+        self.underrun_repeat.store(repeat, Relaxed);
+    }
+
+    pub fn get_line(&self) -> (u32, LineGuard) {
+        let line_ix = self.line_queue.take_blocking();
+        let slot = line_ix as usize % N_VIDEO_BUFFERS;
+        let guard = LineGuard {
+            dvi_out: self,
+            slot,
+            buf_ix: self.line_bufs[slot].load(Acquire),
+        };
+        (line_ix, guard)
+    }
+}
+
+impl Drop for LineGuard<'_> {
+    fn drop(&mut self) {
+        self.dvi_out.line_lent[self.slot].store(false, Release);
+    }
+}
+
+impl LineGuard<'_> {
+    fn buf_mut(&mut self) -> &mut [u32] {
+        unsafe {
+            let line = (*self.dvi_out.video_lines[self.buf_ix].get()).assume_init_mut();
+            &mut line[1..]
+        }
+    }
+}
+
+unsafe impl Sync for DviOut {}
+
+const DREQ_HSTX: u8 = 52;
+
+#[inline(never)]
+pub unsafe fn setup_dma(dma: &DMA, hstx_fifo: &HSTX_FIFO) {
+    let inst = (*DVI_INST.0.get()).assume_init_mut();
+    unsafe {
+        for i in 0..2 {
+            let cmds = if i == 0 {
+                &inst.sync_pulse_vsync_off[..]
+            } else {
+                &inst.sync_line_only_vsync_off[..]
+            };
+            let ch = dma.ch(i);
+            ch.ch_read_addr().write(|w| w.bits(cmds.as_ptr() as u32));
+            ch.ch_write_addr()
+                .write(|w| w.bits(hstx_fifo.fifo().as_ptr() as u32));
+            ch.ch_trans_count().write(|w| w.bits(cmds.len() as u32));
+            ch.ch_al1_ctrl().write(|w| {
+                w.chain_to()
+                    .bits((i ^ 1) as u8)
+                    .data_size()
+                    .bits(2)
+                    .incr_read()
+                    .set_bit()
+                    .treq_sel()
+                    .bits(DREQ_HSTX)
+                    .en()
+                    .set_bit()
+            });
+        }
+        dma.ints0().write(|w| w.ints0().bits(3));
+        dma.inte0().write(|w| w.inte0().bits(3));
+    }
+}
+
+pub unsafe fn start_dma(dma: &DMA) {
+    unsafe {
+        dma.multi_chan_trigger()
+            .write(|w| w.multi_chan_trigger().bits(1));
+    }
+}
+
+impl DviInst {
+    pub fn new(timing: DviTiming, pin: Pin<Gpio10, FunctionSio<SioOutput>, PullDown>) -> Self {
+        let sync_pulse_vsync_off = timing.make_sync_pulse(false);
+        let sync_pulse_vsync_on = timing.make_sync_pulse(true);
+        let sync_line_only_vsync_off = timing.make_sync_line_only(false);
+        let sync_line_only_vsync_on = timing.make_sync_line_only(true);
+        let underrun_line = timing.make_err_line(pack_color(ERROR_COLOR));
+        for line in &DVI_OUT.underrun_lines {
+            unsafe {
+                *line.get() = underrun_line;
+            }
+        }
+        DVI_OUT.line_width.store(timing.h_active_pixels(), Relaxed);
+
+        let vline_size = 1 + timing.h_active_pixels() as usize * BPP / 32;
+        for line in &DVI_OUT.video_lines {
+            let mut buf = alloc::vec![!0; vline_size];
+            buf[0] = Command::Tmds.word(timing.h_active_pixels());
+            unsafe {
+                (*line.get()).write(buf.into());
+            }
+        }
+
+        #[cfg(feature = "audio")]
+        let data_island_sync = timing.data_island_line();
+        #[cfg(feature = "audio")]
+        let audio_clock = AudioClock::new(&timing, timing.bit_clk().convert());
+        #[cfg(feature = "audio")]
+        let resampler = Resampler::new(&audio_clock);
+
+        // The number of video lines that have been set up by the
+        // time of the first interrupt.
+        const INIT_TIMING_STATE: u32 = 1;
+        DviInst {
+            timing,
+            dma_pong: false,
+            timing_state: DviTimingState::new(INIT_TIMING_STATE),
+            sync_pulse_vsync_off,
+            sync_pulse_vsync_on,
+            sync_line_only_vsync_off,
+            sync_line_only_vsync_on,
+            #[cfg(feature = "audio")]
+            data_island_sync,
+            spare: N_VIDEO_BUFFERS,
+            spare_good: false,
+            shown: [false; N_VIDEO_BUFFERS],
+            underrun_sent: false,
+            #[cfg(feature = "audio")]
+            audio_clock,
+            #[cfg(feature = "audio")]
+            resampler,
+            audio_buf: Default::default(),
+            audio_ix: 0,
+            frame_count: 0,
+            missed: [false; N_VIDEO_BUFFERS],
+            pin,
+        }
+    }
+
+    /// Set the TMDS bit clock actually achieved.
+    ///
+    /// Audio clock regeneration and resampling are derived from this, so
+    /// audio doesn't drift when the clock setup can't hit the nominal
+    /// frequency exactly.
+    #[cfg(feature = "audio")]
+    pub fn set_bit_clock(&mut self, bit_clk: HertzU32) {
+        self.audio_clock = AudioClock::new(&self.timing, bit_clk);
+        self.resampler = Resampler::new(&self.audio_clock);
+    }
+
+    #[cfg(feature = "audio")]
+    #[link_section = ".data"]
+    /// Return true if audio buffer is updated
+    fn do_audio(&mut self, packet: &mut DataPacket) -> bool {
+        let y = self.timing_state.v_ctr();
+        // Strategy here is to encode audio on even scanlines. It's not clear this is
+        // fully spec-compliant, but we'll see.
+        let samples_this_scanline = self
+            .audio_clock
+            .samples_for_line(self.audio_buf.len() - self.audio_ix);
+        for i in 0..samples_this_scanline {
+            self.audio_buf[self.audio_ix + i] = self.resampler.next_frame(&DVI_OUT.audio_queue);
+        }
+        self.audio_ix += samples_this_scanline;
+        if y.is_multiple_of(2) {
+            packet.set_audio(&self.audio_buf[..self.audio_ix], &mut self.frame_count);
+            self.audio_ix = 0;
+            self.resampler.adjust(&DVI_OUT.audio_queue);
+        } else {
+            match y {
+                1 => packet.set_audio_info_frame(AUDIO_RATE),
+                3 => packet.set_avi_info_frame(
+                    data_island::ScanInfo::Underscan,
+                    COLOR_MODE.pixel_format(),
+                    COLOR_MODE.colorimetry(),
+                    data_island::PictureAspectRatio::Ratio4_3,
+                    data_island::ActiveFormatAspectRatio::SameAsPar,
+                    COLOR_MODE.quantization_range(),
+                    data_island::VideoCode::Code640x480P60,
+                ),
+                5 => packet.set_audio_clock_regeneration(self.audio_clock.cts, self.audio_clock.n),
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    /// The line to send in place of one that isn't ready.
+    #[link_section = ".data"]
+    unsafe fn underrun_line(&mut self) -> &'static [u32] {
+        if DVI_OUT.underrun_repeat.load(Relaxed) && self.spare_good {
+            return &(*DVI_OUT.video_lines[self.spare].get()).assume_init_ref()[..];
+        }
+        // Mark the current line as read by the DMA, until the next line.
+        let mut state = DVI_OUT.underrun_state.load(Relaxed);
+        loop {
+            let busy = (state & 1) | (2 << (state & 1));
+            match DVI_OUT
+                .underrun_state
+                .compare_exchange_weak(state, busy, AcqRel, Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => state = current,
+            }
+        }
+        self.underrun_sent = true;
+        &*DVI_OUT.underrun_lines[(state & 1) as usize].get()
+    }
+}
+
+/// Render and scan out `width` by `height` display lists.
+#[link_section = ".data"]
+pub fn core1_main(width: u32, height: u32) -> ! {
+    let mut scan_render = ScanRender::new(width, height);
+    unsafe {
+        // Render times are measured with this core's cycle counter.
+        let mut core = cortex_m::Peripherals::steal();
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+        NVIC::unmask(Interrupt::DMA_IRQ_0);
+        let dma = &Peripherals::steal().DMA;
+        start_dma(dma);
+    }
+    loop {
+        let (y, mut guard) = DVI_OUT.get_line();
+        let line = guard.buf_mut();
+        scan_render.render_scanline(line, y);
+    }
+}
+
+// In Rust 2024, this would need to be marked unsafe, but the cortex-m-rt crate
+// won't accept it. So 2021 it is.
+#[link_section = ".data"]
+#[interrupt]
+fn DMA_IRQ_0() {
+    unsafe {
+        let inst = (*DVI_INST.0.get()).assume_init_mut();
+        _ = inst.pin.toggle();
+        let ch_num = inst.dma_pong as usize;
+        let dma = &mut Peripherals::steal().DMA;
+        dma.intr().write(|w| w.bits(1 << ch_num));
+        let ch = dma.ch(ch_num);
+        inst.dma_pong = !inst.dma_pong;
+        if inst.dma_pong {
+            // interrupt at end of sync pulse, set up next sync pulse
+            #[cfg(feature = "audio")]
+            let mut pack = MaybeUninit::uninit();
+            #[cfg(feature = "audio")]
+            data_island::clear_data_packet(&mut pack);
+            #[cfg(feature = "audio")]
+            let packet = pack.assume_init_mut();
+            #[cfg(feature = "audio")]
+            let is_audio = inst.do_audio(packet);
+            let state = inst.timing_state.v_state(&inst.timing);
+            let cmds = match state {
+                #[cfg(feature = "audio")]
+                _ if is_audio => {
+                    inst.timing
+                        .encode_data_island(&mut inst.data_island_sync, state, packet);
+                    inst.data_island_sync.words()
+                }
+                #[cfg(feature = "audio")]
+                DviTimingLineState::Active => {
+                    inst.timing
+                        .encode_data_island_empty(&mut inst.data_island_sync, state);
+                    inst.data_island_sync.words()
+                }
+                DviTimingLineState::Sync => &inst.sync_pulse_vsync_on[..],
+                _ => &inst.sync_pulse_vsync_off[..],
+            };
+            ch.ch_read_addr().write(|w| w.bits(cmds.as_ptr() as u32));
+            ch.ch_trans_count().write(|w| w.bits(cmds.len() as u32));
+        } else {
+            // interrupt at end of line, set up next line
+            if inst.underrun_sent {
+                // The DMA has finished reading it.
+                DVI_OUT.underrun_state.fetch_and(1, Release);
+                inst.underrun_sent = false;
+            }
+            let offset = VIDEO_PIPELINE_SLACK + ((N_VIDEO_BUFFERS - 1) * VERTICAL_REPEAT) as u32;
+            if let Some(y) = inst.timing_state.v_scanline_index(&inst.timing, offset) {
+                if (y as usize).is_multiple_of(VERTICAL_REPEAT) {
+                    let y_scaled = y / VERTICAL_REPEAT as u32;
+                    let slot = y_scaled as usize % N_VIDEO_BUFFERS;
+                    let missed = DVI_OUT.line_lent[slot].load(Acquire);
+                    if !missed {
+                        if inst.shown[slot] {
+                            // Keep the line just scanned out for repeating,
+                            // and render into the spare buffer instead.
+                            let shown = DVI_OUT.line_bufs[slot].load(Relaxed);
+                            DVI_OUT.line_bufs[slot].store(inst.spare, Relaxed);
+                            inst.spare = shown;
+                            inst.spare_good = true;
+                            inst.shown[slot] = false;
+                        }
+                        DVI_OUT.line_queue.push_unchecked(y_scaled);
+                        DVI_OUT.line_lent[slot].store(true, Relaxed);
+                    }
+                    inst.missed[slot] = missed;
+                }
+            }
+            let cmds = match inst.timing_state.v_state(&inst.timing) {
+                DviTimingLineState::Active => {
+                    // TODO: could be optimized
+                    let y = inst
+                        .timing_state
+                        .v_scanline_index(&inst.timing, 0)
+                        .unwrap_or_default();
+                    let slot = (y as usize / VERTICAL_REPEAT) % N_VIDEO_BUFFERS;
+                    let ready = if inst.missed[slot] {
+                        STATS.missed_line();
+                        false
+                    } else if DVI_OUT.line_lent[slot].load(Acquire) {
+                        STATS.late_line();
+                        false
+                    } else {
+                        true
+                    };
+                    if ready {
+                        inst.shown[slot] = true;
+                        let buf_ix = DVI_OUT.line_bufs[slot].load(Relaxed);
+                        &(*DVI_OUT.video_lines[buf_ix].get()).assume_init_ref()[..]
+                    } else {
+                        inst.underrun_line()
+                    }
+                }
+                DviTimingLineState::Sync => &inst.sync_line_only_vsync_on[..],
+                _ => &inst.sync_line_only_vsync_off[..],
+            };
+            ch.ch_read_addr().write(|w| w.bits(cmds.as_ptr() as u32));
+            ch.ch_trans_count().write(|w| w.bits(cmds.len() as u32));
+            inst.timing_state.advance(&inst.timing);
+            STATS.end_line();
+            if inst.timing_state.v_ctr() == 0 {
+                STATS.end_frame();
+            }
+        }
+        _ = inst.pin.toggle();
+    }
+}